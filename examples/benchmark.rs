@@ -0,0 +1,88 @@
+//! Compares rolling an expression many times with the tree walker (with and without descriptions)
+//! and with the bytecode VM.
+//!
+//! ```text
+//! cargo run --release --example benchmark -- "6x(4d6dl1)h3 + 1d20" 1000000
+//! ```
+
+use std::env;
+use std::time::Duration;
+use std::time::Instant;
+
+use dice_algebra_calculator::ASTExecutable;
+use dice_algebra_calculator::Environment;
+use dice_algebra_calculator::bytecode;
+use rand::SeedableRng;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let expression = args
+        .next()
+        .unwrap_or("{1d20 + 5, 1d20 + 3}kh1 + 2d6 + 4d6dl1".to_string());
+    let iterations: u64 = match args.next() {
+        Some(count) => count
+            .parse()
+            .expect("the iteration count should be an integer"),
+        None => 100_000,
+    };
+
+    let tokens = dice_algebra_calculator::tokenize(&expression).unwrap();
+    let ast = dice_algebra_calculator::parse(&tokens).unwrap();
+    let program = bytecode::compile(&ast);
+
+    let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+    let start = Instant::now();
+    let mut tree_total: i128 = 0;
+    for _ in 0..iterations {
+        tree_total += ast.execute_ast(&mut rng).unwrap().result;
+    }
+    let tree_time = start.elapsed();
+
+    let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+    let mut env = Environment::new().without_descriptions();
+    let start = Instant::now();
+    let mut quiet_total: i128 = 0;
+    for _ in 0..iterations {
+        quiet_total += ast.execute_ast_in(&mut rng, &mut env).unwrap().result;
+    }
+    let quiet_time = start.elapsed();
+
+    let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+    let start = Instant::now();
+    let mut program_total: i128 = 0;
+    for _ in 0..iterations {
+        program_total += program.execute(&mut rng).unwrap();
+    }
+    let program_time = start.elapsed();
+
+    assert_eq!(tree_total, quiet_total, "all should roll the same dice");
+    assert_eq!(tree_total, program_total, "all should roll the same dice");
+
+    println!("Rolled {} {} times", expression, iterations);
+    println!(
+        "  tree walker:          {:>10.2?} ({})",
+        tree_time,
+        per_roll(tree_time, iterations)
+    );
+    println!(
+        "  without descriptions: {:>10.2?} ({})",
+        quiet_time,
+        per_roll(quiet_time, iterations)
+    );
+    println!(
+        "  bytecode VM:          {:>10.2?} ({})",
+        program_time,
+        per_roll(program_time, iterations)
+    );
+    println!(
+        "  bytecode speedup:     {:>10.1}x",
+        tree_time.as_secs_f64() / program_time.as_secs_f64()
+    );
+}
+
+fn per_roll(time: Duration, iterations: u64) -> String {
+    return format!(
+        "{:.0} ns per roll",
+        time.as_nanos() as f64 / iterations as f64
+    );
+}
@@ -1,158 +1,1192 @@
+use std::cmp::Ordering;
+use std::ops::Add;
+
 use crate::dice_error::DiceError;
-use rand;
+use crate::dice_error::Span;
+use crate::dice_source::DiceSource;
+use crate::environment::Environment;
+use crate::json::JsonValue;
+
+pub const MAX_DICE: u64 = 10_000;
+pub const MAX_REPEATS: u64 = 10_000;
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IntegerAST {
     pub integer: u64,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ShortRollAST {
     pub faces: u64,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LongRollAST {
     pub die: u64,
     pub faces: u64,
     pub keep_high: Option<u64>,
     pub keep_low: Option<u64>,
+    pub drop_high: Option<u64>,
+    pub drop_low: Option<u64>,
+    pub critical_success: Option<SuccessCondition>,
+    pub critical_failure: Option<SuccessCondition>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DynamicRollAST {
+    pub die: Box<AST>,
+    pub faces: Box<AST>,
+    pub keep_high: Option<u64>,
+    pub keep_low: Option<u64>,
+    pub drop_high: Option<u64>,
+    pub drop_low: Option<u64>,
+    pub critical_success: Option<SuccessCondition>,
+    pub critical_failure: Option<SuccessCondition>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MathOperation {
     Add,
     Subtract,
     Multiply,
     Divide,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MathAST {
     pub operation: MathOperation,
     pub left: Box<AST>,
     pub right: Box<AST>,
 }
 
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VariableAST {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AttributeAST {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LetAST {
+    pub name: String,
+    pub value: Box<AST>,
+    pub body: Box<AST>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConditionalAST {
+    pub condition: Box<AST>,
+    pub if_true: Box<AST>,
+    pub if_false: Box<AST>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RepeatAST {
+    pub count: u64,
+    pub expression: Box<AST>,
+    pub keep_high: Option<u64>,
+    pub keep_low: Option<u64>,
+    pub drop_high: Option<u64>,
+    pub drop_low: Option<u64>,
+    pub sort: Option<SortOrder>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SuccessCondition {
+    pub comparison: Comparison,
+    pub target: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GroupAST {
+    pub children: Vec<AST>,
+    pub keep_high: Option<u64>,
+    pub keep_low: Option<u64>,
+    pub drop_high: Option<u64>,
+    pub drop_low: Option<u64>,
+    pub success: Option<SuccessCondition>,
+}
+
+/// An expression with a label, such as a damage type, whose total is reported as a subtotal.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LabelAST {
+    pub label: String,
+    pub expression: Box<AST>,
+}
+
+/// A parsed dice algebra expression.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AST {
     Integer(IntegerAST),
     ShortRoll(ShortRollAST),
     LongRoll(LongRollAST),
+    DynamicRoll(DynamicRollAST),
     Math(MathAST),
+    Variable(VariableAST),
+    Attribute(AttributeAST),
+    Let(LetAST),
+    Conditional(ConditionalAST),
+    Repeat(RepeatAST),
+    Group(GroupAST),
+    Label(LabelAST),
 }
 
+/// The total of an executed expression, the entries of its list (if it produced one), and a
+/// description of every roll made along the way.
+///
+/// `rolls` holds every die in the order it was rolled. Like the description, it is left empty
+/// when the [`Environment`] doesn't record descriptions.
+///
+/// `subtotals` holds the part of the total contributed by each labelled subexpression, in the
+/// order the labels first appear.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ASTExecutionResult {
     pub result: i128,
     pub description: String,
+    pub list: Option<Vec<i128>>,
+    pub rolls: Vec<DieRoll>,
+    pub subtotals: Vec<(String, i128)>,
+    pub criticals: Criticals,
+}
+
+/// How many kept dice landed in the critical success range (`cs`) or the critical failure range
+/// (`cf`) of their roll. Like the total, dice dropped by a modifier don't count.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Criticals {
+    pub successes: u64,
+    pub failures: u64,
 }
 
+/// One rolled die, and whether it counted towards the total. Dice dropped by a keep or drop
+/// modifier, or which belong to a dropped repeat entry or group entry, aren't kept.
+///
+/// `critical` is the critical range a kept die landed in: its roll's `cs` and `cf` ranges, or
+/// its highest face and 1 when the roll has neither.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DieRoll {
+    pub faces: u64,
+    pub value: u64,
+    pub kept: bool,
+    pub critical: Option<Critical>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Critical {
+    Success,
+    Failure,
+}
+
+/// Rolls the dice of an expression. `execute_ast` runs without any bindings or attributes, while
+/// `execute_ast_in` resolves names against an [`Environment`].
 pub trait ASTExecutable {
-    fn execute_ast(&self, rng: &mut impl rand::Rng) -> Result<ASTExecutionResult, DiceError>;
+    fn execute_ast(&self, dice: &mut impl DiceSource) -> Result<ASTExecutionResult, DiceError> {
+        return self.execute_ast_in(dice, &mut Environment::new());
+    }
+
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError>;
 }
 
 impl ASTExecutable for AST {
-    fn execute_ast(&self, rng: &mut impl rand::Rng) -> Result<ASTExecutionResult, DiceError> {
-        match self {
-            AST::Integer(ast) => ast.execute_ast(rng),
-            AST::ShortRoll(ast) => ast.execute_ast(rng),
-            AST::LongRoll(ast) => ast.execute_ast(rng),
-            AST::Math(ast) => ast.execute_ast(rng),
-        }
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        env.begin_execution();
+        let result = match self {
+            AST::Integer(ast) => ast.execute_ast_in(dice, env),
+            AST::ShortRoll(ast) => ast.execute_ast_in(dice, env),
+            AST::LongRoll(ast) => ast.execute_ast_in(dice, env),
+            AST::DynamicRoll(ast) => ast.execute_ast_in(dice, env),
+            AST::Math(ast) => ast.execute_ast_in(dice, env),
+            AST::Variable(ast) => ast.execute_ast_in(dice, env),
+            AST::Attribute(ast) => ast.execute_ast_in(dice, env),
+            AST::Let(ast) => ast.execute_ast_in(dice, env),
+            AST::Conditional(ast) => ast.execute_ast_in(dice, env),
+            AST::Repeat(ast) => ast.execute_ast_in(dice, env),
+            AST::Group(ast) => ast.execute_ast_in(dice, env),
+            AST::Label(ast) => ast.execute_ast_in(dice, env),
+        };
+        env.end_execution();
+
+        return result;
     }
 }
 
 impl ASTExecutable for IntegerAST {
-    fn execute_ast(&self, _rng: &mut impl rand::Rng) -> Result<ASTExecutionResult, DiceError> {
+    fn execute_ast_in(
+        &self,
+        _dice: &mut impl DiceSource,
+        _env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
         return Ok(ASTExecutionResult {
             result: self.integer as i128,
             description: String::new(),
+            list: None,
+            rolls: Vec::new(),
+            subtotals: Vec::new(),
+            criticals: Criticals::default(),
         });
     }
 }
 
 impl ASTExecutable for ShortRollAST {
-    fn execute_ast(&self, rng: &mut impl rand::Rng) -> Result<ASTExecutionResult, DiceError> {
-        let roll = match self.faces {
-            0 => 0,
-            1 => 1,
-            _ => rng.random_range(1..self.faces),
-        };
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        let roll = roll_die(dice, self.faces, env)?;
+
+        let mut rolls: Vec<DieRoll> = Vec::new();
+        if env.records_descriptions() {
+            rolls.push(DieRoll {
+                faces: self.faces,
+                value: roll,
+                kept: true,
+                critical: natural_critical(self.faces, roll as i128),
+            });
+        }
+
+        let mut criticals = Criticals::default();
+        criticals.count(natural_critical(self.faces, roll as i128));
 
         return Ok(ASTExecutionResult {
             result: roll as i128,
-            description: format!("\nRolling d{}...\nYou rolled: {}\n", self.faces, roll),
+            description: env
+                .describe(|| format!("\nRolling d{}...\nYou rolled: {}\n", self.faces, roll)),
+            list: None,
+            rolls: rolls,
+            subtotals: Vec::new(),
+            criticals: criticals,
         });
     }
 }
 
 impl ASTExecutable for LongRollAST {
-    fn execute_ast(&self, rng: &mut impl rand::Rng) -> Result<ASTExecutionResult, DiceError> {
-        let mut description = format!("\nRolling {}d{}...\n", self.die, self.faces);
-        let mut rolls: Vec<u64> = Vec::new();
-        let mut sum: i128 = 0;
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        check_dice_count(self.die)?;
+
+        let has_modifier = self.keep_high.is_some()
+            || self.keep_low.is_some()
+            || self.drop_high.is_some()
+            || self.drop_low.is_some()
+            || self.critical_success.is_some()
+            || self.critical_failure.is_some();
+
+        // Nothing needs the individual rolls, so they are summed as they're rolled.
+        if !has_modifier && !env.records_descriptions() {
+            let mut sum: i128 = 0;
+            let mut criticals = Criticals::default();
+            for _ in 0..self.die {
+                let roll = roll_die(dice, self.faces, env)? as i128;
+                criticals.count(natural_critical(self.faces, roll));
+                sum += roll;
+            }
+
+            return Ok(ASTExecutionResult {
+                result: sum,
+                description: String::new(),
+                list: None,
+                rolls: Vec::new(),
+                subtotals: Vec::new(),
+                criticals: criticals,
+            });
+        }
 
+        let mut rolls: Vec<i128> = Vec::with_capacity(self.die as usize);
         for _ in 0..self.die {
-            let roll = match self.faces {
-                0 => 0,
-                1 => 1,
-                _ => rng.random_range(1..self.faces),
+            rolls.push(roll_die(dice, self.faces, env)? as i128);
+        }
+
+        let kept = kept_mask(
+            &rolls,
+            self.keep_high,
+            self.keep_low,
+            self.drop_high,
+            self.drop_low,
+        );
+
+        let mut description =
+            env.describe(|| format!("\nRolling {}d{}...\n", self.die, self.faces));
+        let mut criticals = Criticals::default();
+
+        for (roll, is_kept) in rolls.iter().zip(&kept) {
+            let critical = match *is_kept {
+                true => self.critical_of(*roll),
+                false => None,
+            };
+
+            // Only dice in ranges written on the roll are noted, so a 6 on a damage die isn't.
+            let note = match (is_kept, self.has_critical_ranges(), &critical) {
+                (false, _, _) => " (dropped)",
+                (true, true, Some(Critical::Success)) => " (critical success)",
+                (true, true, Some(Critical::Failure)) => " (critical failure)",
+                (true, _, _) => "",
             };
 
-            rolls.push(roll);
-            description.push_str(&format!("You rolled: {}\n", roll));
-            sum = sum + roll as i128;
+            criticals.count(critical);
+            if env.records_descriptions() {
+                description.push_str(&format!("You rolled: {}{}\n", roll, note));
+            }
         }
 
-        if let Some(keep_low) = self.keep_low {
-            if keep_low < self.die {
-                rolls.sort_unstable();
-                sum = 0;
+        let sum = checked_sum(
+            rolls
+                .iter()
+                .zip(&kept)
+                .filter(|(_, is_kept)| **is_kept)
+                .map(|(roll, _)| *roll),
+        )?;
+
+        let mut die_rolls: Vec<DieRoll> = Vec::new();
+        if env.records_descriptions() {
+            die_rolls = rolls
+                .iter()
+                .zip(&kept)
+                .map(|(roll, is_kept)| DieRoll {
+                    faces: self.faces,
+                    value: *roll as u64,
+                    kept: *is_kept,
+                    critical: match is_kept {
+                        true => self.critical_of(*roll),
+                        false => None,
+                    },
+                })
+                .collect();
+        }
 
-                for &roll in rolls.iter().take(keep_low as usize) {
-                    sum = sum + roll as i128;
-                }
+        return Ok(ASTExecutionResult {
+            result: sum,
+            description: description,
+            list: None,
+            rolls: die_rolls,
+            subtotals: Vec::new(),
+            criticals: criticals,
+        });
+    }
+}
+
+impl LongRollAST {
+    fn has_critical_ranges(&self) -> bool {
+        return self.critical_success.is_some() || self.critical_failure.is_some();
+    }
+
+    // The critical a kept die rolled: in the roll's ranges if it has any, otherwise on its highest
+    // face or 1.
+    fn critical_of(&self, roll: i128) -> Option<Critical> {
+        return match self.has_critical_ranges() {
+            true => self.critical(roll),
+            false => natural_critical(self.faces, roll),
+        };
+    }
+
+    // A die in both ranges, such as a 1 on `1d20cs<=1cf1`, counts as a critical success.
+    fn critical(&self, roll: i128) -> Option<Critical> {
+        if let Some(range) = &self.critical_success
+            && range.is_met_by(roll)
+        {
+            return Some(Critical::Success);
+        }
+        if let Some(range) = &self.critical_failure
+            && range.is_met_by(roll)
+        {
+            return Some(Critical::Failure);
+        }
+
+        return None;
+    }
+}
+
+// A die of a roll without critical ranges is critical when it lands on its highest face or on 1.
+fn natural_critical(faces: u64, roll: i128) -> Option<Critical> {
+    if faces < 2 {
+        return None;
+    }
+    if roll == faces as i128 {
+        return Some(Critical::Success);
+    }
+    if roll == 1 {
+        return Some(Critical::Failure);
+    }
+
+    return None;
+}
+
+impl ASTExecutable for DynamicRollAST {
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        let die = self.die.execute_ast_in(dice, env)?;
+        let faces = self.faces.execute_ast_in(dice, env)?;
+
+        let (die_count, faces_count) = dynamic_roll_size(die.result, faces.result)?;
+
+        let roll = LongRollAST {
+            die: die_count,
+            faces: faces_count,
+            keep_high: self.keep_high,
+            keep_low: self.keep_low,
+            drop_high: self.drop_high,
+            drop_low: self.drop_low,
+            critical_success: self.critical_success.clone(),
+            critical_failure: self.critical_failure.clone(),
+        }
+        .execute_ast_in(dice, env)?;
+
+        return Ok(ASTExecutionResult {
+            result: roll.result,
+            description: env.describe(|| {
+                format!(
+                    "{}{}{}",
+                    die.description, faces.description, roll.description
+                )
+            }),
+            list: None,
+            rolls: [die.rolls, faces.rolls, roll.rolls].concat(),
+            subtotals: Vec::new(),
+            criticals: die.criticals + faces.criticals + roll.criticals,
+        });
+    }
+}
+
+impl ASTExecutable for MathAST {
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        let left = self.left.execute_ast_in(dice, env)?;
+        let right = self.right.execute_ast_in(dice, env)?;
+
+        let result = self.operation.apply(left.result, right.result)?;
+        let subtotals = self.operation.apply_to_subtotals(&left, &right)?;
+
+        return Ok(ASTExecutionResult {
+            result: result,
+            description: env.describe(|| format!("{}{}", left.description, right.description)),
+            list: None,
+            rolls: [left.rolls, right.rolls].concat(),
+            subtotals: subtotals,
+            criticals: left.criticals + right.criticals,
+        });
+    }
+}
+
+impl ASTExecutable for VariableAST {
+    fn execute_ast_in(
+        &self,
+        _dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        let Some(value) = env.lookup(&self.name) else {
+            return Err(DiceError::with_span(
+                &format!("Undefined variable: {}", self.name),
+                self.span,
+            ));
+        };
+
+        return Ok(ASTExecutionResult {
+            result: value,
+            description: env.describe(|| format!("\nUsing {} = {}\n", self.name, value)),
+            list: None,
+            rolls: Vec::new(),
+            subtotals: Vec::new(),
+            criticals: Criticals::default(),
+        });
+    }
+}
+
+impl ASTExecutable for AttributeAST {
+    fn execute_ast_in(
+        &self,
+        _dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        let Some(value) = env.attribute(&self.name) else {
+            return Err(DiceError::with_span(
+                &format!("Undefined attribute: @{}", self.name),
+                self.span,
+            ));
+        };
+
+        return Ok(ASTExecutionResult {
+            result: value,
+            description: env.describe(|| format!("\nUsing @{} = {}\n", self.name, value)),
+            list: None,
+            rolls: Vec::new(),
+            subtotals: Vec::new(),
+            criticals: Criticals::default(),
+        });
+    }
+}
+
+impl ASTExecutable for LetAST {
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        let value = self.value.execute_ast_in(dice, env)?;
+
+        env.bind(&self.name, value.result);
+        let body = self.body.execute_ast_in(dice, env);
+        env.unbind();
+        let body = body?;
+
+        return Ok(ASTExecutionResult {
+            result: body.result,
+            description: env.describe(|| {
+                format!(
+                    "{}\nBinding {} = {}\n{}",
+                    value.description, self.name, value.result, body.description
+                )
+            }),
+            list: body.list,
+            rolls: [value.rolls, body.rolls].concat(),
+            subtotals: body.subtotals,
+            criticals: value.criticals + body.criticals,
+        });
+    }
+}
+
+impl ASTExecutable for ConditionalAST {
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        let condition = self.condition.execute_ast_in(dice, env)?;
+
+        let branch = match condition.result {
+            0 => self.if_false.execute_ast_in(dice, env)?,
+            _ => self.if_true.execute_ast_in(dice, env)?,
+        };
+
+        return Ok(ASTExecutionResult {
+            result: branch.result,
+            description: env
+                .describe(|| format!("{}{}", condition.description, branch.description)),
+            list: branch.list,
+            rolls: [condition.rolls, branch.rolls].concat(),
+            subtotals: branch.subtotals,
+            criticals: condition.criticals + branch.criticals,
+        });
+    }
+}
+
+impl ASTExecutable for RepeatAST {
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        check_repeat_count(self.count)?;
+
+        let mut values: Vec<i128> = Vec::new();
+        let mut descriptions: Vec<String> = Vec::new();
+        let mut entry_rolls: Vec<Vec<DieRoll>> = Vec::new();
+        let mut entry_subtotals: Vec<Vec<(String, i128)>> = Vec::new();
+        let mut entry_criticals: Vec<Criticals> = Vec::new();
+
+        for _ in 0..self.count {
+            env.step()?;
+            let entry = self.expression.execute_ast_in(dice, env)?;
+            values.push(entry.result);
+            descriptions.push(entry.description);
+            entry_rolls.push(entry.rolls);
+            entry_subtotals.push(entry.subtotals);
+            entry_criticals.push(entry.criticals);
+        }
+
+        let kept = kept_mask(
+            &values,
+            self.keep_high,
+            self.keep_low,
+            self.drop_high,
+            self.drop_low,
+        );
+
+        let mut description = env.describe(|| format!("\nRepeating {} times...\n", self.count));
+        let mut list: Vec<i128> = Vec::new();
+
+        for (idx, entry_description) in descriptions.iter().enumerate() {
+            if kept[idx] {
+                list.push(values[idx]);
+            }
+            if !env.records_descriptions() {
+                continue;
             }
-        } else if let Some(keep_high) = self.keep_high {
-            if keep_high < self.die {
-                rolls.sort_unstable();
-                sum = 0;
 
-                for &roll in rolls.iter().rev().take(keep_high as usize) {
-                    sum = sum + roll as i128;
-                }
+            description.push_str(entry_description);
+            if kept[idx] {
+                description.push_str(&format!("Entry {}: {}\n", idx + 1, values[idx]));
+            } else {
+                description.push_str(&format!("Entry {}: {} (dropped)\n", idx + 1, values[idx]));
             }
         }
 
+        match self.sort {
+            Some(SortOrder::Ascending) => list.sort_unstable(),
+            Some(SortOrder::Descending) => list.sort_unstable_by(|a, b| b.cmp(a)),
+            None => {}
+        }
+
         return Ok(ASTExecutionResult {
-            result: sum,
+            result: checked_sum(list.iter().copied())?,
             description: description,
+            list: Some(list),
+            rolls: drop_unkept(entry_rolls, &kept),
+            subtotals: merge_kept_subtotals(entry_subtotals, &kept)?,
+            criticals: sum_kept_criticals(&entry_criticals, &kept),
         });
     }
 }
 
-impl ASTExecutable for MathAST {
-    fn execute_ast(&self, rng: &mut impl rand::Rng) -> Result<ASTExecutionResult, DiceError> {
-        let left = self.left.execute_ast(rng)?;
-        let right = self.right.execute_ast(rng)?;
-
-        let result = match self.operation {
-            MathOperation::Add => left.result + right.result,
-            MathOperation::Subtract => left.result - right.result,
-            MathOperation::Multiply => left.result * right.result,
-            MathOperation::Divide => {
-                if right.result == 0 {
-                    return Err(DiceError::new("Division by zero is not allowed."));
+impl ASTExecutable for GroupAST {
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        let mut totals: Vec<i128> = Vec::new();
+        let mut child_rolls: Vec<Vec<DieRoll>> = Vec::new();
+        let mut child_subtotals: Vec<Vec<(String, i128)>> = Vec::new();
+        let mut child_criticals: Vec<Criticals> = Vec::new();
+        let mut description =
+            env.describe(|| format!("\nRolling a group of {}...\n", self.children.len()));
+
+        for child in &self.children {
+            env.step()?;
+            let child_result = child.execute_ast_in(dice, env)?;
+            totals.push(child_result.result);
+            description.push_str(&child_result.description);
+            child_rolls.push(child_result.rolls);
+            child_subtotals.push(child_result.subtotals);
+            child_criticals.push(child_result.criticals);
+        }
+
+        let kept = kept_mask(
+            &totals,
+            self.keep_high,
+            self.keep_low,
+            self.drop_high,
+            self.drop_low,
+        );
+        let mut result: i128 = 0;
+
+        for (idx, total) in totals.iter().enumerate() {
+            let outcome = if !kept[idx] {
+                " (dropped)"
+            } else if let Some(success) = &self.success {
+                match success.is_met_by(*total) {
+                    true => {
+                        result = MathOperation::Add.apply(result, 1)?;
+                        " (success)"
+                    }
+                    false => " (failure)",
                 }
+            } else {
+                result = MathOperation::Add.apply(result, *total)?;
+                " (kept)"
+            };
 
-                left.result / right.result
+            if env.records_descriptions() {
+                description.push_str(&format!("Group entry {}: {}{}\n", idx + 1, total, outcome));
             }
+        }
+
+        // A success count isn't a sum of the children, so their subtotals don't add up to it.
+        let subtotals = match self.success {
+            Some(_) => Vec::new(),
+            None => merge_kept_subtotals(child_subtotals, &kept)?,
         };
 
         return Ok(ASTExecutionResult {
             result: result,
-            description: format!("{}{}", left.description, right.description),
+            description: description,
+            list: None,
+            rolls: drop_unkept(child_rolls, &kept),
+            subtotals: subtotals,
+            criticals: sum_kept_criticals(&child_criticals, &kept),
+        });
+    }
+}
+
+impl ASTExecutable for LabelAST {
+    fn execute_ast_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &mut Environment,
+    ) -> Result<ASTExecutionResult, DiceError> {
+        let inner = self.expression.execute_ast_in(dice, env)?;
+
+        // Labels inside this one keep their own subtotals, but a label repeated inside itself
+        // isn't counted twice.
+        let mut subtotals = inner.subtotals;
+        match subtotals.iter_mut().find(|(label, _)| *label == self.label) {
+            Some(subtotal) => subtotal.1 = inner.result,
+            None => subtotals.insert(0, (self.label.clone(), inner.result)),
+        }
+
+        return Ok(ASTExecutionResult {
+            result: inner.result,
+            description: env.describe(|| {
+                format!(
+                    "{}\nSubtotal [{}]: {}\n",
+                    inner.description, self.label, inner.result
+                )
+            }),
+            list: inner.list,
+            rolls: inner.rolls,
+            subtotals: subtotals,
+            criticals: inner.criticals,
         });
     }
 }
 
+// Spans only locate a name in the text it was parsed from, so they don't take part in equality.
+impl PartialEq for VariableAST {
+    fn eq(&self, other: &VariableAST) -> bool {
+        return self.name == other.name;
+    }
+}
+
+impl PartialEq for AttributeAST {
+    fn eq(&self, other: &AttributeAST) -> bool {
+        return self.name == other.name;
+    }
+}
+
+impl MathOperation {
+    pub fn apply(&self, left: i128, right: i128) -> Result<i128, DiceError> {
+        let result = match self {
+            MathOperation::Add => left.checked_add(right),
+            MathOperation::Subtract => left.checked_sub(right),
+            MathOperation::Multiply => left.checked_mul(right),
+            MathOperation::Divide => {
+                if right == 0 {
+                    return Err(DiceError::new("Division by zero is not allowed."));
+                }
+
+                left.checked_div(right)
+            }
+            MathOperation::Equal => Some((left == right) as i128),
+            MathOperation::NotEqual => Some((left != right) as i128),
+            MathOperation::Greater => Some((left > right) as i128),
+            MathOperation::GreaterOrEqual => Some((left >= right) as i128),
+            MathOperation::Less => Some((left < right) as i128),
+            MathOperation::LessOrEqual => Some((left <= right) as i128),
+        };
+
+        return result.ok_or_else(|| DiceError::new("The result is too large to calculate."));
+    }
+
+    // Works out the labelled subtotals of `left` and `right` after this operation. Sums and
+    // differences combine the subtotals of both sides, while a product or quotient scales the
+    // subtotals of one side by the other side's total. Comparisons have no subtotals.
+    fn apply_to_subtotals(
+        &self,
+        left: &ASTExecutionResult,
+        right: &ASTExecutionResult,
+    ) -> Result<Vec<(String, i128)>, DiceError> {
+        let scale = |subtotals: &[(String, i128)], operation: MathOperation, factor: i128| {
+            return subtotals
+                .iter()
+                .map(|(label, value)| Ok((label.clone(), operation.apply(*value, factor)?)))
+                .collect::<Result<Vec<(String, i128)>, DiceError>>();
+        };
+
+        return match self {
+            MathOperation::Add | MathOperation::Subtract => {
+                let mut subtotals = left.subtotals.clone();
+                for (label, value) in &right.subtotals {
+                    let value = match self {
+                        MathOperation::Subtract => MathOperation::Subtract.apply(0, *value)?,
+                        _ => *value,
+                    };
+                    add_subtotal(&mut subtotals, label, value)?;
+                }
+
+                Ok(subtotals)
+            }
+            MathOperation::Multiply if right.subtotals.is_empty() => {
+                scale(&left.subtotals, MathOperation::Multiply, right.result)
+            }
+            MathOperation::Multiply if left.subtotals.is_empty() => {
+                scale(&right.subtotals, MathOperation::Multiply, left.result)
+            }
+            MathOperation::Divide if right.subtotals.is_empty() => {
+                scale(&left.subtotals, MathOperation::Divide, right.result)
+            }
+            _ => Ok(Vec::new()),
+        };
+    }
+}
+
+impl Criticals {
+    /// The criticals written as `critical success` or `2 critical successes, 1 critical failure`,
+    /// or `None` when no die rolled a critical.
+    pub fn summary(&self) -> Option<String> {
+        let mut parts: Vec<String> = Vec::new();
+
+        match self.successes {
+            0 => {}
+            1 => parts.push("critical success".to_string()),
+            count => parts.push(format!("{} critical successes", count)),
+        }
+        match self.failures {
+            0 => {}
+            1 => parts.push("critical failure".to_string()),
+            count => parts.push(format!("{} critical failures", count)),
+        }
+
+        return match parts.is_empty() {
+            true => None,
+            false => Some(parts.join(", ")),
+        };
+    }
+
+    fn count(&mut self, critical: Option<Critical>) {
+        match critical {
+            Some(Critical::Success) => self.successes += 1,
+            Some(Critical::Failure) => self.failures += 1,
+            None => {}
+        }
+    }
+}
+
+impl Add for Criticals {
+    type Output = Criticals;
+
+    fn add(self, other: Criticals) -> Criticals {
+        return Criticals {
+            successes: self.successes + other.successes,
+            failures: self.failures + other.failures,
+        };
+    }
+}
+
+impl SuccessCondition {
+    pub fn is_met_by(&self, value: i128) -> bool {
+        let target = self.target as i128;
+
+        return match self.comparison {
+            Comparison::Equal => value == target,
+            Comparison::NotEqual => value != target,
+            Comparison::Greater => value > target,
+            Comparison::GreaterOrEqual => value >= target,
+            Comparison::Less => value < target,
+            Comparison::LessOrEqual => value <= target,
+        };
+    }
+}
+
+impl ASTExecutionResult {
+    pub fn to_json(&self) -> JsonValue {
+        let mut members = vec![("result".to_string(), JsonValue::Integer(self.result))];
+
+        if let Some(list) = &self.list {
+            let entries = list
+                .iter()
+                .map(|value| JsonValue::Integer(*value))
+                .collect();
+            members.push(("list".to_string(), JsonValue::Array(entries)));
+        }
+
+        members.push((
+            "description".to_string(),
+            JsonValue::String(self.description.clone()),
+        ));
+
+        let rolls = self
+            .rolls
+            .iter()
+            .map(|roll| {
+                JsonValue::Object(vec![
+                    ("faces".to_string(), JsonValue::Integer(roll.faces as i128)),
+                    ("value".to_string(), JsonValue::Integer(roll.value as i128)),
+                    ("kept".to_string(), JsonValue::Bool(roll.kept)),
+                    (
+                        "critical".to_string(),
+                        match roll.critical {
+                            Some(Critical::Success) => JsonValue::String("success".to_string()),
+                            Some(Critical::Failure) => JsonValue::String("failure".to_string()),
+                            None => JsonValue::Null,
+                        },
+                    ),
+                ])
+            })
+            .collect();
+        members.push(("rolls".to_string(), JsonValue::Array(rolls)));
+
+        let subtotals = self
+            .subtotals
+            .iter()
+            .map(|(label, value)| (label.clone(), JsonValue::Integer(*value)))
+            .collect();
+        members.push(("subtotals".to_string(), JsonValue::Object(subtotals)));
+        members.push((
+            "criticals".to_string(),
+            JsonValue::Object(vec![
+                (
+                    "successes".to_string(),
+                    JsonValue::Integer(self.criticals.successes as i128),
+                ),
+                (
+                    "failures".to_string(),
+                    JsonValue::Integer(self.criticals.failures as i128),
+                ),
+            ]),
+        ));
+
+        return JsonValue::Object(members);
+    }
+
+    /// The labelled subtotals written as `fire: 7, slashing: 5`, or `None` without any labels.
+    pub fn subtotals_summary(&self) -> Option<String> {
+        if self.subtotals.is_empty() {
+            return None;
+        }
+
+        return Some(
+            self.subtotals
+                .iter()
+                .map(|(label, value)| format!("{}: {}", label, value))
+                .collect::<Vec<String>>()
+                .join(", "),
+        );
+    }
+}
+
+pub(crate) fn roll_die(
+    dice: &mut impl DiceSource,
+    faces: u64,
+    env: &Environment,
+) -> Result<u64, DiceError> {
+    env.step()?;
+
+    return match faces {
+        0 => Ok(0),
+        1 => Ok(1),
+        _ => dice.roll(faces),
+    };
+}
+
+pub(crate) fn checked_sum(values: impl IntoIterator<Item = i128>) -> Result<i128, DiceError> {
+    return values
+        .into_iter()
+        .try_fold(0, |sum, value| MathOperation::Add.apply(sum, value));
+}
+
+pub(crate) fn check_dice_count(die: u64) -> Result<(), DiceError> {
+    if die > MAX_DICE {
+        return Err(DiceError::new(&format!(
+            "Cannot roll more than {} dice at once (tried to roll {}).",
+            MAX_DICE, die
+        )));
+    }
+
+    return Ok(());
+}
+
+pub(crate) fn check_repeat_count(count: u64) -> Result<(), DiceError> {
+    if count > MAX_REPEATS {
+        return Err(DiceError::new(&format!(
+            "Cannot repeat an expression more than {} times (tried to repeat it {} times).",
+            MAX_REPEATS, count
+        )));
+    }
+
+    return Ok(());
+}
+
+// Converts the evaluated dice count and face count of a dynamic roll into the size of a roll.
+// Dice counts too large for a u64 are left for the dice limit to reject.
+pub(crate) fn dynamic_roll_size(die: i128, faces: i128) -> Result<(u64, u64), DiceError> {
+    if die < 0 {
+        return Err(DiceError::new(&format!(
+            "Cannot roll a negative number of dice ({}).",
+            die
+        )));
+    }
+    if faces < 0 {
+        return Err(DiceError::new(&format!(
+            "Cannot roll dice with a negative number of faces ({}).",
+            faces
+        )));
+    }
+    let Ok(faces_count) = u64::try_from(faces) else {
+        return Err(DiceError::new(&format!(
+            "Cannot roll dice with {} faces.",
+            faces
+        )));
+    };
+
+    return Ok((u64::try_from(die).unwrap_or(u64::MAX), faces_count));
+}
+
+// Joins the dice of each repeat or group entry, marking the dice of dropped entries as not kept.
+fn drop_unkept(entry_rolls: Vec<Vec<DieRoll>>, kept: &[bool]) -> Vec<DieRoll> {
+    let mut rolls: Vec<DieRoll> = Vec::new();
+
+    for (entry, is_kept) in entry_rolls.into_iter().zip(kept) {
+        rolls.extend(entry.into_iter().map(|roll| DieRoll {
+            kept: roll.kept && *is_kept,
+            critical: roll.critical.filter(|_| *is_kept),
+            ..roll
+        }));
+    }
+
+    return rolls;
+}
+
+// Adds `value` to the subtotal of `label`, starting a new subtotal the first time a label is seen.
+fn add_subtotal(
+    subtotals: &mut Vec<(String, i128)>,
+    label: &str,
+    value: i128,
+) -> Result<(), DiceError> {
+    match subtotals.iter_mut().find(|(existing, _)| existing == label) {
+        Some(subtotal) => subtotal.1 = MathOperation::Add.apply(subtotal.1, value)?,
+        None => subtotals.push((label.to_string(), value)),
+    }
+
+    return Ok(());
+}
+
+// Sums the subtotals of the kept repeat or group entries.
+fn merge_kept_subtotals(
+    entry_subtotals: Vec<Vec<(String, i128)>>,
+    kept: &[bool],
+) -> Result<Vec<(String, i128)>, DiceError> {
+    let mut subtotals: Vec<(String, i128)> = Vec::new();
+
+    for (entry, is_kept) in entry_subtotals.into_iter().zip(kept) {
+        if !is_kept {
+            continue;
+        }
+        for (label, value) in entry {
+            add_subtotal(&mut subtotals, &label, value)?;
+        }
+    }
+
+    return Ok(subtotals);
+}
+
+fn sum_kept_criticals(entry_criticals: &[Criticals], kept: &[bool]) -> Criticals {
+    return entry_criticals
+        .iter()
+        .zip(kept)
+        .filter(|(_, is_kept)| **is_kept)
+        .fold(Criticals::default(), |sum, (criticals, _)| sum + *criticals);
+}
+
+// Marks which values survive a keep or drop modifier. Only one modifier applies, checked in the
+// order keep low, keep high, drop low, drop high, and ties are broken by position.
+pub(crate) fn kept_mask<T: PartialOrd>(
+    values: &[T],
+    keep_high: Option<u64>,
+    keep_low: Option<u64>,
+    drop_high: Option<u64>,
+    drop_low: Option<u64>,
+) -> Vec<bool> {
+    let count = values.len();
+    let clamp = |n: u64| (n as usize).min(count);
+
+    let (discard_low, discard_high) = if let Some(keep_low) = keep_low {
+        (0, count - clamp(keep_low))
+    } else if let Some(keep_high) = keep_high {
+        (count - clamp(keep_high), 0)
+    } else if let Some(drop_low) = drop_low {
+        (clamp(drop_low), 0)
+    } else if let Some(drop_high) = drop_high {
+        (0, clamp(drop_high))
+    } else {
+        (0, 0)
+    };
+
+    let mut order: Vec<usize> = (0..count).collect();
+    order.sort_by(|a, b| {
+        values[*a]
+            .partial_cmp(&values[*b])
+            .unwrap_or(Ordering::Equal)
+    });
+
+    let mut kept = vec![true; count];
+    for idx in order.iter().take(discard_low) {
+        kept[*idx] = false;
+    }
+    for idx in order.iter().rev().take(discard_high) {
+        kept[*idx] = false;
+    }
+
+    return kept;
+}
+
 #[cfg(test)]
 #[allow(non_snake_case)]
 mod tests {
+    use std::collections::HashMap;
+
     use rand::SeedableRng;
 
     use super::*;
@@ -175,8 +1209,8 @@ mod tests {
 
         let result = ast.execute_ast(&mut rng).unwrap();
 
-        assert_eq!(9 as i128, result.result);
-        assert_eq!("\nRolling d10...\nYou rolled: 9\n", result.description);
+        assert_eq!(10 as i128, result.result);
+        assert_eq!("\nRolling d10...\nYou rolled: 10\n", result.description);
     }
 
     #[test]
@@ -208,14 +1242,18 @@ mod tests {
             faces: 10,
             keep_high: None,
             keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
         });
         let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
 
         let result = ast.execute_ast(&mut rng).unwrap();
 
-        assert_eq!(16, result.result);
+        assert_eq!(17, result.result);
         assert_eq!(
-            "\nRolling 2d10...\nYou rolled: 9\nYou rolled: 7\n",
+            "\nRolling 2d10...\nYou rolled: 10\nYou rolled: 7\n",
             result.description
         );
     }
@@ -227,6 +1265,10 @@ mod tests {
             faces: 10,
             keep_high: None,
             keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
         });
         let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
 
@@ -243,6 +1285,10 @@ mod tests {
             faces: 0,
             keep_high: None,
             keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
         });
         let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
 
@@ -262,14 +1308,18 @@ mod tests {
             faces: 10,
             keep_high: Some(1),
             keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
         });
         let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
 
         let result = ast.execute_ast(&mut rng).unwrap();
 
-        assert_eq!(9, result.result);
+        assert_eq!(10, result.result);
         assert_eq!(
-            "\nRolling 2d10...\nYou rolled: 9\nYou rolled: 7\n",
+            "\nRolling 2d10...\nYou rolled: 10\nYou rolled: 7 (dropped)\n",
             result.description
         );
     }
@@ -281,6 +1331,10 @@ mod tests {
             faces: 10,
             keep_high: None,
             keep_low: Some(1),
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
         });
         let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
 
@@ -288,7 +1342,7 @@ mod tests {
 
         assert_eq!(7, result.result);
         assert_eq!(
-            "\nRolling 2d10...\nYou rolled: 9\nYou rolled: 7\n",
+            "\nRolling 2d10...\nYou rolled: 10 (dropped)\nYou rolled: 7\n",
             result.description
         );
     }
@@ -300,6 +1354,10 @@ mod tests {
             faces: 10,
             keep_high: Some(1),
             keep_low: Some(1),
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
         });
         let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
 
@@ -307,7 +1365,7 @@ mod tests {
 
         assert_eq!(7, result.result);
         assert_eq!(
-            "\nRolling 2d10...\nYou rolled: 9\nYou rolled: 7\n",
+            "\nRolling 2d10...\nYou rolled: 10 (dropped)\nYou rolled: 7\n",
             result.description
         );
     }
@@ -412,9 +1470,748 @@ mod tests {
 
         let result = ast.execute_ast(&mut rng).unwrap();
 
-        assert_eq!(10 as i128, result.result);
+        assert_eq!(12 as i128, result.result);
+        assert_eq!(
+            "\nRolling d10...\nYou rolled: 10\n\nRolling d2...\nYou rolled: 2\n",
+            result.description
+        );
+    }
+
+    #[test]
+    fn MathAST__execute_ast__comparison_operation__returns_one_when_true_and_zero_when_false() {
+        let ast = AST::Math(MathAST {
+            operation: MathOperation::GreaterOrEqual,
+            left: Box::new(AST::Integer(IntegerAST { integer: 20 })),
+            right: Box::new(AST::Integer(IntegerAST { integer: 20 })),
+        });
+        let inverse = AST::Math(MathAST {
+            operation: MathOperation::Less,
+            left: Box::new(AST::Integer(IntegerAST { integer: 20 })),
+            right: Box::new(AST::Integer(IntegerAST { integer: 20 })),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        assert_eq!(1, ast.execute_ast(&mut rng).unwrap().result);
+        assert_eq!(0, inverse.execute_ast(&mut rng).unwrap().result);
+    }
+
+    #[test]
+    fn VariableAST__execute_ast__unbound_name__returns_DiceError() {
+        let ast = AST::Variable(VariableAST {
+            name: "a".to_string(),
+            span: Span::new(4, 5),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match ast.execute_ast(&mut rng) {
+            Err(err) => {
+                assert_eq!("Undefined variable: a", err.message);
+                assert_eq!(Some(Span::new(4, 5)), err.span);
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn LetAST__execute_ast__variable_used_twice__rolls_the_value_only_once() {
+        let ast = AST::Let(LetAST {
+            name: "a".to_string(),
+            value: Box::new(AST::ShortRoll(ShortRollAST { faces: 10 })),
+            body: Box::new(AST::Math(MathAST {
+                operation: MathOperation::Add,
+                left: Box::new(AST::Variable(VariableAST {
+                    name: "a".to_string(),
+                    span: Span::default(),
+                })),
+                right: Box::new(AST::Variable(VariableAST {
+                    name: "a".to_string(),
+                    span: Span::default(),
+                })),
+            })),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(20, result.result);
+        assert_eq!(
+            "\nRolling d10...\nYou rolled: 10\n\nBinding a = 10\n\nUsing a = 10\n\nUsing a = 10\n",
+            result.description
+        );
+    }
+
+    #[test]
+    fn LetAST__execute_ast__body_finished__binding_is_no_longer_visible() {
+        let ast = AST::Let(LetAST {
+            name: "a".to_string(),
+            value: Box::new(AST::Integer(IntegerAST { integer: 3 })),
+            body: Box::new(AST::Variable(VariableAST {
+                name: "a".to_string(),
+                span: Span::default(),
+            })),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let mut env = Environment::new();
+
+        let result = ast.execute_ast_in(&mut rng, &mut env).unwrap();
+
+        assert_eq!(3, result.result);
+        assert_eq!(None, env.lookup("a"));
+    }
+
+    #[test]
+    fn ConditionalAST__execute_ast__condition_false__only_executes_the_false_branch() {
+        let ast = AST::Conditional(ConditionalAST {
+            condition: Box::new(AST::Integer(IntegerAST { integer: 0 })),
+            if_true: Box::new(AST::ShortRoll(ShortRollAST { faces: 10 })),
+            if_false: Box::new(AST::Integer(IntegerAST { integer: 7 })),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(7, result.result);
+        assert_eq!("", result.description);
+    }
+
+    #[test]
+    fn AttributeAST__execute_ast__attribute_in_environment__returns_its_value() {
+        let ast = AST::Attribute(AttributeAST {
+            name: "str_mod".to_string(),
+            span: Span::default(),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let mut env = Environment::with_attributes(HashMap::from([("str_mod".to_string(), -1)]));
+
+        let result = ast.execute_ast_in(&mut rng, &mut env).unwrap();
+
+        assert_eq!(-1, result.result);
+        assert_eq!("\nUsing @str_mod = -1\n", result.description);
+    }
+
+    #[test]
+    fn AttributeAST__execute_ast__attribute_not_in_environment__returns_spanned_DiceError() {
+        let ast = AST::Attribute(AttributeAST {
+            name: "prof".to_string(),
+            span: Span::new(7, 12),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match ast.execute_ast(&mut rng) {
+            Err(err) => {
+                assert_eq!("Undefined attribute: @prof", err.message);
+                assert_eq!(Some(Span::new(7, 12)), err.span);
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn LongRollAST__execute_ast__drop_low_N__drops_the_lowest_N_rolls() {
+        let ast = AST::LongRoll(LongRollAST {
+            die: 4,
+            faces: 6,
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: Some(1),
+            critical_success: None,
+            critical_failure: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(14, result.result);
+        assert_eq!(
+            "\nRolling 4d6...\nYou rolled: 6\nYou rolled: 5\nYou rolled: 3\nYou rolled: 2 (dropped)\n",
+            result.description
+        );
+        assert_eq!(
+            vec![(6, true), (5, true), (3, true), (2, false)],
+            result
+                .rolls
+                .iter()
+                .map(|roll| (roll.value, roll.kept))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn RepeatAST__execute_ast__N_times__returns_a_list_of_N_independent_results() {
+        let ast = AST::Repeat(RepeatAST {
+            count: 3,
+            expression: Box::new(AST::ShortRoll(ShortRollAST { faces: 10 })),
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            sort: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(Some(vec![10, 7, 5]), result.list);
+        assert_eq!(22, result.result);
+        assert_eq!(
+            concat!(
+                "\nRepeating 3 times...\n",
+                "\nRolling d10...\nYou rolled: 10\nEntry 1: 10\n",
+                "\nRolling d10...\nYou rolled: 7\nEntry 2: 7\n",
+                "\nRolling d10...\nYou rolled: 5\nEntry 3: 5\n"
+            ),
+            result.description
+        );
+    }
+
+    #[test]
+    fn RepeatAST__execute_ast__keep_high_and_sort_ascending__returns_the_best_entries_sorted() {
+        let ast = AST::Repeat(RepeatAST {
+            count: 3,
+            expression: Box::new(AST::ShortRoll(ShortRollAST { faces: 10 })),
+            keep_high: Some(2),
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            sort: Some(SortOrder::Ascending),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(Some(vec![7, 10]), result.list);
+        assert_eq!(17, result.result);
+        assert!(result.description.contains("Entry 3: 5 (dropped)\n"));
+        assert_eq!(
+            vec![
+                DieRoll {
+                    faces: 10,
+                    value: 10,
+                    kept: true,
+                    critical: Some(Critical::Success),
+                },
+                DieRoll {
+                    faces: 10,
+                    value: 7,
+                    kept: true,
+                    critical: None,
+                },
+                DieRoll {
+                    faces: 10,
+                    value: 5,
+                    kept: false,
+                    critical: None,
+                },
+            ],
+            result.rolls
+        );
+    }
+
+    #[test]
+    fn MathAST__execute_ast__list_operand__uses_the_sum_of_the_list() {
+        let ast = AST::Math(MathAST {
+            operation: MathOperation::Multiply,
+            left: Box::new(AST::Repeat(RepeatAST {
+                count: 3,
+                expression: Box::new(AST::Integer(IntegerAST { integer: 4 })),
+                keep_high: None,
+                keep_low: None,
+                drop_high: None,
+                drop_low: None,
+                sort: None,
+            })),
+            right: Box::new(AST::Integer(IntegerAST { integer: 2 })),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(24, result.result);
+        assert_eq!(None, result.list);
+    }
+
+    #[test]
+    fn GroupAST__execute_ast__keep_high_1__keeps_the_best_child_total() {
+        let ast = AST::Group(GroupAST {
+            children: vec![
+                AST::Math(MathAST {
+                    operation: MathOperation::Add,
+                    left: Box::new(AST::ShortRoll(ShortRollAST { faces: 10 })),
+                    right: Box::new(AST::Integer(IntegerAST { integer: 5 })),
+                }),
+                AST::Math(MathAST {
+                    operation: MathOperation::Add,
+                    left: Box::new(AST::ShortRoll(ShortRollAST { faces: 10 })),
+                    right: Box::new(AST::Integer(IntegerAST { integer: 3 })),
+                }),
+            ],
+            keep_high: Some(1),
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            success: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(15, result.result);
+        assert_eq!(
+            concat!(
+                "\nRolling a group of 2...\n",
+                "\nRolling d10...\nYou rolled: 10\n",
+                "\nRolling d10...\nYou rolled: 7\n",
+                "Group entry 1: 15 (kept)\n",
+                "Group entry 2: 10 (dropped)\n"
+            ),
+            result.description
+        );
+    }
+
+    #[test]
+    fn GroupAST__execute_ast__success_condition__counts_the_children_meeting_it() {
+        let ast = AST::Group(GroupAST {
+            children: vec![
+                AST::Integer(IntegerAST { integer: 12 }),
+                AST::Integer(IntegerAST { integer: 9 }),
+                AST::Integer(IntegerAST { integer: 10 }),
+            ],
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            success: Some(SuccessCondition {
+                comparison: Comparison::GreaterOrEqual,
+                target: 10,
+            }),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(2, result.result);
+        assert!(result.description.contains("Group entry 2: 9 (failure)\n"));
+    }
+
+    #[test]
+    fn LongRollAST__execute_ast__more_than_max_dice__returns_DiceError() {
+        let ast = AST::LongRoll(LongRollAST {
+            die: MAX_DICE + 1,
+            faces: 6,
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match ast.execute_ast(&mut rng) {
+            Err(err) => {
+                assert_eq!(
+                    "Cannot roll more than 10000 dice at once (tried to roll 10001).",
+                    err.message
+                )
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn DynamicRollAST__execute_ast__rolled_dice_count__rolls_that_many_dice_after_the_count() {
+        let ast = AST::DynamicRoll(DynamicRollAST {
+            die: Box::new(AST::ShortRoll(ShortRollAST { faces: 4 })),
+            faces: Box::new(AST::Integer(IntegerAST { integer: 6 })),
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(12, result.result);
+        assert_eq!(
+            concat!(
+                "\nRolling d4...\nYou rolled: 4\n",
+                "\nRolling 4d6...\nYou rolled: 5\nYou rolled: 3\nYou rolled: 2\nYou rolled: 2\n"
+            ),
+            result.description
+        );
+    }
+
+    #[test]
+    fn DynamicRollAST__execute_ast__negative_face_count__returns_DiceError() {
+        let ast = AST::DynamicRoll(DynamicRollAST {
+            die: Box::new(AST::Integer(IntegerAST { integer: 2 })),
+            faces: Box::new(AST::Math(MathAST {
+                operation: MathOperation::Subtract,
+                left: Box::new(AST::Integer(IntegerAST { integer: 1 })),
+                right: Box::new(AST::Integer(IntegerAST { integer: 4 })),
+            })),
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match ast.execute_ast(&mut rng) {
+            Err(err) => {
+                assert_eq!(
+                    "Cannot roll dice with a negative number of faces (-3).",
+                    err.message
+                )
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn DynamicRollAST__execute_ast__huge_dice_count__returns_DiceError() {
+        let ast = AST::DynamicRoll(DynamicRollAST {
+            die: Box::new(AST::Math(MathAST {
+                operation: MathOperation::Multiply,
+                left: Box::new(AST::Integer(IntegerAST { integer: u64::MAX })),
+                right: Box::new(AST::Integer(IntegerAST { integer: 2 })),
+            })),
+            faces: Box::new(AST::Integer(IntegerAST { integer: 6 })),
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match ast.execute_ast(&mut rng) {
+            Err(err) => {
+                assert!(
+                    err.message
+                        .starts_with("Cannot roll more than 10000 dice at once")
+                )
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn MathAST__execute_ast__result_overflows__returns_DiceError() {
+        let ast = AST::Math(MathAST {
+            operation: MathOperation::Multiply,
+            left: Box::new(AST::Integer(IntegerAST { integer: u64::MAX })),
+            right: Box::new(AST::Integer(IntegerAST { integer: u64::MAX })),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match ast.execute_ast(&mut rng) {
+            Err(err) => {
+                assert_eq!("The result is too large to calculate.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn GroupAST__execute_ast__total_overflows__returns_DiceError() {
+        let product = || {
+            AST::Math(MathAST {
+                operation: MathOperation::Multiply,
+                left: Box::new(AST::Integer(IntegerAST {
+                    integer: i64::MAX as u64,
+                })),
+                right: Box::new(AST::Integer(IntegerAST { integer: u64::MAX })),
+            })
+        };
+        let ast = AST::Group(GroupAST {
+            children: vec![product(), product()],
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            success: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match ast.execute_ast(&mut rng) {
+            Err(err) => {
+                assert_eq!("The result is too large to calculate.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn RepeatAST__execute_ast__total_overflows__returns_DiceError() {
+        let ast = AST::Repeat(RepeatAST {
+            count: 2,
+            expression: Box::new(AST::Math(MathAST {
+                operation: MathOperation::Multiply,
+                left: Box::new(AST::Integer(IntegerAST {
+                    integer: i64::MAX as u64,
+                })),
+                right: Box::new(AST::Integer(IntegerAST { integer: u64::MAX })),
+            })),
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            sort: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match ast.execute_ast(&mut rng) {
+            Err(err) => {
+                assert_eq!("The result is too large to calculate.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn AST__serde__every_kind_of_node__round_trips_through_json() {
+        let input = "let a = 1d20 in {a + @prof, (1d4)d6h1}kh1>=10 + 6x(4d6dl1)sd - (a > 3 ? d8 : 2)[acid] + 1d20cs>=19cf1";
+        let tokens = crate::lexer::tokenize(input).unwrap();
+        let ast = crate::parser::parse(&tokens).unwrap();
+
+        let json = serde_json::to_string(&ast).unwrap();
+
+        assert_eq!(ast, serde_json::from_str::<AST>(&json).unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn ASTExecutionResult__serde__list_result__round_trips_through_json() {
+        let ast = AST::Repeat(RepeatAST {
+            count: 3,
+            expression: Box::new(AST::ShortRoll(ShortRollAST { faces: 10 })),
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            sort: None,
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let result = ast.execute_ast(&mut rng).unwrap();
+
+        let json = serde_json::to_string(&result).unwrap();
+
+        assert_eq!(result, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn LongRollAST__execute_ast_in__without_descriptions__rolls_the_same_total_with_no_description()
+    {
+        let ast = AST::LongRoll(LongRollAST {
+            die: 3,
+            faces: 100,
+            keep_high: None,
+            keep_low: Some(2),
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
+        });
+        let plain = AST::LongRoll(LongRollAST {
+            die: 3,
+            faces: 100,
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
+        });
+
+        for ast in [ast, plain] {
+            let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+            let mut quiet_rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+            let result = ast.execute_ast(&mut rng).unwrap();
+            let quiet = ast
+                .execute_ast_in(
+                    &mut quiet_rng,
+                    &mut Environment::new().without_descriptions(),
+                )
+                .unwrap();
+
+            assert_eq!(result.result, quiet.result);
+            assert_eq!("", quiet.description);
+        }
+    }
+
+    #[test]
+    fn RepeatAST__execute_ast_in__without_descriptions__returns_the_same_list() {
+        let ast = AST::Repeat(RepeatAST {
+            count: 4,
+            expression: Box::new(AST::Math(MathAST {
+                operation: MathOperation::Add,
+                left: Box::new(AST::ShortRoll(ShortRollAST { faces: 10 })),
+                right: Box::new(AST::Integer(IntegerAST { integer: 1 })),
+            })),
+            keep_high: Some(3),
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            sort: Some(SortOrder::Descending),
+        });
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let mut quiet_rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = ast.execute_ast(&mut rng).unwrap();
+        let quiet = ast
+            .execute_ast_in(
+                &mut quiet_rng,
+                &mut Environment::new().without_descriptions(),
+            )
+            .unwrap();
+
+        assert_eq!(result.list, quiet.list);
+        assert_eq!("", quiet.description);
+    }
+
+    #[test]
+    fn LabelAST__execute_ast__sum_of_labels__reports_a_subtotal_per_label() {
+        let tokens = crate::lexer::tokenize("2d6[fire] + 1d8[slashing] - d4[fire] + 2").unwrap();
+        let ast = crate::parser::parse(&tokens).unwrap();
+        let mut dice = crate::dice_source::ScriptedDice::new([3, 4, 5, 1]);
+
+        let result = ast.execute_ast(&mut dice).unwrap();
+
+        assert_eq!(13, result.result);
+        assert_eq!(
+            vec![("fire".to_string(), 6), ("slashing".to_string(), 5)],
+            result.subtotals
+        );
+        assert!(result.description.contains("\nSubtotal [fire]: 7\n"));
+    }
+
+    #[test]
+    fn LabelAST__execute_ast__scaled_and_repeated__scales_and_sums_the_kept_subtotals() {
+        let tokens = crate::lexer::tokenize("2 * d6[fire] + 3x(d4[cold])h2").unwrap();
+        let ast = crate::parser::parse(&tokens).unwrap();
+        let mut dice = crate::dice_source::ScriptedDice::new([5, 1, 4, 3]);
+
+        let result = ast.execute_ast(&mut dice).unwrap();
+
+        assert_eq!(17, result.result);
+        assert_eq!(
+            vec![("fire".to_string(), 10), ("cold".to_string(), 7)],
+            result.subtotals
+        );
+        assert_eq!(
+            Some(&JsonValue::Object(vec![
+                ("fire".to_string(), JsonValue::Integer(10)),
+                ("cold".to_string(), JsonValue::Integer(7)),
+            ])),
+            result.to_json().get("subtotals")
+        );
+    }
+
+    #[test]
+    fn LongRollAST__execute_ast__critical_ranges__counts_the_kept_criticals() {
+        let tokens = crate::lexer::tokenize("2d20h1cs>=19cf1 + 3d20cs>=19cf1").unwrap();
+        let ast = crate::parser::parse(&tokens).unwrap();
+        let mut dice = crate::dice_source::ScriptedDice::new([1, 19, 20, 1, 1]);
+
+        let result = ast.execute_ast(&mut dice).unwrap();
+
+        assert_eq!(41, result.result);
+        assert_eq!(
+            Criticals {
+                successes: 2,
+                failures: 2,
+            },
+            result.criticals
+        );
+        assert!(result.description.starts_with(
+            "\nRolling 2d20...\nYou rolled: 1 (dropped)\nYou rolled: 19 (critical success)\n"
+        ));
+        assert_eq!(
+            Some("2 critical successes, 2 critical failures".to_string()),
+            result.criticals.summary()
+        );
+    }
+
+    #[test]
+    fn execute_ast__natural_20_without_critical_ranges__counts_a_critical_success() {
+        for input in ["1d20", "d20"] {
+            let ast = crate::parser::parse(&crate::lexer::tokenize(input).unwrap()).unwrap();
+
+            for mut env in [
+                Environment::new(),
+                Environment::new().without_descriptions(),
+            ] {
+                let mut dice = crate::dice_source::ScriptedDice::new([20]);
+                let result = ast.execute_ast_in(&mut dice, &mut env).unwrap();
+
+                assert_eq!(
+                    Some("critical success".to_string()),
+                    result.criticals.summary()
+                );
+            }
+        }
+
+        let ast = crate::parser::parse(&crate::lexer::tokenize("2d20 + d20").unwrap()).unwrap();
+        let mut dice = crate::dice_source::ScriptedDice::new([1, 7, 20]);
+        let result = ast.execute_ast(&mut dice).unwrap();
+
+        assert_eq!(
+            Some(&JsonValue::Object(vec![
+                ("successes".to_string(), JsonValue::Integer(1)),
+                ("failures".to_string(), JsonValue::Integer(1)),
+            ])),
+            result.to_json().get("criticals")
+        );
+    }
+
+    #[test]
+    fn LongRollAST__execute_ast_in__without_descriptions__still_counts_criticals() {
+        let tokens = crate::lexer::tokenize("{1d20cf1, 1d20cf1}kh1 + 1d20cs20").unwrap();
+        let ast = crate::parser::parse(&tokens).unwrap();
+        let mut dice = crate::dice_source::ScriptedDice::new([1, 12, 20]);
+
+        let result = ast
+            .execute_ast_in(&mut dice, &mut Environment::new().without_descriptions())
+            .unwrap();
+
+        assert_eq!(32, result.result);
+        assert_eq!(
+            Criticals {
+                successes: 1,
+                failures: 0,
+            },
+            result.criticals
+        );
+    }
+
+    #[test]
+    fn RepeatAST__execute_ast__best_of_shorthand__describes_every_roll_and_the_chosen_one() {
+        let tokens = crate::lexer::tokenize("best2of(1d20 + 2) + adv").unwrap();
+        let ast = crate::parser::parse(&tokens).unwrap();
+        let mut dice = crate::dice_source::ScriptedDice::new([8, 15, 4, 11]);
+
+        let result = ast.execute_ast(&mut dice).unwrap();
+
+        assert_eq!(28, result.result);
         assert_eq!(
-            "\nRolling d10...\nYou rolled: 9\n\nRolling d2...\nYou rolled: 1\n",
+            concat!(
+                "\nRepeating 2 times...\n",
+                "\nRolling 1d20...\nYou rolled: 8\n",
+                "Entry 1: 10 (dropped)\n",
+                "\nRolling 1d20...\nYou rolled: 15\n",
+                "Entry 2: 17\n",
+                "\nRolling 2d20...\nYou rolled: 4 (dropped)\nYou rolled: 11\n",
+            ),
             result.description
         );
     }
@@ -0,0 +1,533 @@
+use std::ops::Add;
+use std::ops::Div;
+use std::ops::Mul;
+use std::ops::Sub;
+
+use crate::ast::AST;
+use crate::ast::AttributeAST;
+use crate::ast::Comparison;
+use crate::ast::GroupAST;
+use crate::ast::IntegerAST;
+use crate::ast::LabelAST;
+use crate::ast::LetAST;
+use crate::ast::LongRollAST;
+use crate::ast::MathAST;
+use crate::ast::MathOperation;
+use crate::ast::RepeatAST;
+use crate::ast::ShortRollAST;
+use crate::ast::SortOrder;
+use crate::ast::SuccessCondition;
+use crate::ast::VariableAST;
+use crate::dice_error::Span;
+
+/// A roll of dice which all have the same number of faces, e.g. `Dice::new(4, 6).drop_low(1)`.
+///
+/// `Dice::d(20)` builds the same [`AST`] as parsing `d20`, and `Dice::new(1, 20)` the same as
+/// parsing `1d20`. A roll keeps or drops dice only once, so each of `keep_high`, `keep_low`,
+/// `drop_high`, and `drop_low` replaces the one chosen before it.
+#[derive(Clone)]
+pub struct Dice {
+    die: Option<u64>,
+    faces: u64,
+    keep_high: Option<u64>,
+    keep_low: Option<u64>,
+    drop_high: Option<u64>,
+    drop_low: Option<u64>,
+    critical_success: Option<SuccessCondition>,
+    critical_failure: Option<SuccessCondition>,
+}
+
+impl Dice {
+    pub fn new(die: u64, faces: u64) -> Dice {
+        return Dice {
+            die: Some(die),
+            faces: faces,
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
+        };
+    }
+
+    pub fn d(faces: u64) -> Dice {
+        return Dice {
+            die: None,
+            ..Dice::new(1, faces)
+        };
+    }
+
+    pub fn keep_high(self, count: u64) -> Dice {
+        return Dice {
+            keep_high: Some(count),
+            ..self.without_selection()
+        };
+    }
+
+    pub fn keep_low(self, count: u64) -> Dice {
+        return Dice {
+            keep_low: Some(count),
+            ..self.without_selection()
+        };
+    }
+
+    pub fn drop_high(self, count: u64) -> Dice {
+        return Dice {
+            drop_high: Some(count),
+            ..self.without_selection()
+        };
+    }
+
+    pub fn drop_low(self, count: u64) -> Dice {
+        return Dice {
+            drop_low: Some(count),
+            ..self.without_selection()
+        };
+    }
+
+    pub fn critical_success(self, comparison: Comparison, target: u64) -> Dice {
+        return Dice {
+            critical_success: Some(SuccessCondition {
+                comparison: comparison,
+                target: target,
+            }),
+            ..self
+        };
+    }
+
+    pub fn critical_failure(self, comparison: Comparison, target: u64) -> Dice {
+        return Dice {
+            critical_failure: Some(SuccessCondition {
+                comparison: comparison,
+                target: target,
+            }),
+            ..self
+        };
+    }
+
+    fn without_selection(self) -> Dice {
+        return Dice {
+            keep_high: None,
+            keep_low: None,
+            drop_high: None,
+            drop_low: None,
+            ..self
+        };
+    }
+}
+
+impl From<Dice> for AST {
+    fn from(dice: Dice) -> AST {
+        let has_modifier = dice.keep_high.is_some()
+            || dice.keep_low.is_some()
+            || dice.drop_high.is_some()
+            || dice.drop_low.is_some()
+            || dice.critical_success.is_some()
+            || dice.critical_failure.is_some();
+
+        if dice.die.is_none() && !has_modifier {
+            return AST::ShortRoll(ShortRollAST { faces: dice.faces });
+        }
+
+        return AST::LongRoll(LongRollAST {
+            die: dice.die.unwrap_or(1),
+            faces: dice.faces,
+            keep_high: dice.keep_high,
+            keep_low: dice.keep_low,
+            drop_high: dice.drop_high,
+            drop_low: dice.drop_low,
+            critical_success: dice.critical_success,
+            critical_failure: dice.critical_failure,
+        });
+    }
+}
+
+impl From<u64> for AST {
+    fn from(integer: u64) -> AST {
+        return AST::Integer(IntegerAST { integer: integer });
+    }
+}
+
+/// An expression repeated to produce a list, e.g. `Repeat::new(6, Dice::new(4, 6).drop_low(1))`.
+///
+/// As with [`Dice`], each selection of entries to keep or drop replaces the one chosen before it.
+#[derive(Clone)]
+pub struct Repeat {
+    ast: RepeatAST,
+}
+
+impl Repeat {
+    pub fn new(count: u64, expression: impl Into<AST>) -> Repeat {
+        return Repeat {
+            ast: RepeatAST {
+                count: count,
+                expression: Box::new(expression.into()),
+                keep_high: None,
+                keep_low: None,
+                drop_high: None,
+                drop_low: None,
+                sort: None,
+            },
+        };
+    }
+
+    pub fn keep_high(mut self, count: u64) -> Repeat {
+        self.clear_selection();
+        self.ast.keep_high = Some(count);
+        return self;
+    }
+
+    pub fn keep_low(mut self, count: u64) -> Repeat {
+        self.clear_selection();
+        self.ast.keep_low = Some(count);
+        return self;
+    }
+
+    pub fn drop_high(mut self, count: u64) -> Repeat {
+        self.clear_selection();
+        self.ast.drop_high = Some(count);
+        return self;
+    }
+
+    pub fn drop_low(mut self, count: u64) -> Repeat {
+        self.clear_selection();
+        self.ast.drop_low = Some(count);
+        return self;
+    }
+
+    pub fn sorted(mut self, order: SortOrder) -> Repeat {
+        self.ast.sort = Some(order);
+        return self;
+    }
+
+    fn clear_selection(&mut self) {
+        self.ast.keep_high = None;
+        self.ast.keep_low = None;
+        self.ast.drop_high = None;
+        self.ast.drop_low = None;
+    }
+}
+
+impl From<Repeat> for AST {
+    fn from(repeat: Repeat) -> AST {
+        return AST::Repeat(repeat.ast);
+    }
+}
+
+/// A group of different expressions, such as the better of two attacks with different bonuses.
+/// As with [`Dice`], each selection of entries to keep or drop replaces the one chosen before it.
+///
+/// ```
+/// use dice_algebra_calculator::AST;
+/// use dice_algebra_calculator::builder::Dice;
+/// use dice_algebra_calculator::builder::Group;
+///
+/// let attacks: AST = Group::new([Dice::new(1, 20) + 5, Dice::new(1, 20) + 3])
+///     .keep_high(1)
+///     .into();
+///
+/// assert_eq!("{1d20 + 5, 1d20 + 3}h1", attacks.to_string());
+/// ```
+#[derive(Clone)]
+pub struct Group {
+    ast: GroupAST,
+}
+
+impl Group {
+    pub fn new<T: Into<AST>>(children: impl IntoIterator<Item = T>) -> Group {
+        return Group {
+            ast: GroupAST {
+                children: children.into_iter().map(|child| child.into()).collect(),
+                keep_high: None,
+                keep_low: None,
+                drop_high: None,
+                drop_low: None,
+                success: None,
+            },
+        };
+    }
+
+    pub fn keep_high(mut self, count: u64) -> Group {
+        self.clear_selection();
+        self.ast.keep_high = Some(count);
+        return self;
+    }
+
+    pub fn keep_low(mut self, count: u64) -> Group {
+        self.clear_selection();
+        self.ast.keep_low = Some(count);
+        return self;
+    }
+
+    pub fn drop_high(mut self, count: u64) -> Group {
+        self.clear_selection();
+        self.ast.drop_high = Some(count);
+        return self;
+    }
+
+    pub fn drop_low(mut self, count: u64) -> Group {
+        self.clear_selection();
+        self.ast.drop_low = Some(count);
+        return self;
+    }
+
+    pub fn count_successes(mut self, comparison: Comparison, target: u64) -> Group {
+        self.ast.success = Some(SuccessCondition {
+            comparison: comparison,
+            target: target,
+        });
+        return self;
+    }
+
+    fn clear_selection(&mut self) {
+        self.ast.keep_high = None;
+        self.ast.keep_low = None;
+        self.ast.drop_high = None;
+        self.ast.drop_low = None;
+    }
+}
+
+impl From<Group> for AST {
+    fn from(group: Group) -> AST {
+        return AST::Group(group.ast);
+    }
+}
+
+pub fn variable(name: &str) -> AST {
+    return AST::Variable(VariableAST {
+        name: name.to_string(),
+        span: Span::default(),
+    });
+}
+
+pub fn attribute(name: &str) -> AST {
+    return AST::Attribute(AttributeAST {
+        name: name.to_string(),
+        span: Span::default(),
+    });
+}
+
+pub fn let_in(name: &str, value: impl Into<AST>, body: impl Into<AST>) -> AST {
+    return AST::Let(LetAST {
+        name: name.to_string(),
+        value: Box::new(value.into()),
+        body: Box::new(body.into()),
+    });
+}
+
+pub fn label(label: &str, expression: impl Into<AST>) -> AST {
+    return AST::Label(LabelAST {
+        label: label.to_string(),
+        expression: Box::new(expression.into()),
+    });
+}
+
+fn math(operation: MathOperation, left: impl Into<AST>, right: impl Into<AST>) -> AST {
+    return AST::Math(MathAST {
+        operation: operation,
+        left: Box::new(left.into()),
+        right: Box::new(right.into()),
+    });
+}
+
+macro_rules! impl_math_operators {
+    ($($expression:ty),*) => {
+        $(
+            impl<T: Into<AST>> Add<T> for $expression {
+                type Output = AST;
+
+                fn add(self, right: T) -> AST {
+                    return math(MathOperation::Add, self, right);
+                }
+            }
+
+            impl<T: Into<AST>> Sub<T> for $expression {
+                type Output = AST;
+
+                fn sub(self, right: T) -> AST {
+                    return math(MathOperation::Subtract, self, right);
+                }
+            }
+
+            impl<T: Into<AST>> Mul<T> for $expression {
+                type Output = AST;
+
+                fn mul(self, right: T) -> AST {
+                    return math(MathOperation::Multiply, self, right);
+                }
+            }
+
+            impl<T: Into<AST>> Div<T> for $expression {
+                type Output = AST;
+
+                fn div(self, right: T) -> AST {
+                    return math(MathOperation::Divide, self, right);
+                }
+            }
+
+            impl Add<$expression> for u64 {
+                type Output = AST;
+
+                fn add(self, right: $expression) -> AST {
+                    return math(MathOperation::Add, self, right);
+                }
+            }
+
+            impl Sub<$expression> for u64 {
+                type Output = AST;
+
+                fn sub(self, right: $expression) -> AST {
+                    return math(MathOperation::Subtract, self, right);
+                }
+            }
+
+            impl Mul<$expression> for u64 {
+                type Output = AST;
+
+                fn mul(self, right: $expression) -> AST {
+                    return math(MathOperation::Multiply, self, right);
+                }
+            }
+
+            impl Div<$expression> for u64 {
+                type Output = AST;
+
+                fn div(self, right: $expression) -> AST {
+                    return math(MathOperation::Divide, self, right);
+                }
+            }
+        )*
+    };
+}
+
+impl_math_operators!(AST, Dice, Repeat, Group);
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::ast::ASTExecutable;
+    use crate::lexer;
+    use crate::parser;
+
+    fn assert_same_evaluation(built: AST, input: &str) {
+        let parsed = parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+        let mut built_rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let mut parsed_rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let built_result = built.execute_ast(&mut built_rng).unwrap();
+        let parsed_result = parsed.execute_ast(&mut parsed_rng).unwrap();
+
+        assert_eq!(parsed_result.result, built_result.result);
+        assert_eq!(parsed_result.description, built_result.description);
+        assert_eq!(parsed_result.list, built_result.list);
+        assert_eq!(parsed_result.subtotals, built_result.subtotals);
+        assert_eq!(parsed_result.criticals, built_result.criticals);
+    }
+
+    #[test]
+    fn Dice__keep_high__builds_a_long_roll_with_the_modifier() {
+        let ast: AST = Dice::new(3, 6).keep_high(2).into();
+
+        match ast {
+            AST::LongRoll(roll) => {
+                assert_eq!(3, roll.die);
+                assert_eq!(6, roll.faces);
+                assert_eq!(Some(2), roll.keep_high);
+            }
+            _ => assert!(false, "Should have built a long roll"),
+        }
+    }
+
+    #[test]
+    fn Dice__second_selection__replaces_the_first_and_parses_back() {
+        let built: AST = Dice::new(4, 6).keep_high(3).drop_low(1).into();
+
+        assert_eq!("4d6dl1", built.to_string());
+        assert_eq!(
+            parser::parse(&lexer::tokenize(&built.to_string()).unwrap()).unwrap(),
+            built
+        );
+
+        let repeat: AST = Repeat::new(6, Dice::d(6)).drop_low(1).keep_high(3).into();
+        let group: AST = Group::new([Dice::d(6), Dice::d(8)])
+            .keep_low(1)
+            .drop_high(1)
+            .into();
+
+        assert_eq!("6x(d6)h3", repeat.to_string());
+        assert_eq!("{d6, d8}dh1", group.to_string());
+        for ast in [repeat, group] {
+            assert_eq!(
+                parser::parse(&lexer::tokenize(&ast.to_string()).unwrap()).unwrap(),
+                ast
+            );
+        }
+    }
+
+    #[test]
+    fn Dice__d__builds_a_short_roll() {
+        let ast: AST = Dice::d(20).into();
+
+        assert!(matches!(ast, AST::ShortRoll(ShortRollAST { faces: 20 })));
+    }
+
+    #[test]
+    fn operators__arithmetic_on_dice__evaluates_like_the_parsed_expression() {
+        let built = (Dice::new(2, 6) + 5) * 10 - Dice::d(4) / 2;
+
+        assert_same_evaluation(built, "(2d6 + 5) * 10 - d4 / 2");
+    }
+
+    #[test]
+    fn operators__integer_on_the_left__evaluates_like_the_parsed_expression() {
+        let built = 3 + Dice::new(1, 20).keep_low(1) * 2;
+
+        assert_same_evaluation(built, "3 + 1d20l1 * 2");
+    }
+
+    #[test]
+    fn Repeat__with_modifiers__evaluates_like_the_parsed_expression() {
+        let built = Repeat::new(6, Dice::new(4, 6).drop_low(1))
+            .keep_high(3)
+            .sorted(SortOrder::Descending)
+            .into();
+
+        assert_same_evaluation(built, "6x(4d6dl1)h3sd");
+    }
+
+    #[test]
+    fn Group__with_keep_high__evaluates_like_the_parsed_expression() {
+        let built = Group::new([Dice::new(1, 20) + 5, Dice::new(1, 20) + 3]).keep_high(1) + 2;
+
+        assert_same_evaluation(built, "{1d20 + 5, 1d20 + 3}kh1 + 2");
+    }
+
+    #[test]
+    fn let_in__variable_used_twice__evaluates_like_the_parsed_expression() {
+        let built = let_in("a", Dice::d(20), variable("a") + variable("a"));
+
+        assert_same_evaluation(built, "let a = d20 in a + a");
+    }
+
+    #[test]
+    fn Dice__critical_ranges__evaluates_like_the_parsed_expression() {
+        let built = Dice::new(2, 20)
+            .keep_high(1)
+            .critical_success(Comparison::GreaterOrEqual, 19)
+            .critical_failure(Comparison::Equal, 1)
+            + 5;
+
+        assert_same_evaluation(built, "2d20h1cs>=19cf1 + 5");
+    }
+
+    #[test]
+    fn label__labelled_dice__evaluates_like_the_parsed_expression() {
+        let built = label("fire", Dice::new(2, 6)) + label("slashing", Dice::new(1, 8) + 3);
+
+        assert_same_evaluation(built, "2d6[fire] + (1d8 + 3)[slashing]");
+    }
+}
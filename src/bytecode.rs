@@ -0,0 +1,525 @@
+use crate::ast::AST;
+use crate::ast::MathOperation;
+use crate::ast::SuccessCondition;
+use crate::ast::check_dice_count;
+use crate::ast::check_repeat_count;
+use crate::ast::checked_sum;
+use crate::ast::dynamic_roll_size;
+use crate::ast::kept_mask;
+use crate::ast::roll_die;
+use crate::dice_error::DiceError;
+use crate::dice_error::Span;
+use crate::dice_source::DiceSource;
+use crate::environment::Environment;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Selection {
+    keep_high: Option<u64>,
+    keep_low: Option<u64>,
+    drop_high: Option<u64>,
+    drop_low: Option<u64>,
+}
+
+impl Selection {
+    fn is_empty(&self) -> bool {
+        return self.keep_high.is_none()
+            && self.keep_low.is_none()
+            && self.drop_high.is_none()
+            && self.drop_low.is_none();
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Instruction {
+    Push(i128),
+    Roll {
+        die: u64,
+        faces: u64,
+        selection: Selection,
+    },
+    // Pops the face count, then the dice count.
+    DynamicRoll(Selection),
+    // Pops the right operand, then the left operand.
+    Math(MathOperation),
+    LoadLocal(usize),
+    LoadVariable(String, Span),
+    LoadAttribute(String, Span),
+    Bind,
+    Unbind,
+    JumpIfZero(usize),
+    Jump(usize),
+    BeginRepeat {
+        count: u64,
+        end: usize,
+    },
+    EndRepeat(usize),
+    // Counts a group entry towards the expression's budget of steps before it is executed.
+    Step,
+    // Pops `count` entries and pushes the total of the kept ones (or the number of successes).
+    Select {
+        count: usize,
+        selection: Selection,
+        success: Option<SuccessCondition>,
+    },
+}
+
+/// An [`AST`] compiled to a flat sequence of instructions for a stack machine.
+///
+/// Executing a program rolls the same dice in the same order as executing the [`AST`] it was
+/// compiled from, and fails in the same cases, but only computes the total. It is much faster when
+/// the same expression is rolled many times and the description isn't needed.
+#[derive(Clone, Debug)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+}
+
+pub fn compile(ast: &AST) -> Program {
+    let mut instructions: Vec<Instruction> = Vec::new();
+
+    compile_ast(ast, &mut instructions, &mut Vec::new());
+
+    return Program {
+        instructions: instructions,
+    };
+}
+
+// `scope` holds the names bound by the enclosing `let`s, innermost last, so that a bound name
+// compiles to the position of its value on the stack of locals.
+fn compile_ast(ast: &AST, instructions: &mut Vec<Instruction>, scope: &mut Vec<String>) {
+    match ast {
+        AST::Integer(integer) => instructions.push(Instruction::Push(integer.integer as i128)),
+        AST::ShortRoll(roll) => instructions.push(Instruction::Roll {
+            die: 1,
+            faces: roll.faces,
+            selection: Selection {
+                keep_high: None,
+                keep_low: None,
+                drop_high: None,
+                drop_low: None,
+            },
+        }),
+        AST::LongRoll(roll) => instructions.push(Instruction::Roll {
+            die: roll.die,
+            faces: roll.faces,
+            selection: Selection {
+                keep_high: roll.keep_high,
+                keep_low: roll.keep_low,
+                drop_high: roll.drop_high,
+                drop_low: roll.drop_low,
+            },
+        }),
+        AST::DynamicRoll(roll) => {
+            compile_ast(&roll.die, instructions, scope);
+            compile_ast(&roll.faces, instructions, scope);
+            instructions.push(Instruction::DynamicRoll(Selection {
+                keep_high: roll.keep_high,
+                keep_low: roll.keep_low,
+                drop_high: roll.drop_high,
+                drop_low: roll.drop_low,
+            }));
+        }
+        AST::Math(math) => {
+            compile_ast(&math.left, instructions, scope);
+            compile_ast(&math.right, instructions, scope);
+            instructions.push(Instruction::Math(math.operation.clone()));
+        }
+        AST::Variable(variable) => match scope.iter().rposition(|name| *name == variable.name) {
+            Some(slot) => instructions.push(Instruction::LoadLocal(slot)),
+            None => instructions.push(Instruction::LoadVariable(
+                variable.name.clone(),
+                variable.span,
+            )),
+        },
+        AST::Attribute(attribute) => instructions.push(Instruction::LoadAttribute(
+            attribute.name.clone(),
+            attribute.span,
+        )),
+        AST::Let(binding) => {
+            compile_ast(&binding.value, instructions, scope);
+            instructions.push(Instruction::Bind);
+            scope.push(binding.name.clone());
+            compile_ast(&binding.body, instructions, scope);
+            scope.pop();
+            instructions.push(Instruction::Unbind);
+        }
+        AST::Conditional(conditional) => {
+            compile_ast(&conditional.condition, instructions, scope);
+            let jump_to_false = instructions.len();
+            instructions.push(Instruction::JumpIfZero(0));
+
+            compile_ast(&conditional.if_true, instructions, scope);
+            let jump_to_end = instructions.len();
+            instructions.push(Instruction::Jump(0));
+
+            instructions[jump_to_false] = Instruction::JumpIfZero(instructions.len());
+            compile_ast(&conditional.if_false, instructions, scope);
+            instructions[jump_to_end] = Instruction::Jump(instructions.len());
+        }
+        AST::Repeat(repeat) => {
+            let begin = instructions.len();
+            instructions.push(Instruction::BeginRepeat {
+                count: repeat.count,
+                end: 0,
+            });
+
+            compile_ast(&repeat.expression, instructions, scope);
+            instructions.push(Instruction::EndRepeat(begin + 1));
+
+            instructions[begin] = Instruction::BeginRepeat {
+                count: repeat.count,
+                end: instructions.len(),
+            };
+            instructions.push(Instruction::Select {
+                count: repeat.count as usize,
+                selection: Selection {
+                    keep_high: repeat.keep_high,
+                    keep_low: repeat.keep_low,
+                    drop_high: repeat.drop_high,
+                    drop_low: repeat.drop_low,
+                },
+                success: None,
+            });
+        }
+        AST::Group(group) => {
+            for child in &group.children {
+                instructions.push(Instruction::Step);
+                compile_ast(child, instructions, scope);
+            }
+
+            instructions.push(Instruction::Select {
+                count: group.children.len(),
+                selection: Selection {
+                    keep_high: group.keep_high,
+                    keep_low: group.keep_low,
+                    drop_high: group.drop_high,
+                    drop_low: group.drop_low,
+                },
+                success: group.success.clone(),
+            });
+        }
+        // Labels only name a part of the total, so a program doesn't need them.
+        AST::Label(label) => compile_ast(&label.expression, instructions, scope),
+    }
+}
+
+impl Program {
+    pub fn execute(&self, dice: &mut impl DiceSource) -> Result<i128, DiceError> {
+        return self.execute_in(dice, &Environment::new());
+    }
+
+    pub fn execute_in(
+        &self,
+        dice: &mut impl DiceSource,
+        env: &Environment,
+    ) -> Result<i128, DiceError> {
+        env.begin_execution();
+        let result = self.run(dice, env);
+        env.end_execution();
+
+        return result;
+    }
+
+    fn run(&self, dice: &mut impl DiceSource, env: &Environment) -> Result<i128, DiceError> {
+        let mut stack: Vec<i128> = Vec::new();
+        let mut locals: Vec<i128> = Vec::new();
+        let mut remaining_repeats: Vec<u64> = Vec::new();
+        let mut rolls: Vec<i128> = Vec::new();
+        let mut pc = 0;
+
+        while let Some(instruction) = self.instructions.get(pc) {
+            pc += 1;
+
+            match instruction {
+                Instruction::Push(value) => stack.push(*value),
+                Instruction::Roll {
+                    die,
+                    faces,
+                    selection,
+                } => stack.push(roll(dice, env, *die, *faces, selection, &mut rolls)?),
+                Instruction::DynamicRoll(selection) => {
+                    let faces = pop(&mut stack);
+                    let die = pop(&mut stack);
+                    let (die, faces) = dynamic_roll_size(die, faces)?;
+                    stack.push(roll(dice, env, die, faces, selection, &mut rolls)?);
+                }
+                Instruction::Math(operation) => {
+                    let right = pop(&mut stack);
+                    let left = pop(&mut stack);
+                    stack.push(operation.apply(left, right)?);
+                }
+                Instruction::LoadLocal(slot) => stack.push(locals[*slot]),
+                Instruction::LoadVariable(name, span) => match env.lookup(name) {
+                    Some(value) => stack.push(value),
+                    None => {
+                        return Err(DiceError::with_span(
+                            &format!("Undefined variable: {}", name),
+                            *span,
+                        ));
+                    }
+                },
+                Instruction::LoadAttribute(name, span) => match env.attribute(name) {
+                    Some(value) => stack.push(value),
+                    None => {
+                        return Err(DiceError::with_span(
+                            &format!("Undefined attribute: @{}", name),
+                            *span,
+                        ));
+                    }
+                },
+                Instruction::Bind => locals.push(pop(&mut stack)),
+                Instruction::Unbind => {
+                    locals.pop();
+                }
+                Instruction::JumpIfZero(target) => {
+                    if pop(&mut stack) == 0 {
+                        pc = *target;
+                    }
+                }
+                Instruction::Jump(target) => pc = *target,
+                Instruction::BeginRepeat { count, end } => {
+                    check_repeat_count(*count)?;
+
+                    match count {
+                        0 => pc = *end,
+                        _ => remaining_repeats.push(*count),
+                    }
+                }
+                Instruction::EndRepeat(start) => {
+                    env.step()?;
+                    let remaining = remaining_repeats.last_mut().expect("repeat was begun");
+                    *remaining -= 1;
+
+                    match remaining {
+                        0 => {
+                            remaining_repeats.pop();
+                        }
+                        _ => pc = *start,
+                    }
+                }
+                Instruction::Step => env.step()?,
+                Instruction::Select {
+                    count,
+                    selection,
+                    success,
+                } => {
+                    let entries = stack.split_off(stack.len() - count);
+                    stack.push(select(&entries, selection, success)?);
+                }
+            }
+        }
+
+        return Ok(pop(&mut stack));
+    }
+}
+
+// The compiler only emits instructions which pop values pushed before them.
+fn pop(stack: &mut Vec<i128>) -> i128 {
+    return stack.pop().expect("stack should not be empty");
+}
+
+fn roll(
+    dice: &mut impl DiceSource,
+    env: &Environment,
+    die: u64,
+    faces: u64,
+    selection: &Selection,
+    rolls: &mut Vec<i128>,
+) -> Result<i128, DiceError> {
+    check_dice_count(die)?;
+
+    rolls.clear();
+    for _ in 0..die {
+        rolls.push(roll_die(dice, faces, env)? as i128);
+    }
+
+    return select(rolls, selection, &None);
+}
+
+fn select(
+    values: &[i128],
+    selection: &Selection,
+    success: &Option<SuccessCondition>,
+) -> Result<i128, DiceError> {
+    if selection.is_empty() && success.is_none() {
+        return checked_sum(values.iter().copied());
+    }
+
+    let kept = kept_mask(
+        values,
+        selection.keep_high,
+        selection.keep_low,
+        selection.drop_high,
+        selection.drop_low,
+    );
+    let kept_values = values
+        .iter()
+        .zip(&kept)
+        .filter(|(_, is_kept)| **is_kept)
+        .map(|(value, _)| *value);
+
+    return match success {
+        Some(success) => Ok(kept_values
+            .filter(|value| success.is_met_by(*value))
+            .count() as i128),
+        None => checked_sum(kept_values),
+    };
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::ast::ASTExecutable;
+    use crate::lexer;
+    use crate::parser;
+
+    fn parse(input: &str) -> AST {
+        return parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn execute__every_kind_of_expression__rolls_the_same_total_as_the_tree() {
+        let inputs = [
+            "d5 + 2d6h1 - 3d100l2 + (10 - 2 * 2) / 2",
+            "let a = 1d20 in a + (a >= 15 ? 10 : 0)",
+            "let a = 2 in let b = a * 3 in let a = b + 1 in a * 10 + b",
+            "6x(4d6dl1)h3sd + 3x(d4)",
+            "{1d20 + 5, 1d20 + 3}kh1 + {3d6, 2d8, 1d20}>=10",
+            "(1d4)d6dh1 * 2d(1d8 + 2)l1",
+            "2x(3x(d6)l1) + 0x(d6)",
+            "1d20 + @str_mod * 2",
+        ];
+        let mut env = Environment::with_attributes(HashMap::from([("str_mod".to_string(), 3)]));
+
+        for input in inputs {
+            let ast = parse(input);
+            let program = compile(&ast);
+
+            for seed in 0..20 {
+                let mut tree_rng = rand_chacha::ChaCha12Rng::seed_from_u64(seed);
+                let mut program_rng = rand_chacha::ChaCha12Rng::seed_from_u64(seed);
+
+                let expected = ast.execute_ast_in(&mut tree_rng, &mut env).unwrap().result;
+
+                assert_eq!(
+                    expected,
+                    program.execute_in(&mut program_rng, &env).unwrap(),
+                    "{} with seed {}",
+                    input,
+                    seed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn execute__undefined_variable__returns_spanned_DiceError() {
+        let program = compile(&parse("1 + foo"));
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match program.execute(&mut rng) {
+            Err(err) => {
+                assert_eq!("Undefined variable: foo", err.message);
+                assert_eq!(Some(Span::new(4, 7)), err.span);
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn execute__division_by_zero_in_repeat__returns_DiceError() {
+        let program = compile(&parse("3x(d6 / (d1 - 1))"));
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match program.execute(&mut rng) {
+            Err(err) => {
+                assert_eq!("Division by zero is not allowed.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn execute__nested_repeats_over_the_budget__returns_DiceError() {
+        let program = compile(&parse("10000x(10000x(1d6))"));
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match program.execute(&mut rng) {
+            Err(err) => assert_eq!(
+                "Cannot roll or repeat more than 1000000 times in one expression.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn execute__group_of_entries_over_the_budget__fails_like_the_ast() {
+        let ast = parse("{1000x(999d6), 1}");
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let results = [
+            ast.execute_ast(&mut rng).map(|result| result.result),
+            compile(&ast).execute(&mut rng),
+        ];
+
+        for result in results {
+            match result {
+                Err(err) => assert_eq!(
+                    "Cannot roll or repeat more than 1000000 times in one expression.",
+                    err.message
+                ),
+                Ok(_) => assert!(false, "Should have returned an error"),
+            }
+        }
+    }
+
+    #[test]
+    fn execute__repeat_total_overflows__returns_DiceError() {
+        let program = compile(&parse("2x(9223372036854775807 * 18446744073709551615)"));
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match program.execute(&mut rng) {
+            Err(err) => {
+                assert_eq!("The result is too large to calculate.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn execute__error_only_in_branch_not_taken__returns_the_other_branch() {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        assert_eq!(
+            7,
+            compile(&parse("1 ? 7 : 1 / 0")).execute(&mut rng).unwrap()
+        );
+        assert_eq!(
+            7,
+            compile(&parse("0 ? 20000d6 : 7"))
+                .execute(&mut rng)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn compile__conditional__jumps_over_the_branch_not_taken() {
+        let program = compile(&parse("1 ? 2 : 3"));
+
+        assert_eq!(
+            vec![
+                Instruction::Push(1),
+                Instruction::JumpIfZero(4),
+                Instruction::Push(2),
+                Instruction::Jump(5),
+                Instruction::Push(3),
+            ],
+            program.instructions
+        );
+    }
+}
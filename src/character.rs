@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use crate::dice_error::DiceError;
+use crate::json::JsonValue;
+use crate::json::parse_json;
+
+pub fn load_character(path: &str) -> Result<HashMap<String, i128>, DiceError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            return Err(DiceError::new(&format!(
+                "Failed to read character file {}: {}",
+                path, err
+            )));
+        }
+    };
+
+    if path.ends_with(".json") {
+        return parse_json_character(&contents);
+    }
+
+    if path.ends_with(".toml") {
+        return parse_toml_character(&contents);
+    }
+
+    return Err(DiceError::new(&format!(
+        "Unsupported character file {}: expected a .json or .toml file.",
+        path
+    )));
+}
+
+pub fn parse_json_character(input: &str) -> Result<HashMap<String, i128>, DiceError> {
+    let JsonValue::Object(members) = parse_json(input)? else {
+        return Err(DiceError::new(
+            "A JSON character file must contain a single object.",
+        ));
+    };
+
+    let mut attributes = HashMap::new();
+
+    for (name, value) in members {
+        let JsonValue::Integer(integer) = value else {
+            return Err(DiceError::new(&format!(
+                "Character attribute {} must be an integer.",
+                name
+            )));
+        };
+
+        insert_attribute(&mut attributes, name, integer)?;
+    }
+
+    return Ok(attributes);
+}
+
+// Only the flat subset of TOML a character sheet needs is supported: one `name = integer` pair
+// per line, with blank lines and `#` comments.
+pub fn parse_toml_character(input: &str) -> Result<HashMap<String, i128>, DiceError> {
+    let mut attributes = HashMap::new();
+
+    for (idx, raw_line) in input.lines().enumerate() {
+        let line = match raw_line.split_once('#') {
+            Some((content, _comment)) => content.trim(),
+            None => raw_line.trim(),
+        };
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(invalid_toml_line(idx, raw_line));
+        };
+
+        let Ok(integer) = value.trim().replace('_', "").parse::<i128>() else {
+            return Err(invalid_toml_line(idx, raw_line));
+        };
+
+        insert_attribute(&mut attributes, name.trim().to_string(), integer)?;
+    }
+
+    return Ok(attributes);
+}
+
+fn insert_attribute(
+    attributes: &mut HashMap<String, i128>,
+    name: String,
+    value: i128,
+) -> Result<(), DiceError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphabetic() || c == '_') {
+        return Err(DiceError::new(&format!(
+            "Invalid attribute name: {} (names may only contain letters and underscores).",
+            name
+        )));
+    }
+
+    if attributes.insert(name.clone(), value).is_some() {
+        return Err(DiceError::new(&format!(
+            "Attribute {} is defined more than once.",
+            name
+        )));
+    }
+
+    return Ok(());
+}
+
+fn invalid_toml_line(idx: usize, line: &str) -> DiceError {
+    return DiceError::new(&format!(
+        "Invalid character file line {}: {} (expected `name = integer`).",
+        idx + 1,
+        line.trim()
+    ));
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_json_character__object_of_integers__returns_attributes() {
+        let input = r#"{"str_mod": 3, "prof": 2, "dex_mod": -1}"#;
+
+        let result = parse_json_character(input).unwrap();
+
+        assert_eq!(
+            HashMap::from([
+                ("str_mod".to_string(), 3),
+                ("prof".to_string(), 2),
+                ("dex_mod".to_string(), -1),
+            ]),
+            result
+        );
+    }
+
+    #[test]
+    fn parse_json_character__non_integer_value__returns_error() {
+        let input = r#"{"name": "Brakka"}"#;
+
+        match parse_json_character(input) {
+            Err(err) => {
+                assert_eq!("Character attribute name must be an integer.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse_toml_character__pairs_with_comments__returns_attributes() {
+        let input = "# Brakka, level 5\nstr_mod = 3 # belt of giant strength\n\nprof = 2\n";
+
+        let result = parse_toml_character(input).unwrap();
+
+        assert_eq!(
+            HashMap::from([("str_mod".to_string(), 3), ("prof".to_string(), 2)]),
+            result
+        );
+    }
+
+    #[test]
+    fn parse_toml_character__table_header__returns_error() {
+        let input = "[abilities]\nstr_mod = 3\n";
+
+        match parse_toml_character(input) {
+            Err(err) => {
+                assert_eq!(
+                    "Invalid character file line 1: [abilities] (expected `name = integer`).",
+                    err.message
+                )
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse_toml_character__duplicate_name__returns_error() {
+        let input = "prof = 2\nprof = 3\n";
+
+        match parse_toml_character(input) {
+            Err(err) => {
+                assert_eq!("Attribute prof is defined more than once.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use crate::ast::ASTExecutable;
+use crate::ast::Critical;
+use crate::ast::DieRoll;
+use crate::dice_error::DiceError;
+use crate::dice_source::DiceSource;
+use crate::environment::Environment;
+use crate::lexer;
+use crate::macros::MacroTable;
+use crate::parser;
+use crate::stats;
+
+/// Rolls with more dice than this are summarised by their count instead of listing every die.
+pub const MAX_LISTED_DICE: usize = 20;
+
+/// Who may see a reply. The user who sent the command can always see it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Visibility {
+    /// Everyone in the channel (`/roll` or `/r`).
+    Public,
+    /// Only the user who rolled (`/proll`).
+    Private,
+    /// The user who rolled and the game master (`/gmroll`).
+    GameMaster,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChatCommand {
+    Roll {
+        expression: String,
+        label: Option<String>,
+        visibility: Visibility,
+    },
+    Stats {
+        expression: String,
+        label: Option<String>,
+    },
+}
+
+/// A chat formatted reply (Markdown emphasis, as understood by Discord and Matrix clients), and
+/// who it is for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reply {
+    pub sender: String,
+    pub visibility: Visibility,
+    pub text: String,
+}
+
+/// A chat service which replies are sent through, implemented by each bot.
+pub trait ChatTransport {
+    fn send(&mut self, reply: Reply) -> Result<(), DiceError>;
+}
+
+/// Parses a chat message like `/roll 2d6 + 3 # fire damage`. Messages which aren't one of the
+/// dice commands return `None`, so that bots can pass every message through.
+pub fn parse_command(message: &str) -> Option<Result<ChatCommand, DiceError>> {
+    let message = message.trim();
+    let (command, arguments) = message
+        .split_once(char::is_whitespace)
+        .unwrap_or((message, ""));
+
+    let visibility = match command {
+        "/roll" | "/r" => Some(Visibility::Public),
+        "/proll" => Some(Visibility::Private),
+        "/gmroll" => Some(Visibility::GameMaster),
+        "/stats" => None,
+        _ => return None,
+    };
+
+    let (expression, label) = match arguments.split_once('#') {
+        Some((expression, label)) => (expression.trim(), label.trim()),
+        None => (arguments.trim(), ""),
+    };
+    if expression.is_empty() {
+        return Some(Err(DiceError::new(&format!(
+            "Usage: {} <expression> [# label]",
+            command
+        ))));
+    }
+
+    let expression = expression.to_string();
+    let label = match label.is_empty() {
+        true => None,
+        false => Some(label.to_string()),
+    };
+
+    return Some(Ok(match visibility {
+        Some(visibility) => ChatCommand::Roll {
+            expression: expression,
+            label: label,
+            visibility: visibility,
+        },
+        None => ChatCommand::Stats {
+            expression: expression,
+            label: label,
+        },
+    }));
+}
+
+/// Answers dice commands from any chat service, rolling with its dice source and expanding its
+/// macros.
+pub struct ChatAdapter<D: DiceSource> {
+    dice: D,
+    macros: MacroTable,
+}
+
+impl<D: DiceSource> ChatAdapter<D> {
+    pub fn new(dice: D, macros: MacroTable) -> ChatAdapter<D> {
+        return ChatAdapter {
+            dice: dice,
+            macros: macros,
+        };
+    }
+
+    /// Sends the reply to `message` through `transport`, if the message is a dice command.
+    pub fn handle(
+        &mut self,
+        sender: &str,
+        message: &str,
+        transport: &mut impl ChatTransport,
+    ) -> Result<(), DiceError> {
+        return match self.respond(sender, message) {
+            Some(reply) => transport.send(reply),
+            None => Ok(()),
+        };
+    }
+
+    /// The reply to `message`, if it is a dice command. Commands which fail are answered with
+    /// the error, with the visibility of the command.
+    pub fn respond(&mut self, sender: &str, message: &str) -> Option<Reply> {
+        let command = match parse_command(message)? {
+            Ok(command) => command,
+            Err(err) => {
+                return Some(Reply {
+                    sender: sender.to_string(),
+                    visibility: Visibility::Private,
+                    text: err.message,
+                });
+            }
+        };
+
+        let (expression, label, visibility) = match &command {
+            ChatCommand::Roll {
+                expression,
+                label,
+                visibility,
+            } => (expression, label, *visibility),
+            ChatCommand::Stats { expression, label } => (expression, label, Visibility::Public),
+        };
+
+        let heading = match label {
+            Some(label) => format!("{} `{}` ({})", sender, expression, label),
+            None => format!("{} `{}`", sender, expression),
+        };
+        let outcome = match &command {
+            ChatCommand::Roll { .. } => self.roll(expression),
+            ChatCommand::Stats { .. } => self.stats(expression),
+        };
+
+        return Some(Reply {
+            sender: sender.to_string(),
+            visibility: visibility,
+            text: match outcome {
+                Ok(outcome) => format!("{}: {}", heading, outcome),
+                Err(err) => format!("{} failed: {}", heading, err.message),
+            },
+        });
+    }
+
+    fn roll(&mut self, expression: &str) -> Result<String, DiceError> {
+        let ast = parser::parse_with_macros(&lexer::tokenize(expression)?, &self.macros)?;
+        let result = ast.execute_ast_in(&mut self.dice, &mut Environment::new())?;
+
+        let mut total = match &result.list {
+            Some(list) => format!(
+                "**{}** ({})",
+                result.result,
+                list.iter()
+                    .map(|entry| entry.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            None => format!("**{}**", result.result),
+        };
+        if let Some(summary) = result.subtotals_summary() {
+            total.push_str(&format!(" ({})", summary));
+        }
+
+        return Ok(match result.rolls.len() {
+            0 => total,
+            count if count > MAX_LISTED_DICE => format!("{} dice = {}", count, total),
+            _ => format!("[{}] = {}", render_dice(&result.rolls), total),
+        });
+    }
+
+    fn stats(&mut self, expression: &str) -> Result<String, DiceError> {
+        let ast = parser::parse_with_macros(&lexer::tokenize(expression)?, &self.macros)?;
+        let statistics = stats::sample(
+            &ast,
+            &mut self.dice,
+            &Environment::new(),
+            stats::DEFAULT_SAMPLES,
+        )?;
+
+        return Ok(format!(
+            "mean **{:.2}**, standard deviation {:.2}, from {} to {} over {} rolls",
+            statistics.mean,
+            statistics.standard_deviation,
+            statistics.min,
+            statistics.max,
+            statistics.samples
+        ));
+    }
+}
+
+// Dropped dice are struck through, and kept dice which landed in a critical success range are
+// bold and those in a critical failure range (a fumble) are italic. Without `cs` or `cf`, these
+// are the highest face and 1.
+fn render_dice(rolls: &[DieRoll]) -> String {
+    return rolls
+        .iter()
+        .map(|roll| match (roll.kept, roll.critical) {
+            (false, _) => format!("~~{}~~", roll.value),
+            (true, Some(Critical::Success)) => format!("**{}**", roll.value),
+            (true, Some(Critical::Failure)) => format!("*{}*", roll.value),
+            (true, None) => roll.value.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::dice_source::ScriptedDice;
+
+    // Keeps every reply in memory instead of sending it to a chat service.
+    #[derive(Default)]
+    struct Inbox {
+        replies: Vec<Reply>,
+    }
+
+    impl ChatTransport for Inbox {
+        fn send(&mut self, reply: Reply) -> Result<(), DiceError> {
+            self.replies.push(reply);
+            return Ok(());
+        }
+    }
+
+    fn replies(dice: impl DiceSource, messages: &[&str]) -> Vec<Reply> {
+        let mut adapter = ChatAdapter::new(dice, MacroTable::new());
+        let mut inbox = Inbox::default();
+
+        for message in messages {
+            adapter.handle("alice", message, &mut inbox).unwrap();
+        }
+
+        return inbox.replies;
+    }
+
+    #[test]
+    fn handle__labelled_roll__replies_publicly_with_the_dice_and_total() {
+        let replies = replies(ScriptedDice::new([4, 6]), &["/roll 2d6+3 # fire damage"]);
+
+        assert_eq!(
+            vec![Reply {
+                sender: "alice".to_string(),
+                visibility: Visibility::Public,
+                text: "alice `2d6+3` (fire damage): [4, **6**] = **13**".to_string(),
+            }],
+            replies
+        );
+    }
+
+    #[test]
+    fn handle__dropped_dice_and_fumbles__are_struck_through_and_emphasised() {
+        let replies = replies(
+            ScriptedDice::new([6, 5, 3, 2, 1]),
+            &["/r 4d6dl1", "/gmroll d20 + 5"],
+        );
+
+        assert_eq!(
+            "alice `4d6dl1`: [**6**, 5, 3, ~~2~~] = **14**",
+            replies[0].text
+        );
+        assert_eq!("alice `d20 + 5`: [*1*] = **6**", replies[1].text);
+        assert_eq!(Visibility::GameMaster, replies[1].visibility);
+    }
+
+    #[test]
+    fn handle__critical_ranges__emphasise_the_dice_in_them() {
+        let replies = replies(
+            ScriptedDice::new([19, 20]),
+            &["/roll d20cs>=19cf<=2", "/roll 1d20cs>=21"],
+        );
+
+        assert_eq!("alice `d20cs>=19cf<=2`: [**19**] = **19**", replies[0].text);
+        assert_eq!("alice `1d20cs>=21`: [20] = **20**", replies[1].text);
+    }
+
+    #[test]
+    fn handle__repeated_roll__lists_the_entries() {
+        let replies = replies(ScriptedDice::new([3, 4, 5]), &["/proll 3x(d8)"]);
+
+        assert_eq!(
+            "alice `3x(d8)`: [3, 4, 5] = **12** (3, 4, 5)",
+            replies[0].text
+        );
+        assert_eq!(Visibility::Private, replies[0].visibility);
+    }
+
+    #[test]
+    fn handle__other_messages__are_not_answered() {
+        let replies = replies(
+            ScriptedDice::new([]),
+            &["hello", "/help", "/rolling 2d6", "2d6"],
+        );
+
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn handle__invalid_expression__replies_with_the_error() {
+        let replies = replies(
+            ScriptedDice::new([]),
+            &["/roll 2d6 +", "/roll # just a label"],
+        );
+
+        assert_eq!("alice `2d6 +` failed: Invalid expression.", replies[0].text);
+        assert_eq!("Usage: /roll <expression> [# label]", replies[1].text);
+        assert_eq!(Visibility::Private, replies[1].visibility);
+    }
+
+    #[test]
+    fn handle__stats_command__summarises_the_distribution() {
+        let rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let replies = replies(rng, &["/stats 1d1 + 3"]);
+
+        assert_eq!(
+            "alice `1d1 + 3`: mean **4.00**, standard deviation 0.00, from 4 to 4 over 10000 rolls",
+            replies[0].text
+        );
+    }
+
+    #[test]
+    fn handle__labelled_subexpressions__shows_the_subtotals() {
+        let replies = replies(ScriptedDice::new([3, 4, 5]), &["/r 2d6[fire] + d8[cold]"]);
+
+        assert_eq!(
+            "alice `2d6[fire] + d8[cold]`: [3, 4, 5] = **12** (fire: 7, cold: 5)",
+            replies[0].text
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use crate::ast::AST;
+use crate::ast::IntegerAST;
+use crate::ast::LongRollAST;
+use crate::ast::MathAST;
+use crate::ast::MathOperation;
+use crate::dice_error::DiceError;
+
+/// How a critical hit increases the damage of an expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CriticalRule {
+    /// Rolls twice as many dice but adds the modifiers once, as in D&D 5e: `2d6 + 3` becomes
+    /// `4d6 + 3`.
+    DoubleDice,
+    /// Adds the most the dice could roll to a normal roll of them: `2d6 + 3` becomes
+    /// `12 + 2d6 + 3`.
+    MaxPlusRoll,
+    /// Multiplies the whole total: `2d6 + 3` becomes `(2d6 + 3) * 2`.
+    Multiply(u64),
+}
+
+impl CriticalRule {
+    /// Reads a rule written as `double`, `max`, or `x` followed by a factor (e.g. `x3`).
+    pub fn parse(text: &str) -> Result<CriticalRule, DiceError> {
+        let rule = text.trim().to_ascii_lowercase();
+
+        if rule == "double" {
+            return Ok(CriticalRule::DoubleDice);
+        }
+        if rule == "max" {
+            return Ok(CriticalRule::MaxPlusRoll);
+        }
+        if let Some(factor) = rule.strip_prefix('x')
+            && let Ok(factor) = factor.parse::<u64>()
+        {
+            return Ok(CriticalRule::Multiply(factor));
+        }
+
+        return Err(DiceError::new(&format!(
+            "Unknown critical rule: {} (expected double, max, or x followed by a factor).",
+            text
+        )));
+    }
+}
+
+/// Rewrites a damage expression into the damage of a critical hit under `rule`.
+///
+/// Only dice which add to the total are changed. The dice of conditions, comparisons, counted
+/// groups, and the dice and face counts of dynamic rolls decide what is rolled rather than how
+/// much damage is dealt, so they are left as they are. So are the dice of a `let` value, which
+/// may be an attack roll as well as damage. A divisor is left alone, as is the second factor of
+/// a product whose first factor has dice, since changing both would multiply the damage again. A
+/// dynamic roll's highest total isn't known until it is rolled, so [`CriticalRule::MaxPlusRoll`]
+/// doubles its dice instead.
+pub fn critical_damage(ast: AST, rule: &CriticalRule) -> AST {
+    return match rule {
+        CriticalRule::Multiply(factor) => math(
+            MathOperation::Multiply,
+            ast,
+            AST::Integer(IntegerAST { integer: *factor }),
+        ),
+        _ => transform_dice(ast, rule),
+    };
+}
+
+fn transform_dice(ast: AST, rule: &CriticalRule) -> AST {
+    return match ast {
+        AST::ShortRoll(roll) => match rule {
+            CriticalRule::MaxPlusRoll => math(
+                MathOperation::Add,
+                AST::Integer(IntegerAST {
+                    integer: roll.faces,
+                }),
+                AST::ShortRoll(roll),
+            ),
+            _ => AST::LongRoll(LongRollAST {
+                die: 2,
+                faces: roll.faces,
+                keep_high: None,
+                keep_low: None,
+                drop_high: None,
+                drop_low: None,
+                critical_success: None,
+                critical_failure: None,
+            }),
+        },
+        AST::LongRoll(roll) => match rule {
+            CriticalRule::MaxPlusRoll => math(
+                MathOperation::Add,
+                AST::Integer(IntegerAST {
+                    integer: highest_total(&roll),
+                }),
+                AST::LongRoll(roll),
+            ),
+            _ => AST::LongRoll(LongRollAST {
+                die: roll.die.saturating_mul(2),
+                keep_high: roll.keep_high.map(double),
+                keep_low: roll.keep_low.map(double),
+                drop_high: roll.drop_high.map(double),
+                drop_low: roll.drop_low.map(double),
+                ..roll
+            }),
+        },
+        AST::DynamicRoll(mut roll) => {
+            roll.die = Box::new(math(
+                MathOperation::Multiply,
+                AST::Integer(IntegerAST { integer: 2 }),
+                *roll.die,
+            ));
+            roll.keep_high = roll.keep_high.map(double);
+            roll.keep_low = roll.keep_low.map(double);
+            roll.drop_high = roll.drop_high.map(double);
+            roll.drop_low = roll.drop_low.map(double);
+            AST::DynamicRoll(roll)
+        }
+        AST::Math(mut math) => match math.operation {
+            MathOperation::Add | MathOperation::Subtract => AST::Math(MathAST {
+                operation: math.operation,
+                left: Box::new(transform_dice(*math.left, rule)),
+                right: Box::new(transform_dice(*math.right, rule)),
+            }),
+            MathOperation::Multiply => {
+                let left = transform_dice((*math.left).clone(), rule);
+                if left == *math.left {
+                    math.right = Box::new(transform_dice(*math.right, rule));
+                } else {
+                    math.left = Box::new(left);
+                }
+                AST::Math(math)
+            }
+            MathOperation::Divide => {
+                math.left = Box::new(transform_dice(*math.left, rule));
+                AST::Math(math)
+            }
+            _ => AST::Math(math),
+        },
+        AST::Let(mut binding) => {
+            binding.body = Box::new(transform_dice(*binding.body, rule));
+            AST::Let(binding)
+        }
+        AST::Conditional(mut conditional) => {
+            conditional.if_true = Box::new(transform_dice(*conditional.if_true, rule));
+            conditional.if_false = Box::new(transform_dice(*conditional.if_false, rule));
+            AST::Conditional(conditional)
+        }
+        AST::Repeat(mut repeat) => {
+            repeat.expression = Box::new(transform_dice(*repeat.expression, rule));
+            AST::Repeat(repeat)
+        }
+        AST::Group(mut group) if group.success.is_none() => {
+            group.children = group
+                .children
+                .into_iter()
+                .map(|child| transform_dice(child, rule))
+                .collect();
+            AST::Group(group)
+        }
+        AST::Label(mut label) => {
+            label.expression = Box::new(transform_dice(*label.expression, rule));
+            AST::Label(label)
+        }
+        ast => ast,
+    };
+}
+
+// The total of a roll whose kept dice all rolled their highest face. The modifiers are checked in
+// the same order as when the roll is executed.
+fn highest_total(roll: &LongRollAST) -> u64 {
+    let kept = if let Some(count) = roll.keep_low.or(roll.keep_high) {
+        count.min(roll.die)
+    } else if let Some(count) = roll.drop_low.or(roll.drop_high) {
+        roll.die - count.min(roll.die)
+    } else {
+        roll.die
+    };
+
+    return kept.saturating_mul(roll.faces);
+}
+
+fn double(count: u64) -> u64 {
+    return count.saturating_mul(2);
+}
+
+fn math(operation: MathOperation, left: AST, right: AST) -> AST {
+    return AST::Math(MathAST {
+        operation: operation,
+        left: Box::new(left),
+        right: Box::new(right),
+    });
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+    use crate::lexer;
+    use crate::parser;
+
+    fn critical(input: &str, rule: CriticalRule) -> String {
+        let ast = parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+
+        return critical_damage(ast, &rule).to_string();
+    }
+
+    #[test]
+    fn critical_damage__double_dice__doubles_the_dice_but_not_the_modifiers() {
+        assert_eq!(
+            "4d6 + 2d8[fire] + 3",
+            critical("2d6 + d8[fire] + 3", CriticalRule::DoubleDice)
+        );
+        assert_eq!(
+            "8d6dl2 + (2 * 1d4)d6",
+            critical("4d6dl1 + (1d4)d6", CriticalRule::DoubleDice)
+        );
+    }
+
+    #[test]
+    fn critical_damage__max_plus_roll__adds_the_highest_total_of_the_kept_dice() {
+        assert_eq!(
+            "12 + 2d6 + 3 + (18 + 4d6dl1)",
+            critical("2d6 + 3 + 4d6dl1", CriticalRule::MaxPlusRoll)
+        );
+    }
+
+    #[test]
+    fn critical_damage__multiply__multiplies_the_whole_total() {
+        assert_eq!(
+            "(2d6 + 3) * 2",
+            critical("2d6 + 3", CriticalRule::Multiply(2))
+        );
+    }
+
+    #[test]
+    fn critical_damage__dice_in_conditions__are_left_alone() {
+        assert_eq!(
+            "1d20 >= 15 ? 4d6 : 0",
+            critical("1d20 >= 15 ? 2d6 : 0", CriticalRule::DoubleDice)
+        );
+    }
+
+    #[test]
+    fn critical_damage__dice_bound_by_let__are_left_alone() {
+        assert_eq!(
+            "let a = 1d20 in a >= 15 ? 4d6 : 0",
+            critical(
+                "let a = 1d20 in a >= 15 ? 2d6 : 0",
+                CriticalRule::DoubleDice
+            )
+        );
+    }
+
+    #[test]
+    fn critical_damage__products_and_quotients__change_only_one_operand() {
+        assert_eq!(
+            "20 / 1d4 + 4d6 / 2",
+            critical("20 / 1d4 + 2d6 / 2", CriticalRule::DoubleDice)
+        );
+        assert_eq!(
+            "2d6 * 1d4 + 3 * 2d8",
+            critical("1d6 * 1d4 + 3 * 1d8", CriticalRule::DoubleDice)
+        );
+    }
+
+    #[test]
+    fn CriticalRule__parse__unknown_rule__returns_DiceError() {
+        assert_eq!(
+            CriticalRule::Multiply(3),
+            CriticalRule::parse("x3").unwrap()
+        );
+
+        match CriticalRule::parse("triple") {
+            Err(err) => assert_eq!(
+                "Unknown critical rule: triple (expected double, max, or x followed by a factor).",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+}
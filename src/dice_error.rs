@@ -1,15 +1,42 @@
 use std::error::Error;
 use std::fmt;
 
+/// A range of character positions in the input expression, `start` inclusive and `end` exclusive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        return Span {
+            start: start,
+            end: end,
+        };
+    }
+}
+
+/// The error returned by every stage of evaluation.
 #[derive(Debug)]
 pub struct DiceError {
     pub message: String,
+    pub span: Option<Span>,
 }
 
 impl DiceError {
     pub fn new(message: &str) -> DiceError {
         DiceError {
             message: message.to_string(),
+            span: None,
+        }
+    }
+
+    pub fn with_span(message: &str, span: Span) -> DiceError {
+        DiceError {
+            message: message.to_string(),
+            span: Some(span),
         }
     }
 }
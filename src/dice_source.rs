@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::io::Write;
+
+use crate::dice_error::DiceError;
+
+/// Where the face values of rolled dice come from.
+///
+/// Every random number generator is a dice source, and [`ScriptedDice`] and [`PromptedDice`]
+/// supply known values instead. Dice with 0 or 1 faces always roll 0 or 1 without asking the
+/// source.
+pub trait DiceSource {
+    /// Rolls one die with `faces` faces, where `faces` is at least 2.
+    fn roll(&mut self, faces: u64) -> Result<u64, DiceError>;
+}
+
+impl<R: rand::Rng> DiceSource for R {
+    fn roll(&mut self, faces: u64) -> Result<u64, DiceError> {
+        return Ok(self.random_range(1..=faces));
+    }
+}
+
+/// Rolls the values it was given, in order, e.g. to replay a disputed roll or to test an
+/// expression against known dice.
+pub struct ScriptedDice {
+    values: VecDeque<u64>,
+}
+
+impl ScriptedDice {
+    pub fn new(values: impl IntoIterator<Item = u64>) -> ScriptedDice {
+        return ScriptedDice {
+            values: values.into_iter().collect(),
+        };
+    }
+
+    /// The number of values which haven't been rolled yet.
+    pub fn remaining(&self) -> usize {
+        return self.values.len();
+    }
+}
+
+impl DiceSource for ScriptedDice {
+    fn roll(&mut self, faces: u64) -> Result<u64, DiceError> {
+        let Some(value) = self.values.pop_front() else {
+            return Err(DiceError::new(&format!(
+                "No scripted value is left to roll a d{}.",
+                faces
+            )));
+        };
+
+        if !(1..=faces).contains(&value) {
+            return Err(DiceError::new(&format!(
+                "The scripted value {} cannot be rolled on a d{}.",
+                value, faces
+            )));
+        }
+
+        return Ok(value);
+    }
+}
+
+/// Asks for the value of every die, so that the calculator can total real dice rolled at the
+/// table. Answers which aren't a face of the die are asked for again.
+pub struct PromptedDice<I: BufRead, O: Write> {
+    input: I,
+    output: O,
+}
+
+impl<I: BufRead, O: Write> PromptedDice<I, O> {
+    pub fn new(input: I, output: O) -> PromptedDice<I, O> {
+        return PromptedDice {
+            input: input,
+            output: output,
+        };
+    }
+}
+
+impl<I: BufRead, O: Write> DiceSource for PromptedDice<I, O> {
+    fn roll(&mut self, faces: u64) -> Result<u64, DiceError> {
+        loop {
+            write!(self.output, "Enter your d{} result: ", faces)
+                .and_then(|_| self.output.flush())
+                .map_err(|err| DiceError::new(&format!("Failed to ask for a roll: {}", err)))?;
+
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) => {
+                    return Err(DiceError::new(&format!(
+                        "No result was entered for a d{}.",
+                        faces
+                    )));
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    return Err(DiceError::new(&format!("Failed to read a roll: {}", err)));
+                }
+            }
+
+            match line.trim().parse::<u64>() {
+                Ok(value) if (1..=faces).contains(&value) => return Ok(value),
+                _ => {
+                    writeln!(self.output, "Please enter a number from 1 to {}.", faces).map_err(
+                        |err| DiceError::new(&format!("Failed to ask for a roll: {}", err)),
+                    )?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+    use crate::ast::ASTExecutable;
+    use crate::lexer;
+    use crate::parser;
+
+    fn execute(input: &str, dice: &mut impl DiceSource) -> Result<i128, DiceError> {
+        let ast = parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+
+        return ast.execute_ast(dice).map(|result| result.result);
+    }
+
+    #[test]
+    fn ScriptedDice__roll__known_values__are_totalled_in_order() {
+        let mut dice = ScriptedDice::new([20, 3, 4]);
+
+        assert_eq!(27, execute("1d20 + 2d4", &mut dice).unwrap());
+        assert_eq!(0, dice.remaining());
+    }
+
+    #[test]
+    fn ScriptedDice__roll__dice_with_one_face__do_not_use_a_value() {
+        let mut dice = ScriptedDice::new([6]);
+
+        assert_eq!(9, execute("3d1 + d6 + d0", &mut dice).unwrap());
+    }
+
+    #[test]
+    fn ScriptedDice__roll__values_run_out__returns_DiceError() {
+        let mut dice = ScriptedDice::new([2]);
+
+        match execute("2d6", &mut dice) {
+            Err(err) => {
+                assert_eq!("No scripted value is left to roll a d6.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn ScriptedDice__roll__value_larger_than_the_die__returns_DiceError() {
+        let mut dice = ScriptedDice::new([7]);
+
+        match execute("d6", &mut dice) {
+            Err(err) => {
+                assert_eq!(
+                    "The scripted value 7 cannot be rolled on a d6.",
+                    err.message
+                )
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn PromptedDice__roll__invalid_answer__asks_again() {
+        let mut output: Vec<u8> = Vec::new();
+        let mut dice = PromptedDice::new("21\nfoo\n20\n".as_bytes(), &mut output);
+
+        assert_eq!(25, execute("d20 + 5", &mut dice).unwrap());
+        assert_eq!(
+            concat!(
+                "Enter your d20 result: Please enter a number from 1 to 20.\n",
+                "Enter your d20 result: Please enter a number from 1 to 20.\n",
+                "Enter your d20 result: "
+            ),
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn PromptedDice__roll__input_ends__returns_DiceError() {
+        let mut dice = PromptedDice::new("".as_bytes(), Vec::new());
+
+        match execute("d8", &mut dice) {
+            Err(err) => assert_eq!("No result was entered for a d8.", err.message),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+}
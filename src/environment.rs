@@ -0,0 +1,121 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use crate::dice_error::DiceError;
+
+/// The most dice an expression may roll plus entries it may repeat or group, counted across all
+/// of its nested rolls, repeats, and groups.
+pub const MAX_EXECUTION_STEPS: u64 = 1_000_000;
+
+/// The `let` bindings and `@attributes` visible while executing an expression, and whether
+/// executing it should describe the rolls.
+pub struct Environment {
+    bindings: Vec<(String, i128)>,
+    attributes: HashMap<String, i128>,
+    records_descriptions: bool,
+    // The dice rolled and entries executed so far by the expression being executed.
+    steps: Cell<u64>,
+    // How many expressions are being executed inside each other, so that the steps are only
+    // counted from zero again when a new expression starts.
+    depth: Cell<usize>,
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        return Environment::new();
+    }
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        return Environment::with_attributes(HashMap::new());
+    }
+
+    pub fn with_attributes(attributes: HashMap<String, i128>) -> Environment {
+        return Environment {
+            bindings: Vec::new(),
+            attributes: attributes,
+            records_descriptions: true,
+            steps: Cell::new(0),
+            depth: Cell::new(0),
+        };
+    }
+
+    /// Executions in the returned environment leave every description empty, which avoids
+    /// formatting (and allocating) a line for each die when only the total is needed.
+    pub fn without_descriptions(self) -> Environment {
+        return Environment {
+            records_descriptions: false,
+            ..self
+        };
+    }
+
+    pub fn records_descriptions(&self) -> bool {
+        return self.records_descriptions;
+    }
+
+    /// Builds a description with `describe`, or returns an empty one without calling it when
+    /// descriptions aren't being recorded.
+    pub fn describe(&self, describe: impl FnOnce() -> String) -> String {
+        if !self.records_descriptions {
+            return String::new();
+        }
+
+        return describe();
+    }
+
+    pub fn bind(&mut self, name: &str, value: i128) {
+        self.bindings.push((name.to_string(), value));
+    }
+
+    pub fn unbind(&mut self) {
+        self.bindings.pop();
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<i128> {
+        return self
+            .bindings
+            .iter()
+            .rev()
+            .find(|(bound_name, _)| bound_name == name)
+            .map(|(_, value)| *value);
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<i128> {
+        return self.attributes.get(name).copied();
+    }
+
+    // Starts executing an expression, which is part of the current expression if there is one,
+    // or a new one with a fresh count of steps if not. Each call is paired with `end_execution`.
+    pub(crate) fn begin_execution(&self) {
+        if self.depth.get() == 0 {
+            self.steps.set(0);
+        }
+
+        self.depth.set(self.depth.get() + 1);
+    }
+
+    pub(crate) fn end_execution(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    // The steps taken by the expression being executed, or by the last one.
+    pub(crate) fn steps(&self) -> u64 {
+        return self.steps.get();
+    }
+
+    // Counts one more die rolled or entry executed, failing once the expression has taken more
+    // than `MAX_EXECUTION_STEPS`.
+    pub(crate) fn step(&self) -> Result<(), DiceError> {
+        let steps = self.steps.get() + 1;
+        if steps > MAX_EXECUTION_STEPS {
+            return Err(DiceError::new(&format!(
+                "Cannot roll or repeat more than {} times in one expression.",
+                MAX_EXECUTION_STEPS
+            )));
+        }
+
+        self.steps.set(steps);
+        return Ok(());
+    }
+}
@@ -0,0 +1,516 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::ast::AST;
+use crate::ast::Comparison;
+use crate::ast::MathOperation;
+use crate::ast::SuccessCondition;
+use crate::ast::check_dice_count;
+use crate::ast::check_repeat_count;
+use crate::ast::dynamic_roll_size;
+use crate::ast::kept_mask;
+use crate::dice_error::DiceError;
+use crate::environment::Environment;
+
+/// The value which [`estimate`] substitutes for each die instead of rolling it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Estimate {
+    /// Each die counts as its average face, e.g. 4.5 for a d8.
+    Average,
+    /// Each die counts as 1.
+    Minimum,
+    /// Each die counts as its highest face.
+    Maximum,
+}
+
+/// How an estimate which isn't a whole number is turned into one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rounding {
+    Down,
+    Up,
+    Nearest,
+}
+
+impl Rounding {
+    /// Reads a policy written as `down`, `up`, or `nearest`.
+    pub fn parse(text: &str) -> Result<Rounding, DiceError> {
+        return match text.trim().to_ascii_lowercase().as_str() {
+            "down" => Ok(Rounding::Down),
+            "up" => Ok(Rounding::Up),
+            "nearest" => Ok(Rounding::Nearest),
+            _ => Err(DiceError::new(&format!(
+                "Unknown rounding policy: {} (expected down, up, or nearest).",
+                text
+            ))),
+        };
+    }
+
+    fn apply(&self, value: Fraction) -> i128 {
+        let whole = value.numerator.div_euclid(value.denominator);
+        let remainder = value.numerator.rem_euclid(value.denominator);
+        if remainder == 0 {
+            return whole;
+        }
+
+        return match self {
+            Rounding::Down => whole,
+            Rounding::Up => whole + 1,
+            // Halves round away from zero.
+            Rounding::Nearest => match remainder.cmp(&(value.denominator - remainder)) {
+                Ordering::Greater => whole + 1,
+                Ordering::Equal if value.numerator > 0 => whole + 1,
+                _ => whole,
+            },
+        };
+    }
+}
+
+/// The average, minimum, and maximum of an expression, as printed by the CLI's `estimate`
+/// subcommand: `13 avg / 6 min / 20 max`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimates {
+    pub average: i128,
+    pub minimum: i128,
+    pub maximum: i128,
+}
+
+/// Works out the total of `ast` with every die replaced by the value `mode` chooses, without
+/// rolling anything.
+///
+/// The total is only rounded with `rounding` at the end, so `2d8 + 4` averages to 13 rather than
+/// to the 12 of rounding each d8 down to 4. Dice counts and face counts of dynamic rolls are
+/// rounded as soon as they are worked out, since a roll needs whole dice, and division truncates
+/// toward zero as it does when rolling, so `7 / 2` is 3 whatever the rounding.
+///
+/// Each die is replaced on its own, so the minimum of an expression which subtracts or divides by
+/// dice is not the lowest total it could roll.
+pub fn estimate(
+    ast: &AST,
+    mode: Estimate,
+    rounding: Rounding,
+    env: &Environment,
+) -> Result<i128, DiceError> {
+    let mut estimator = Estimator {
+        mode: mode,
+        rounding: rounding,
+        env: env,
+        bindings: Vec::new(),
+    };
+
+    let total = estimator.value(ast)?;
+
+    return Ok(rounding.apply(total));
+}
+
+/// Works out the average, minimum, and maximum of `ast` with [`estimate`].
+pub fn estimates(ast: &AST, rounding: Rounding, env: &Environment) -> Result<Estimates, DiceError> {
+    return Ok(Estimates {
+        average: estimate(ast, Estimate::Average, rounding, env)?,
+        minimum: estimate(ast, Estimate::Minimum, rounding, env)?,
+        maximum: estimate(ast, Estimate::Maximum, rounding, env)?,
+    });
+}
+
+impl fmt::Display for Estimates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} avg / {} min / {} max",
+            self.average, self.minimum, self.maximum
+        )
+    }
+}
+
+struct Estimator<'a> {
+    mode: Estimate,
+    rounding: Rounding,
+    env: &'a Environment,
+    // `let` bindings keep their unrounded estimates, so they can't be bound in the environment.
+    bindings: Vec<(String, Fraction)>,
+}
+
+impl Estimator<'_> {
+    fn value(&mut self, ast: &AST) -> Result<Fraction, DiceError> {
+        return match ast {
+            AST::Integer(integer) => Ok(Fraction::whole(integer.integer as i128)),
+            AST::ShortRoll(roll) => self.die(roll.faces),
+            AST::LongRoll(roll) => self.roll(
+                roll.die,
+                roll.faces,
+                [roll.keep_high, roll.keep_low, roll.drop_high, roll.drop_low],
+            ),
+            AST::DynamicRoll(roll) => {
+                let die = self.whole(&roll.die)?;
+                let faces = self.whole(&roll.faces)?;
+                let (die, faces) = dynamic_roll_size(die, faces)?;
+
+                self.roll(
+                    die,
+                    faces,
+                    [roll.keep_high, roll.keep_low, roll.drop_high, roll.drop_low],
+                )
+            }
+            AST::Math(math) => {
+                let left = self.value(&math.left)?;
+                let right = self.value(&math.right)?;
+
+                apply(&math.operation, left, right)
+            }
+            AST::Variable(variable) => {
+                let bound = self
+                    .bindings
+                    .iter()
+                    .rev()
+                    .find(|(name, _)| *name == variable.name)
+                    .map(|(_, value)| *value);
+
+                match bound.or_else(|| self.env.lookup(&variable.name).map(Fraction::whole)) {
+                    Some(value) => Ok(value),
+                    None => Err(DiceError::with_span(
+                        &format!("Undefined variable: {}", variable.name),
+                        variable.span,
+                    )),
+                }
+            }
+            AST::Attribute(attribute) => match self.env.attribute(&attribute.name) {
+                Some(value) => Ok(Fraction::whole(value)),
+                None => Err(DiceError::with_span(
+                    &format!("Undefined attribute: @{}", attribute.name),
+                    attribute.span,
+                )),
+            },
+            AST::Let(binding) => {
+                let value = self.value(&binding.value)?;
+
+                self.bindings.push((binding.name.clone(), value));
+                let body = self.value(&binding.body);
+                self.bindings.pop();
+
+                body
+            }
+            AST::Conditional(conditional) => {
+                if self.value(&conditional.condition)?.numerator == 0 {
+                    self.value(&conditional.if_false)
+                } else {
+                    self.value(&conditional.if_true)
+                }
+            }
+            AST::Repeat(repeat) => {
+                check_repeat_count(repeat.count)?;
+                let entry = self.value(&repeat.expression)?;
+                let kept = kept_count(
+                    repeat.count,
+                    [
+                        repeat.keep_high,
+                        repeat.keep_low,
+                        repeat.drop_high,
+                        repeat.drop_low,
+                    ],
+                );
+
+                Fraction::whole(kept as i128).multiply(entry)
+            }
+            AST::Group(group) => {
+                let mut totals: Vec<Fraction> = Vec::new();
+                for child in &group.children {
+                    totals.push(self.value(child)?);
+                }
+
+                let kept = kept_mask(
+                    &totals,
+                    group.keep_high,
+                    group.keep_low,
+                    group.drop_high,
+                    group.drop_low,
+                );
+                let mut kept_totals = totals
+                    .iter()
+                    .zip(kept)
+                    .filter(|(_, is_kept)| *is_kept)
+                    .map(|(total, _)| *total);
+
+                match &group.success {
+                    Some(success) => Ok(Fraction::whole(
+                        kept_totals.filter(|total| meets(success, *total)).count() as i128,
+                    )),
+                    None => kept_totals.try_fold(Fraction::whole(0), |sum, total| sum.add(total)),
+                }
+            }
+            AST::Label(label) => self.value(&label.expression),
+        };
+    }
+
+    fn die(&self, faces: u64) -> Result<Fraction, DiceError> {
+        if faces == 0 {
+            return Ok(Fraction::whole(0));
+        }
+
+        return match self.mode {
+            Estimate::Average => Fraction::new(Some(faces as i128 + 1), Some(2)),
+            Estimate::Minimum => Ok(Fraction::whole(1)),
+            Estimate::Maximum => Ok(Fraction::whole(faces as i128)),
+        };
+    }
+
+    fn roll(
+        &self,
+        die: u64,
+        faces: u64,
+        modifiers: [Option<u64>; 4],
+    ) -> Result<Fraction, DiceError> {
+        check_dice_count(die)?;
+
+        return Fraction::whole(kept_count(die, modifiers) as i128).multiply(self.die(faces)?);
+    }
+
+    fn whole(&mut self, ast: &AST) -> Result<i128, DiceError> {
+        let value = self.value(ast)?;
+
+        return Ok(self.rounding.apply(value));
+    }
+}
+
+// An estimate worked out exactly. Averages of dice are halves, and products of them quarters and
+// so on, which floating point stops holding exactly once totals are large.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Fraction {
+    numerator: i128,
+    // Always positive, and shares no factor with the numerator.
+    denominator: i128,
+}
+
+impl Fraction {
+    fn whole(value: i128) -> Fraction {
+        return Fraction {
+            numerator: value,
+            denominator: 1,
+        };
+    }
+
+    // Reduces `numerator / denominator`, where `None` is a product which overflowed.
+    fn new(numerator: Option<i128>, denominator: Option<i128>) -> Result<Fraction, DiceError> {
+        let (Some(numerator), Some(denominator)) = (numerator, denominator) else {
+            return Err(DiceError::new("The result is too large to calculate."));
+        };
+
+        let (mut a, mut b) = (numerator.unsigned_abs(), denominator.unsigned_abs());
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        let divisor = a.max(1) as i128;
+
+        return Ok(Fraction {
+            numerator: numerator / divisor,
+            denominator: denominator / divisor,
+        });
+    }
+
+    fn add(self, other: Fraction) -> Result<Fraction, DiceError> {
+        let left = self.numerator.checked_mul(other.denominator);
+        let right = other.numerator.checked_mul(self.denominator);
+
+        return Fraction::new(
+            left.zip(right)
+                .and_then(|(left, right)| left.checked_add(right)),
+            self.denominator.checked_mul(other.denominator),
+        );
+    }
+
+    fn multiply(self, other: Fraction) -> Result<Fraction, DiceError> {
+        return Fraction::new(
+            self.numerator.checked_mul(other.numerator),
+            self.denominator.checked_mul(other.denominator),
+        );
+    }
+
+    // Divides and truncates toward zero, as rolling does.
+    fn divide(self, other: Fraction) -> Result<Fraction, DiceError> {
+        let left = self.numerator.checked_mul(other.denominator);
+        let right = other.numerator.checked_mul(self.denominator);
+
+        return Fraction::new(
+            left.zip(right)
+                .and_then(|(left, right)| left.checked_div(right)),
+            Some(1),
+        );
+    }
+}
+
+impl PartialOrd for Fraction {
+    fn partial_cmp(&self, other: &Fraction) -> Option<Ordering> {
+        let left = self.numerator.checked_mul(other.denominator);
+        let right = other.numerator.checked_mul(self.denominator);
+
+        return match left.zip(right) {
+            Some((left, right)) => left.partial_cmp(&right),
+            // Only fractions with enormous denominators get here, and those are close enough.
+            None => (self.numerator as f64 / self.denominator as f64)
+                .partial_cmp(&(other.numerator as f64 / other.denominator as f64)),
+        };
+    }
+}
+
+// Every die or entry has the same estimate, so only the number which are kept matters.
+fn kept_count(count: u64, [keep_high, keep_low, drop_high, drop_low]: [Option<u64>; 4]) -> usize {
+    let values = vec![0; count as usize];
+
+    return kept_mask(&values, keep_high, keep_low, drop_high, drop_low)
+        .iter()
+        .filter(|is_kept| **is_kept)
+        .count();
+}
+
+fn apply(
+    operation: &MathOperation,
+    left: Fraction,
+    right: Fraction,
+) -> Result<Fraction, DiceError> {
+    let truth = |condition: bool| -> Result<Fraction, DiceError> {
+        return Ok(Fraction::whole(condition as i128));
+    };
+
+    return match operation {
+        MathOperation::Add => left.add(right),
+        MathOperation::Subtract => left.add(right.multiply(Fraction::whole(-1))?),
+        MathOperation::Multiply => left.multiply(right),
+        MathOperation::Divide if right.numerator == 0 => {
+            Err(DiceError::new("Division by zero is not allowed."))
+        }
+        MathOperation::Divide => left.divide(right),
+        MathOperation::Equal => truth(left == right),
+        MathOperation::NotEqual => truth(left != right),
+        MathOperation::Greater => truth(left > right),
+        MathOperation::GreaterOrEqual => truth(left >= right),
+        MathOperation::Less => truth(left < right),
+        MathOperation::LessOrEqual => truth(left <= right),
+    };
+}
+
+fn meets(success: &SuccessCondition, value: Fraction) -> bool {
+    let target = Fraction::whole(success.target as i128);
+
+    return match success.comparison {
+        Comparison::Equal => value == target,
+        Comparison::NotEqual => value != target,
+        Comparison::Greater => value > target,
+        Comparison::GreaterOrEqual => value >= target,
+        Comparison::Less => value < target,
+        Comparison::LessOrEqual => value <= target,
+    };
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::lexer;
+    use crate::parser;
+
+    fn estimate_input(input: &str, mode: Estimate, rounding: Rounding) -> Result<i128, DiceError> {
+        let ast = parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+        let env = Environment::with_attributes(HashMap::from([("str".to_string(), 3)]));
+
+        return estimate(&ast, mode, rounding, &env);
+    }
+
+    #[test]
+    fn estimates__damage_roll__prints_the_average_minimum_and_maximum() {
+        let ast = parser::parse(&lexer::tokenize("2d8+4").unwrap()).unwrap();
+
+        let estimates = estimates(&ast, Rounding::Down, &Environment::new()).unwrap();
+
+        assert_eq!("13 avg / 6 min / 20 max", estimates.to_string());
+    }
+
+    #[test]
+    fn estimate__rounding_policies__round_only_the_total() {
+        assert_eq!(
+            10,
+            estimate_input("3d6", Estimate::Average, Rounding::Down).unwrap()
+        );
+        assert_eq!(
+            11,
+            estimate_input("3d6", Estimate::Average, Rounding::Up).unwrap()
+        );
+        assert_eq!(
+            11,
+            estimate_input("3d6", Estimate::Average, Rounding::Nearest).unwrap()
+        );
+    }
+
+    #[test]
+    fn estimate__kept_dice_and_bindings__counts_only_the_kept_dice() {
+        assert_eq!(
+            18,
+            estimate_input("4d6dl1", Estimate::Maximum, Rounding::Down).unwrap()
+        );
+        assert_eq!(
+            10,
+            estimate_input(
+                "let a = d6 in a * 2 + @str",
+                Estimate::Average,
+                Rounding::Down
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            1,
+            estimate_input("{d20, 10}h1 >= 15", Estimate::Maximum, Rounding::Down).unwrap()
+        );
+    }
+
+    #[test]
+    fn estimate__division__truncates_like_rolling() {
+        assert_eq!(
+            3,
+            estimate_input("7 / 2", Estimate::Average, Rounding::Up).unwrap()
+        );
+        assert_eq!(
+            6,
+            estimate_input("(2d6 + 1) / 2", Estimate::Maximum, Rounding::Up).unwrap()
+        );
+        assert_eq!(
+            -3,
+            estimate_input("(0 - 7) / 2", Estimate::Average, Rounding::Down).unwrap()
+        );
+    }
+
+    #[test]
+    fn estimate__large_totals_and_products_of_averages__stay_exact() {
+        assert_eq!(
+            9007199254740993,
+            estimate_input("9007199254740993 + 0", Estimate::Average, Rounding::Down).unwrap()
+        );
+        assert_eq!(
+            13,
+            estimate_input("d6 * d6", Estimate::Average, Rounding::Up).unwrap()
+        );
+        assert_eq!(
+            -4,
+            estimate_input("0 - d6", Estimate::Average, Rounding::Nearest).unwrap()
+        );
+    }
+
+    #[test]
+    fn estimate__division_by_zero__returns_DiceError() {
+        match estimate_input("d6 / (d4 - 1)", Estimate::Minimum, Rounding::Down) {
+            Err(err) => assert_eq!("Division by zero is not allowed.", err.message),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn Rounding__parse__unknown_policy__returns_DiceError() {
+        assert_eq!(Rounding::Up, Rounding::parse("UP").unwrap());
+
+        match Rounding::parse("sideways") {
+            Err(err) => assert_eq!(
+                "Unknown rounding policy: sideways (expected down, up, or nearest).",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+}
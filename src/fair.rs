@@ -0,0 +1,165 @@
+use rand::RngCore;
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::dice_error::DiceError;
+
+/// The material which determines every die of one provably fair roll.
+///
+/// The dice are rolled by a [`ChaCha12Rng`] seeded with the SHA-256 hash of
+/// `server_seed:client_seed:nonce`, so the roller cannot pick dice they like without changing
+/// the server seed, which was committed to (see [`commitment`]) before the client seed was known.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FairRoll {
+    pub server_seed: String,
+    pub client_seed: String,
+    pub nonce: u64,
+}
+
+impl FairRoll {
+    pub fn dice(&self) -> ChaCha12Rng {
+        let seed = Sha256::new()
+            .chain_update(self.server_seed.as_bytes())
+            .chain_update(b":")
+            .chain_update(self.client_seed.as_bytes())
+            .chain_update(b":")
+            .chain_update(self.nonce.to_string().as_bytes())
+            .finalize();
+
+        return ChaCha12Rng::from_seed(seed.into());
+    }
+
+    /// The dice of this roll, if its server seed is the one that `commitment` was published for.
+    pub fn verify(&self, commitment: &str) -> Result<ChaCha12Rng, DiceError> {
+        if !self::commitment(&self.server_seed).eq_ignore_ascii_case(commitment.trim()) {
+            return Err(DiceError::new(
+                "The server seed does not match the published commitment.",
+            ));
+        }
+
+        return Ok(self.dice());
+    }
+}
+
+/// The hash of a server seed which is published before rolling, as a lowercase hex string.
+pub fn commitment(server_seed: &str) -> String {
+    return to_hex(&Sha256::digest(server_seed.as_bytes()));
+}
+
+/// A new random server seed, as a hex string of 32 random bytes.
+pub fn generate_server_seed() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+
+    return to_hex(&bytes);
+}
+
+/// Hands out a [`FairRoll`] for every roll of a session, with nonces counting up from 0.
+pub struct FairSession {
+    server_seed: String,
+    client_seed: String,
+    nonce: u64,
+}
+
+impl FairSession {
+    pub fn new(server_seed: String, client_seed: String) -> FairSession {
+        return FairSession {
+            server_seed: server_seed,
+            client_seed: client_seed,
+            nonce: 0,
+        };
+    }
+
+    pub fn next_roll(&mut self) -> FairRoll {
+        let roll = FairRoll {
+            server_seed: self.server_seed.clone(),
+            client_seed: self.client_seed.clone(),
+            nonce: self.nonce,
+        };
+        self.nonce += 1;
+
+        return roll;
+    }
+
+    /// The server seed, which may only be revealed once the session's rolls are done.
+    pub fn server_seed(&self) -> &str {
+        return &self.server_seed;
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+    use crate::ast::ASTExecutable;
+    use crate::lexer;
+    use crate::parser;
+
+    fn roll(input: &str, dice: &mut ChaCha12Rng) -> i128 {
+        let ast = parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+
+        return ast.execute_ast(dice).unwrap().result;
+    }
+
+    #[test]
+    fn commitment__known_seed__is_its_sha256_hash() {
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            commitment("abc")
+        );
+    }
+
+    #[test]
+    fn verify__revealed_seed__rolls_the_same_dice_as_the_session() {
+        let server_seed = generate_server_seed();
+        let published = commitment(&server_seed);
+        let mut session = FairSession::new(server_seed, "lucky".to_string());
+        session.next_roll();
+        let second = session.next_roll();
+
+        let revealed = FairRoll {
+            server_seed: session.server_seed().to_string(),
+            client_seed: "lucky".to_string(),
+            nonce: 1,
+        };
+
+        assert_eq!(second, revealed);
+        assert_eq!(
+            roll("10d20", &mut second.dice()),
+            roll("10d20", &mut revealed.verify(&published).unwrap())
+        );
+    }
+
+    #[test]
+    fn dice__different_nonces__roll_different_dice() {
+        let mut session = FairSession::new("server".to_string(), "client".to_string());
+
+        let first = roll("20d100", &mut session.next_roll().dice());
+        let second = roll("20d100", &mut session.next_roll().dice());
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn verify__different_server_seed__returns_DiceError() {
+        let roll = FairRoll {
+            server_seed: "fudged".to_string(),
+            client_seed: "client".to_string(),
+            nonce: 0,
+        };
+
+        match roll.verify(&commitment("honest")) {
+            Err(err) => assert_eq!(
+                "The server seed does not match the published commitment.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+}
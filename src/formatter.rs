@@ -0,0 +1,326 @@
+use std::fmt;
+
+use crate::ast::AST;
+use crate::ast::Comparison;
+use crate::ast::GroupAST;
+use crate::ast::MathOperation;
+use crate::ast::SortOrder;
+use crate::ast::SuccessCondition;
+
+// How tightly an expression binds, from the loosest (`let` and `?:`, which extend as far right as
+// they can) to the tightest (rolls, names, groups, and anything else the parser reads as an atom).
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    Expression,
+    Comparison,
+    Add,
+    Multiply,
+    Atom,
+}
+
+/// Writes an [`AST`] in canonical dice notation: lowercase, single spaces around operators, and
+/// only the parentheses which the precedence of the expression requires. Parsing the output gives
+/// back an equal [`AST`].
+impl fmt::Display for AST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write_ast(f, self, Precedence::Expression);
+    }
+}
+
+fn precedence(ast: &AST) -> Precedence {
+    return match ast {
+        AST::Let(_) | AST::Conditional(_) => Precedence::Expression,
+        AST::Math(math) => match math.operation {
+            MathOperation::Add | MathOperation::Subtract => Precedence::Add,
+            MathOperation::Multiply | MathOperation::Divide => Precedence::Multiply,
+            _ => Precedence::Comparison,
+        },
+        _ => Precedence::Atom,
+    };
+}
+
+fn write_ast(f: &mut fmt::Formatter, ast: &AST, minimum: Precedence) -> fmt::Result {
+    if precedence(ast) < minimum {
+        return write_parenthesized(f, ast);
+    }
+
+    match ast {
+        AST::Integer(integer) => write!(f, "{}", integer.integer),
+        AST::ShortRoll(roll) => write!(f, "d{}", roll.faces),
+        AST::LongRoll(roll) => {
+            write!(f, "{}d{}", roll.die, roll.faces)?;
+            write_selection(
+                f,
+                roll.keep_high,
+                roll.keep_low,
+                roll.drop_high,
+                roll.drop_low,
+            )?;
+            write_critical_ranges(f, &roll.critical_success, &roll.critical_failure)
+        }
+        AST::DynamicRoll(roll) => {
+            // `3d6` would parse back as a plain roll, so a literal dice count is only left bare
+            // when the face count is parenthesized.
+            let faces_is_integer = matches!(*roll.faces, AST::Integer(_));
+            match &*roll.die {
+                AST::Integer(die) if !faces_is_integer => write!(f, "{}", die.integer)?,
+                die => write_parenthesized(f, die)?,
+            }
+            write!(f, "d")?;
+            match &*roll.faces {
+                AST::Integer(faces) => write!(f, "{}", faces.integer)?,
+                faces => write_parenthesized(f, faces)?,
+            }
+            write_selection(
+                f,
+                roll.keep_high,
+                roll.keep_low,
+                roll.drop_high,
+                roll.drop_low,
+            )?;
+            write_critical_ranges(f, &roll.critical_success, &roll.critical_failure)
+        }
+        AST::Math(math) => {
+            let (symbol, left, right) = match math.operation {
+                MathOperation::Add => ("+", Precedence::Add, Precedence::Multiply),
+                MathOperation::Subtract => ("-", Precedence::Add, Precedence::Multiply),
+                MathOperation::Multiply => ("*", Precedence::Multiply, Precedence::Atom),
+                MathOperation::Divide => ("/", Precedence::Multiply, Precedence::Atom),
+                MathOperation::Equal => ("==", Precedence::Add, Precedence::Add),
+                MathOperation::NotEqual => ("!=", Precedence::Add, Precedence::Add),
+                MathOperation::Greater => (">", Precedence::Add, Precedence::Add),
+                MathOperation::GreaterOrEqual => (">=", Precedence::Add, Precedence::Add),
+                MathOperation::Less => ("<", Precedence::Add, Precedence::Add),
+                MathOperation::LessOrEqual => ("<=", Precedence::Add, Precedence::Add),
+            };
+
+            write_ast(f, &math.left, left)?;
+            write!(f, " {} ", symbol)?;
+            write_ast(f, &math.right, right)
+        }
+        AST::Variable(variable) => write!(f, "{}", variable.name),
+        AST::Attribute(attribute) => write!(f, "@{}", attribute.name),
+        AST::Let(binding) => {
+            write!(f, "let {} = ", binding.name)?;
+            write_ast(f, &binding.value, Precedence::Expression)?;
+            write!(f, " in ")?;
+            write_ast(f, &binding.body, Precedence::Expression)
+        }
+        AST::Conditional(conditional) => {
+            write_ast(f, &conditional.condition, Precedence::Comparison)?;
+            write!(f, " ? ")?;
+            write_ast(f, &conditional.if_true, Precedence::Expression)?;
+            write!(f, " : ")?;
+            write_ast(f, &conditional.if_false, Precedence::Expression)
+        }
+        AST::Repeat(repeat) => {
+            // The repeated expression is always parenthesized so that its own modifiers can't be
+            // confused with the modifiers of the list.
+            write!(f, "{}x", repeat.count)?;
+            write_parenthesized(f, &repeat.expression)?;
+            write_selection(
+                f,
+                repeat.keep_high,
+                repeat.keep_low,
+                repeat.drop_high,
+                repeat.drop_low,
+            )?;
+            match repeat.sort {
+                Some(SortOrder::Ascending) => write!(f, "s"),
+                Some(SortOrder::Descending) => write!(f, "sd"),
+                None => Ok(()),
+            }
+        }
+        AST::Group(group) => write_group(f, group),
+        AST::Label(label) => {
+            // A label straight after a repeat's expression would label the expression instead.
+            match &*label.expression {
+                AST::Repeat(_) => write_parenthesized(f, &label.expression)?,
+                expression => write_ast(f, expression, Precedence::Atom)?,
+            }
+            write!(f, "[{}]", label.label)
+        }
+    }
+}
+
+fn write_parenthesized(f: &mut fmt::Formatter, ast: &AST) -> fmt::Result {
+    write!(f, "(")?;
+    write_ast(f, ast, Precedence::Expression)?;
+    return write!(f, ")");
+}
+
+fn write_group(f: &mut fmt::Formatter, group: &GroupAST) -> fmt::Result {
+    write!(f, "{{")?;
+    for (idx, child) in group.children.iter().enumerate() {
+        if idx > 0 {
+            write!(f, ", ")?;
+        }
+        write_ast(f, child, Precedence::Expression)?;
+    }
+    write!(f, "}}")?;
+
+    write_selection(
+        f,
+        group.keep_high,
+        group.keep_low,
+        group.drop_high,
+        group.drop_low,
+    )?;
+
+    let Some(success) = &group.success else {
+        return Ok(());
+    };
+
+    return write!(
+        f,
+        "{}{}",
+        comparison_symbol(&success.comparison),
+        success.target
+    );
+}
+
+fn write_critical_ranges(
+    f: &mut fmt::Formatter,
+    critical_success: &Option<SuccessCondition>,
+    critical_failure: &Option<SuccessCondition>,
+) -> fmt::Result {
+    for (prefix, range) in [("cs", critical_success), ("cf", critical_failure)] {
+        let Some(range) = range else {
+            continue;
+        };
+
+        // A range of one face is written as just its target, e.g. `cf1`.
+        match range.comparison {
+            Comparison::Equal => write!(f, "{}{}", prefix, range.target)?,
+            _ => write!(
+                f,
+                "{}{}{}",
+                prefix,
+                comparison_symbol(&range.comparison),
+                range.target
+            )?,
+        }
+    }
+
+    return Ok(());
+}
+
+fn comparison_symbol(comparison: &Comparison) -> &'static str {
+    return match comparison {
+        Comparison::Equal => "==",
+        Comparison::NotEqual => "!=",
+        Comparison::Greater => ">",
+        Comparison::GreaterOrEqual => ">=",
+        Comparison::Less => "<",
+        Comparison::LessOrEqual => "<=",
+    };
+}
+
+fn write_selection(
+    f: &mut fmt::Formatter,
+    keep_high: Option<u64>,
+    keep_low: Option<u64>,
+    drop_high: Option<u64>,
+    drop_low: Option<u64>,
+) -> fmt::Result {
+    if let Some(count) = keep_high {
+        write!(f, "h{}", count)?;
+    }
+    if let Some(count) = keep_low {
+        write!(f, "l{}", count)?;
+    }
+    if let Some(count) = drop_high {
+        write!(f, "dh{}", count)?;
+    }
+    if let Some(count) = drop_low {
+        write!(f, "dl{}", count)?;
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+    use crate::builder;
+    use crate::builder::Dice;
+    use crate::lexer;
+    use crate::parser;
+
+    fn parse(input: &str) -> AST {
+        return parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+    }
+
+    fn assert_round_trips(input: &str) {
+        let ast = parse(input);
+
+        assert_eq!(ast, parse(&ast.to_string()), "formatted as {}", ast);
+    }
+
+    #[test]
+    fn to_string__irregular_spacing_and_case__writes_canonical_notation() {
+        assert_eq!("2d6 + 5", parse("2D6+ 5").to_string());
+        assert_eq!("4d6dl1 * 2d8h1", parse(" 4d6DL1*2D8H1").to_string());
+    }
+
+    #[test]
+    fn to_string__redundant_parentheses__are_removed() {
+        assert_eq!("(1 + 2) * 3", parse("((1 + 2)) * 3").to_string());
+        assert_eq!("1 + 2 * 3", parse("1 + (2 * 3)").to_string());
+        assert_eq!("1 - 2 + 3", parse("(1 - 2) + 3").to_string());
+    }
+
+    #[test]
+    fn to_string__parentheses_required_by_precedence__are_kept() {
+        assert_eq!("1 - (2 - 3)", parse("1 - (2 - 3)").to_string());
+        assert_eq!("8 / (4 / 2)", parse("8 / (4 / 2)").to_string());
+        assert_eq!("(1 > 0) == 1", parse("(1 > 0) == 1").to_string());
+        assert_eq!("2 * (1 ? 3 : 4)", parse("2 * (1 ? 3 : 4)").to_string());
+    }
+
+    #[test]
+    fn to_string__dynamic_roll_with_literal_counts__keeps_it_dynamic() {
+        assert_eq!("(3)d6", parse("3d(6)").to_string());
+        assert_eq!("1d(1d8 + 2)", parse("d(1d8 + 2)").to_string());
+    }
+
+    #[test]
+    fn to_string__group_compared_without_success_condition__keeps_the_comparison_apart() {
+        assert_eq!("{1d20} >= 10", parse("({1d20}) >= 10").to_string());
+        assert_eq!("2 * {1d20}h1 > 3", parse("(2 * {1d20}h1) > 3").to_string());
+        assert_eq!("{1d20}>=10 > 3", parse("({1d20}>=10) > 3").to_string());
+    }
+
+    #[test]
+    fn to_string__built_expression__parenthesizes_by_precedence() {
+        let ast = (builder::let_in("a", 1, Dice::d(4)) + 2) * Dice::new(3, 6).keep_high(2);
+
+        assert_eq!("((let a = 1 in d4) + 2) * 3d6h2", ast.to_string());
+        assert_eq!(ast, parse(&ast.to_string()));
+    }
+
+    #[test]
+    fn to_string__every_kind_of_expression__parses_back_to_the_same_ast() {
+        let inputs = [
+            "d5 + 2d6h1 - 3d100l2 + (10 - 2 * 2) / 2",
+            "let a = 1d20 in a + (a >= 20 ? 10 : 0)",
+            "let a = let b = 2 in b * b in (a ? a : 1) - 1",
+            "1d20 + @str_mod + @prof",
+            "1 ? 2 ? 3 : 4 : 5 ? 6 : 7",
+            "6x(4d6dl1)h3sd",
+            "6x(1d20)s + 3x(d4)",
+            "{1d20 + 5, 1d20 + 3}kh1 + {3d6, 2d8, 1d20}>=10",
+            "({1d20}h1) < 5 ? {2d6}=7 : 0",
+            "(1d4)d6dh1 * 2d(1d8 + 2)l1",
+            "((1d4)d(2)) != (d4)d(d6)",
+            "2d6[fire] + (1d8 + 3)[slashing] * 2",
+            "(6x(d6)h3)[fire] + 6x(d6[cold]) + (d4)d6[acid][magic]",
+            "1d20cs>=19cf1 + 2d20h1cf<=2cs20 + (1d4)d20cs!=3",
+        ];
+
+        for input in inputs {
+            assert_round_trips(input);
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt;
+
+use crate::dice_error::DiceError;
+
+/// The deepest nesting of arrays and objects which [`parse_json`] reads, so that a document of
+/// brackets can't overflow the stack.
+pub const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Integer(i128),
+    Float(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    /// The value of the member named `key`, if this is an object which has one.
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        let JsonValue::Object(members) = self else {
+            return None;
+        };
+
+        return members
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value);
+    }
+}
+
+struct CharItr {
+    chars: Vec<char>,
+    cur_char: usize,
+    depth: usize,
+}
+
+impl CharItr {
+    fn next(&mut self) -> Option<char> {
+        let result = self.peek();
+
+        if result.is_some() {
+            self.cur_char += 1;
+        }
+
+        return result;
+    }
+
+    fn peek(&self) -> Option<char> {
+        return self.chars.get(self.cur_char).copied();
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\n' | '\t' | '\r') = self.peek() {
+            self.cur_char += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), DiceError> {
+        self.skip_whitespace();
+
+        match self.next() {
+            Some(char) if char == expected => return Ok(()),
+            _ => return Err(invalid_json(&format!("expected '{}'", expected))),
+        }
+    }
+}
+
+pub fn parse_json(input: &str) -> Result<JsonValue, DiceError> {
+    let mut chars = CharItr {
+        chars: input.chars().collect(),
+        cur_char: 0,
+        depth: 0,
+    };
+
+    let result = parse_value(&mut chars)?;
+
+    chars.skip_whitespace();
+    if chars.peek().is_some() {
+        return Err(invalid_json("unexpected trailing characters"));
+    }
+
+    return Ok(result);
+}
+
+fn parse_value(chars: &mut CharItr) -> Result<JsonValue, DiceError> {
+    chars.skip_whitespace();
+
+    return match chars.peek() {
+        Some('{') => parse_nested(chars, parse_object),
+        Some('[') => parse_nested(chars, parse_array),
+        Some('"') => Ok(JsonValue::String(parse_string(chars)?)),
+        Some('-' | '0'..='9') => parse_number(chars),
+        Some('t') => parse_literal(chars, "true", JsonValue::Bool(true)),
+        Some('f') => parse_literal(chars, "false", JsonValue::Bool(false)),
+        Some('n') => parse_literal(chars, "null", JsonValue::Null),
+        _ => Err(invalid_json("expected a value")),
+    };
+}
+
+fn parse_nested(
+    chars: &mut CharItr,
+    parse: fn(&mut CharItr) -> Result<JsonValue, DiceError>,
+) -> Result<JsonValue, DiceError> {
+    if chars.depth >= MAX_DEPTH {
+        return Err(invalid_json(&format!(
+            "nested more than {} levels deep",
+            MAX_DEPTH
+        )));
+    }
+
+    chars.depth += 1;
+    let result = parse(chars);
+    chars.depth -= 1;
+
+    return result;
+}
+
+fn parse_object(chars: &mut CharItr) -> Result<JsonValue, DiceError> {
+    let mut members: Vec<(String, JsonValue)> = Vec::new();
+
+    chars.expect('{')?;
+    chars.skip_whitespace();
+    if chars.peek() == Some('}') {
+        chars.next(); // discard } character
+        return Ok(JsonValue::Object(members));
+    }
+
+    loop {
+        chars.skip_whitespace();
+        let key = parse_string(chars)?;
+        chars.expect(':')?;
+        members.push((key, parse_value(chars)?));
+
+        chars.skip_whitespace();
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => return Ok(JsonValue::Object(members)),
+            _ => return Err(invalid_json("expected ',' or '}'")),
+        }
+    }
+}
+
+fn parse_array(chars: &mut CharItr) -> Result<JsonValue, DiceError> {
+    let mut items: Vec<JsonValue> = Vec::new();
+
+    chars.expect('[')?;
+    chars.skip_whitespace();
+    if chars.peek() == Some(']') {
+        chars.next(); // discard ] character
+        return Ok(JsonValue::Array(items));
+    }
+
+    loop {
+        items.push(parse_value(chars)?);
+
+        chars.skip_whitespace();
+        match chars.next() {
+            Some(',') => continue,
+            Some(']') => return Ok(JsonValue::Array(items)),
+            _ => return Err(invalid_json("expected ',' or ']'")),
+        }
+    }
+}
+
+fn parse_string(chars: &mut CharItr) -> Result<String, DiceError> {
+    let mut result = String::new();
+
+    if chars.next() != Some('"') {
+        return Err(invalid_json("expected a string"));
+    }
+
+    loop {
+        let char = match chars.next() {
+            None => return Err(invalid_json("unterminated string")),
+            Some('"') => return Ok(result),
+            Some('\\') => match chars.next() {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('/') => '/',
+                Some('b') => '\u{8}',
+                Some('f') => '\u{c}',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('u') => parse_unicode_escape(chars)?,
+                _ => return Err(invalid_json("invalid escape sequence")),
+            },
+            Some(char) => char,
+        };
+
+        result.push(char);
+    }
+}
+
+fn parse_unicode_escape(chars: &mut CharItr) -> Result<char, DiceError> {
+    let mut code: u32 = 0;
+
+    for _ in 0..4 {
+        let Some(digit) = chars.next().and_then(|c| c.to_digit(16)) else {
+            return Err(invalid_json("invalid unicode escape"));
+        };
+        code = code * 16 + digit;
+    }
+
+    return char::from_u32(code).ok_or_else(|| invalid_json("invalid unicode escape"));
+}
+
+fn parse_number(chars: &mut CharItr) -> Result<JsonValue, DiceError> {
+    let mut number = String::new();
+
+    while let Some(char) = chars.peek()
+        && matches!(char, '-' | '+' | '.' | 'e' | 'E' | '0'..='9')
+    {
+        number.push(char);
+        chars.next();
+    }
+
+    if let Ok(integer) = number.parse::<i128>() {
+        return Ok(JsonValue::Integer(integer));
+    }
+
+    match number.parse::<f64>() {
+        Ok(float) => return Ok(JsonValue::Float(float)),
+        Err(_) => return Err(invalid_json(&format!("invalid number {}", number))),
+    }
+}
+
+fn parse_literal(
+    chars: &mut CharItr,
+    literal: &str,
+    value: JsonValue,
+) -> Result<JsonValue, DiceError> {
+    for expected in literal.chars() {
+        if chars.next() != Some(expected) {
+            return Err(invalid_json("expected a value"));
+        }
+    }
+
+    return Ok(value);
+}
+
+fn invalid_json(reason: &str) -> DiceError {
+    return DiceError::new(&format!("Invalid JSON: {}.", reason));
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonValue::Null => return write!(f, "null"),
+            JsonValue::Bool(bool) => return write!(f, "{}", bool),
+            JsonValue::Integer(integer) => return write!(f, "{}", integer),
+            JsonValue::Float(float) => return write!(f, "{}", float),
+            JsonValue::String(string) => return write_string(f, string),
+            JsonValue::Array(items) => {
+                write!(f, "[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                return write!(f, "]");
+            }
+            JsonValue::Object(members) => {
+                write!(f, "{{")?;
+                for (idx, (key, value)) in members.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                return write!(f, "}}");
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+
+    for char in string.chars() {
+        match char {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+
+    return write!(f, "\"");
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_json__nested_document__returns_matching_values() {
+        let input = r#"{"name": "Brakka\n", "level": 5, "hp": -1.5, "tags": [true, null]}"#;
+
+        let result = parse_json(input).unwrap();
+
+        assert_eq!(
+            JsonValue::Object(vec![
+                (
+                    "name".to_string(),
+                    JsonValue::String("Brakka\n".to_string())
+                ),
+                ("level".to_string(), JsonValue::Integer(5)),
+                ("hp".to_string(), JsonValue::Float(-1.5)),
+                (
+                    "tags".to_string(),
+                    JsonValue::Array(vec![JsonValue::Bool(true), JsonValue::Null])
+                ),
+            ]),
+            result
+        );
+    }
+
+    #[test]
+    fn parse_json__unterminated_object__returns_error() {
+        let input = r#"{"level": 5"#;
+
+        match parse_json(input) {
+            Err(err) => {
+                assert_eq!("Invalid JSON: expected ',' or '}'.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse_json__deeply_nested_arrays__returns_error() {
+        match parse_json(&"[".repeat(60_000)) {
+            Err(err) => assert_eq!(
+                "Invalid JSON: nested more than 128 levels deep.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse_json__trailing_characters__returns_error() {
+        let input = "5 6";
+
+        match parse_json(input) {
+            Err(err) => {
+                assert_eq!("Invalid JSON: unexpected trailing characters.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn to_string__nested_value__writes_compact_json_that_parses_back() {
+        let value = JsonValue::Object(vec![
+            ("result".to_string(), JsonValue::Integer(-3)),
+            (
+                "list".to_string(),
+                JsonValue::Array(vec![JsonValue::Integer(1), JsonValue::Null]),
+            ),
+            (
+                "description".to_string(),
+                JsonValue::String("\"a\"\n\t\\".to_string()),
+            ),
+        ]);
+
+        let result = value.to_string();
+
+        assert_eq!(
+            r#"{"result":-3,"list":[1,null],"description":"\"a\"\n\t\\"}"#,
+            result
+        );
+        assert_eq!(value, parse_json(&result).unwrap());
+    }
+}
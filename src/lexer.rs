@@ -1,91 +1,362 @@
+use std::iter::Enumerate;
+use std::iter::Peekable;
+use std::str::Chars;
+
 use crate::dice_error::DiceError;
+use crate::dice_error::Span;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum TokenType {
     D,
     KeepHigh,
     KeepLow,
+    DropHigh,
+    DropLow,
+    Repeat,
+    Sort,
+    SortDescending,
     Add,
     Subtract,
     Multiply,
     Divide,
     OpenParenthesis,
     CloseParenthesis,
+    OpenBrace,
+    CloseBrace,
     Integer,
+    Identifier,
+    Attribute,
+    Let,
+    In,
+    Assign,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Question,
+    Colon,
+    Comma,
+    Label,
+    CriticalSuccess,
+    CriticalFailure,
+    Advantage,
+    Disadvantage,
+    Best,
+    Worst,
+    Of,
 }
 
+/// A lexical token. `integer` is only meaningful for integers and `text` for names, keywords, and
+/// labels.
 #[derive(Debug, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
     pub integer: u64,
+    pub text: String,
+    pub span: Span,
+}
+
+impl Token {
+    /// The name this token spells, if it can be used as one. Keywords which only mean something
+    /// after another part of an expression (such as the `x` of `6x(4d6)`) are names everywhere
+    /// else.
+    pub fn name(&self) -> Option<&str> {
+        return match self.token_type.can_be_name() {
+            true => Some(&self.text),
+            false => None,
+        };
+    }
 }
 
+impl TokenType {
+    fn can_be_name(&self) -> bool {
+        return matches!(
+            self,
+            TokenType::Identifier
+                | TokenType::Repeat
+                | TokenType::Sort
+                | TokenType::SortDescending
+                | TokenType::CriticalSuccess
+                | TokenType::CriticalFailure
+                | TokenType::Advantage
+                | TokenType::Disadvantage
+                | TokenType::Best
+                | TokenType::Worst
+                | TokenType::Of
+        );
+    }
+}
+
+type CharItr<'a> = Peekable<Enumerate<Chars<'a>>>;
+
 pub fn tokenize(input: &str) -> Result<Vec<Token>, DiceError> {
-    let mut ongoing_integer = String::new();
     let mut results: Vec<Token> = Vec::new();
+    let mut chars = input.chars().enumerate().peekable();
+    let length = input.chars().count();
 
-    for char in input.chars() {
-        let maybe_token_type: Option<TokenType> = match char {
-            'd' | 'D' => Some(TokenType::D),
-            'h' | 'H' => Some(TokenType::KeepHigh),
-            'l' | 'L' => Some(TokenType::KeepLow),
-            '+' => Some(TokenType::Add),
-            '-' => Some(TokenType::Subtract),
-            '*' => Some(TokenType::Multiply),
-            '/' => Some(TokenType::Divide),
-            '(' => Some(TokenType::OpenParenthesis),
-            ')' => Some(TokenType::CloseParenthesis),
-            ' ' | '\n' | '\t' | '\r' => None,
-            '0'..='9' => {
-                ongoing_integer.push(char);
-                None
-            }
-            _ => {
-                return Err(DiceError::new(&format!("Unexpected character: {}", char)));
-            }
-        };
+    while let Some((start, char)) = chars.next() {
+        tokenize_next(start, char, &mut chars, length, true, &mut results)?;
+    }
 
-        let Some(token_type) = maybe_token_type else {
-            continue;
-        };
+    return Ok(results);
+}
+
+/// Splits `input` into the runs of tokens between the characters which [`tokenize`] rejects, such
+/// as the punctuation of the prose around dice expressions. Spans are positions in `input`.
+pub(crate) fn tokenize_runs(input: &str) -> Vec<Vec<Token>> {
+    let mut runs: Vec<Vec<Token>> = vec![Vec::new()];
+    let mut chars = input.chars().enumerate().peekable();
+    let length = input.chars().count();
+    // A `[` with no `]` after it is rejected, rather than reading the rest of the input as a label.
+    let last_close = input
+        .chars()
+        .enumerate()
+        .filter(|(_, c)| *c == ']')
+        .last()
+        .map(|(idx, _)| idx);
+
+    while let Some((start, char)) = chars.next() {
+        let labels = last_close.is_some_and(|close| start < close);
+        let run = runs.last_mut().expect("there is always a run");
 
-        if !ongoing_integer.is_empty() {
-            match ongoing_integer.parse::<u64>() {
-                Ok(integer) => {
-                    results.push(Token {
-                        token_type: TokenType::Integer,
-                        integer: integer,
-                    });
-                }
-                Err(e) => {
-                    return Err(DiceError::new(&format!("Unexpected error: {}", e)));
-                }
+        if tokenize_next(start, char, &mut chars, length, labels, run).is_err() {
+            runs.push(Vec::new());
+        }
+    }
+
+    return runs;
+}
+
+// Reads the token starting with `char` at `start`. A `[` only starts a label when `labels` is set.
+fn tokenize_next(
+    start: usize,
+    char: char,
+    chars: &mut CharItr,
+    length: usize,
+    labels: bool,
+    results: &mut Vec<Token>,
+) -> Result<(), DiceError> {
+    let token_type: TokenType = match char {
+        '+' => TokenType::Add,
+        '-' => TokenType::Subtract,
+        '*' => TokenType::Multiply,
+        '/' => TokenType::Divide,
+        '(' => TokenType::OpenParenthesis,
+        ')' => TokenType::CloseParenthesis,
+        '{' => TokenType::OpenBrace,
+        '}' => TokenType::CloseBrace,
+        '?' => TokenType::Question,
+        ':' => TokenType::Colon,
+        ',' => TokenType::Comma,
+        '=' => match next_if_char(chars, '=') {
+            true => TokenType::Equal,
+            false => TokenType::Assign,
+        },
+        '>' => match next_if_char(chars, '=') {
+            true => TokenType::GreaterOrEqual,
+            false => TokenType::Greater,
+        },
+        '<' => match next_if_char(chars, '=') {
+            true => TokenType::LessOrEqual,
+            false => TokenType::Less,
+        },
+        '!' => match next_if_char(chars, '=') {
+            true => TokenType::NotEqual,
+            false => return Err(unexpected_character(start, char)),
+        },
+        ' ' | '\n' | '\t' | '\r' => return Ok(()),
+        '0'..='9' => {
+            let integer = tokenize_integer(char, chars)?;
+            results.push(Token {
+                token_type: TokenType::Integer,
+                integer: integer,
+                text: String::new(),
+                span: Span::new(start, position(chars, length)),
+            });
+            return Ok(());
+        }
+        '@' => {
+            let Some((_, first)) = chars.next_if(|(_, c)| is_word_char(*c)) else {
+                return Err(unexpected_character(start, char));
             };
+            results.push(Token {
+                token_type: TokenType::Attribute,
+                integer: 0,
+                text: read_word(first, chars),
+                span: Span::new(start, position(chars, length)),
+            });
+            return Ok(());
+        }
+        '[' if labels => {
+            results.push(tokenize_label(start, chars, length)?);
+            return Ok(());
+        }
+        'a'..='z' | 'A'..='Z' | '_' => {
+            let word = read_word(char, chars);
+            tokenize_word(word, start, results);
+            return Ok(());
+        }
+        _ => return Err(unexpected_character(start, char)),
+    };
+
+    results.push(Token {
+        token_type: token_type,
+        integer: 0,
+        text: String::new(),
+        span: Span::new(start, position(chars, length)),
+    });
+
+    return Ok(());
+}
+
+fn position(chars: &mut CharItr, length: usize) -> usize {
+    return match chars.peek() {
+        Some((idx, _)) => *idx,
+        None => length,
+    };
+}
+
+fn next_if_char(chars: &mut CharItr, expected: char) -> bool {
+    return chars.next_if(|(_, c)| *c == expected).is_some();
+}
+
+fn unexpected_character(position: usize, char: char) -> DiceError {
+    return DiceError::with_span(
+        &format!("Unexpected character: {}", char),
+        Span::new(position, position + 1),
+    );
+}
+
+fn tokenize_integer(first: char, chars: &mut CharItr) -> Result<u64, DiceError> {
+    let mut ongoing_integer = String::from(first);
 
-            ongoing_integer.clear();
+    while let Some((_, char)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+        ongoing_integer.push(char);
+    }
+
+    match ongoing_integer.parse::<u64>() {
+        Ok(integer) => return Ok(integer),
+        Err(e) => {
+            return Err(DiceError::new(&format!("Unexpected error: {}", e)));
+        }
+    };
+}
+
+// A label is any text up to the closing bracket, with surrounding whitespace trimmed.
+fn tokenize_label(start: usize, chars: &mut CharItr, length: usize) -> Result<Token, DiceError> {
+    let mut label = String::new();
+
+    loop {
+        match chars.next() {
+            Some((_, ']')) => break,
+            Some((_, char)) => label.push(char),
+            None => {
+                return Err(DiceError::with_span(
+                    "Missing ] after label.",
+                    Span::new(start, length),
+                ));
+            }
         }
+    }
+
+    let span = Span::new(start, position(chars, length));
+    if label.trim().is_empty() {
+        return Err(DiceError::with_span("Labels cannot be empty.", span));
+    }
+
+    return Ok(Token {
+        token_type: TokenType::Label,
+        integer: 0,
+        text: label.trim().to_string(),
+        span: span,
+    });
+}
+
+fn is_word_char(char: char) -> bool {
+    return char.is_ascii_alphabetic() || char == '_';
+}
+
+fn read_word(first: char, chars: &mut CharItr) -> String {
+    let mut word = String::from(first);
+
+    while let Some((_, char)) = chars.next_if(|(_, c)| is_word_char(*c)) {
+        word.push(char);
+    }
+
+    return word;
+}
+
+// Words are runs of letters and underscores. Keywords win, a run made only of dice letters
+// (e.g. "dD" or "lh") is split into one token per letter, and anything else is an identifier.
+fn tokenize_word(word: String, start: usize, results: &mut Vec<Token>) {
+    let end = start + word.chars().count();
 
+    if let Some(token_type) = keyword(&word) {
         results.push(Token {
+            text: match token_type.can_be_name() {
+                true => word,
+                false => String::new(),
+            },
             token_type: token_type,
             integer: 0,
+            span: Span::new(start, end),
         });
+        return;
     }
 
-    if !ongoing_integer.is_empty() {
-        match ongoing_integer.parse::<u64>() {
-            Ok(integer) => {
+    if word.chars().all(|c| dice_letter(c).is_some()) {
+        for (offset, char) in word.chars().enumerate() {
+            if let Some(token_type) = dice_letter(char) {
                 results.push(Token {
-                    token_type: TokenType::Integer,
-                    integer: integer,
+                    token_type: token_type,
+                    integer: 0,
+                    text: String::new(),
+                    span: Span::new(start + offset, start + offset + 1),
                 });
             }
-            Err(e) => {
-                return Err(DiceError::new(&format!("Unexpected error: {}", e)));
-            }
-        };
+        }
+        return;
     }
 
-    return Ok(results);
+    results.push(Token {
+        token_type: TokenType::Identifier,
+        integer: 0,
+        text: word,
+        span: Span::new(start, end),
+    });
+}
+
+fn keyword(word: &str) -> Option<TokenType> {
+    return match word.to_ascii_lowercase().as_str() {
+        "let" => Some(TokenType::Let),
+        "in" => Some(TokenType::In),
+        "kh" => Some(TokenType::KeepHigh),
+        "kl" => Some(TokenType::KeepLow),
+        "dh" => Some(TokenType::DropHigh),
+        "dl" => Some(TokenType::DropLow),
+        "x" => Some(TokenType::Repeat),
+        "s" | "sa" => Some(TokenType::Sort),
+        "sd" => Some(TokenType::SortDescending),
+        "cs" => Some(TokenType::CriticalSuccess),
+        "cf" => Some(TokenType::CriticalFailure),
+        "adv" => Some(TokenType::Advantage),
+        "dis" => Some(TokenType::Disadvantage),
+        "best" => Some(TokenType::Best),
+        "worst" => Some(TokenType::Worst),
+        "of" => Some(TokenType::Of),
+        _ => None,
+    };
+}
+
+fn dice_letter(char: char) -> Option<TokenType> {
+    return match char {
+        'd' | 'D' => Some(TokenType::D),
+        'h' | 'H' => Some(TokenType::KeepHigh),
+        'l' | 'L' => Some(TokenType::KeepLow),
+        _ => None,
+    };
 }
 
 #[cfg(test)]
@@ -113,11 +384,11 @@ mod tests {
 
     #[test]
     fn tokenize_inputUnexpectedCharacter_returnsDiceError() {
-        let input = "k";
+        let input = "&";
 
         match tokenize(input) {
             Err(err) => {
-                assert_eq!("Unexpected character: k", err.message)
+                assert_eq!("Unexpected character: &", err.message)
             }
             Ok(_) => assert!(false, "Should have returned an error"),
         }
@@ -133,58 +404,86 @@ mod tests {
             Token {
                 token_type: TokenType::Integer,
                 integer: 100,
+                text: String::new(),
+                span: Span::new(0, 3),
             },
             Token {
                 token_type: TokenType::D,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(3, 4),
             },
             Token {
                 token_type: TokenType::D,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(4, 5),
             },
             Token {
                 token_type: TokenType::Integer,
                 integer: 1,
+                text: String::new(),
+                span: Span::new(5, 6),
             },
             Token {
                 token_type: TokenType::Add,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(6, 7),
             },
             Token {
                 token_type: TokenType::Subtract,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(7, 8),
             },
             Token {
                 token_type: TokenType::Multiply,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(8, 9),
             },
             Token {
                 token_type: TokenType::Divide,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(9, 10),
             },
             Token {
                 token_type: TokenType::OpenParenthesis,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(10, 11),
             },
             Token {
                 token_type: TokenType::CloseParenthesis,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(11, 12),
             },
             Token {
                 token_type: TokenType::KeepLow,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(12, 13),
             },
             Token {
                 token_type: TokenType::KeepLow,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(13, 14),
             },
             Token {
                 token_type: TokenType::KeepHigh,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(14, 15),
             },
             Token {
                 token_type: TokenType::KeepHigh,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(15, 16),
             },
         ];
         assert_eq!(expected, result);
@@ -200,60 +499,314 @@ mod tests {
             Token {
                 token_type: TokenType::Integer,
                 integer: 100,
+                text: String::new(),
+                span: Span::new(0, 3),
             },
             Token {
                 token_type: TokenType::D,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(7, 8),
             },
             Token {
                 token_type: TokenType::D,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(12, 13),
             },
             Token {
                 token_type: TokenType::Integer,
                 integer: 1,
+                text: String::new(),
+                span: Span::new(17, 18),
             },
             Token {
                 token_type: TokenType::Add,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(22, 23),
             },
             Token {
                 token_type: TokenType::Subtract,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(27, 28),
             },
             Token {
                 token_type: TokenType::Multiply,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(32, 33),
             },
             Token {
                 token_type: TokenType::Divide,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(37, 38),
             },
             Token {
                 token_type: TokenType::OpenParenthesis,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(42, 43),
             },
             Token {
                 token_type: TokenType::CloseParenthesis,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(47, 48),
             },
             Token {
                 token_type: TokenType::KeepLow,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(52, 53),
             },
             Token {
                 token_type: TokenType::KeepLow,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(57, 58),
             },
             Token {
                 token_type: TokenType::KeepHigh,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(62, 63),
             },
             Token {
                 token_type: TokenType::KeepHigh,
                 integer: 0,
+                text: String::new(),
+                span: Span::new(67, 68),
             },
         ];
         assert_eq!(expected, result);
     }
+
+    #[test]
+    fn tokenize_inputLetBinding_returnsKeywordAndIdentifierTokens() {
+        let input = "let atk = d20 in atk";
+
+        let result = tokenize(input).unwrap();
+
+        let expected: Vec<Token> = vec![
+            Token {
+                token_type: TokenType::Let,
+                integer: 0,
+                text: String::new(),
+                span: Span::new(0, 3),
+            },
+            Token {
+                token_type: TokenType::Identifier,
+                integer: 0,
+                text: "atk".to_string(),
+                span: Span::new(4, 7),
+            },
+            Token {
+                token_type: TokenType::Assign,
+                integer: 0,
+                text: String::new(),
+                span: Span::new(8, 9),
+            },
+            Token {
+                token_type: TokenType::D,
+                integer: 0,
+                text: String::new(),
+                span: Span::new(10, 11),
+            },
+            Token {
+                token_type: TokenType::Integer,
+                integer: 20,
+                text: String::new(),
+                span: Span::new(11, 13),
+            },
+            Token {
+                token_type: TokenType::In,
+                integer: 0,
+                text: String::new(),
+                span: Span::new(14, 16),
+            },
+            Token {
+                token_type: TokenType::Identifier,
+                integer: 0,
+                text: "atk".to_string(),
+                span: Span::new(17, 20),
+            },
+        ];
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn tokenize_inputComparisonsAndConditional_returnsMatchingTokens() {
+        let input = "== != > >= < <= ? :";
+
+        let result = tokenize(input).unwrap();
+
+        let types: Vec<TokenType> = result.into_iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Equal,
+                TokenType::NotEqual,
+                TokenType::Greater,
+                TokenType::GreaterOrEqual,
+                TokenType::Less,
+                TokenType::LessOrEqual,
+                TokenType::Question,
+                TokenType::Colon,
+            ],
+            types
+        );
+    }
+
+    #[test]
+    fn tokenize_inputLoneExclamationMark_returnsDiceError() {
+        let input = "1 ! 2";
+
+        match tokenize(input) {
+            Err(err) => {
+                assert_eq!("Unexpected character: !", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn tokenize_inputRepeatWithDropAndSort_returnsMatchingTokens() {
+        let input = "6x(4d6dl1)sd";
+
+        let result = tokenize(input).unwrap();
+
+        let types: Vec<TokenType> = result.into_iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Integer,
+                TokenType::Repeat,
+                TokenType::OpenParenthesis,
+                TokenType::Integer,
+                TokenType::D,
+                TokenType::Integer,
+                TokenType::DropLow,
+                TokenType::Integer,
+                TokenType::CloseParenthesis,
+                TokenType::SortDescending,
+            ],
+            types
+        );
+    }
+
+    #[test]
+    fn tokenize_inputLabelledRoll_returnsTrimmedLabelToken() {
+        let input = "2d6[ fire ] + 1";
+
+        let result = tokenize(input).unwrap();
+
+        assert_eq!(
+            Token {
+                token_type: TokenType::Label,
+                integer: 0,
+                text: "fire".to_string(),
+                span: Span::new(3, 11),
+            },
+            result[3]
+        );
+        assert_eq!(TokenType::Add, result[4].token_type);
+    }
+
+    #[test]
+    fn tokenize_inputUnclosedLabel_returnsSpannedDiceError() {
+        let input = "2d6[fire";
+
+        match tokenize(input) {
+            Err(err) => {
+                assert_eq!("Missing ] after label.", err.message);
+                assert_eq!(Some(Span::new(3, 8)), err.span);
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn tokenize_inputCriticalRanges_returnsCriticalTokens() {
+        let input = "1d20cs>=19cf1";
+
+        let result = tokenize(input).unwrap();
+
+        let types: Vec<TokenType> = result.into_iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Integer,
+                TokenType::D,
+                TokenType::Integer,
+                TokenType::CriticalSuccess,
+                TokenType::GreaterOrEqual,
+                TokenType::Integer,
+                TokenType::CriticalFailure,
+                TokenType::Integer,
+            ],
+            types
+        );
+    }
+
+    #[test]
+    fn tokenize_inputBestOfAndAdvantage_returnsKeywordTokens() {
+        let input = "best3of(d20) + 1d20 dis";
+
+        let result = tokenize(input).unwrap();
+
+        let types: Vec<TokenType> = result.into_iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Best,
+                TokenType::Integer,
+                TokenType::Of,
+                TokenType::OpenParenthesis,
+                TokenType::D,
+                TokenType::Integer,
+                TokenType::CloseParenthesis,
+                TokenType::Add,
+                TokenType::Integer,
+                TokenType::D,
+                TokenType::Integer,
+                TokenType::Disadvantage,
+            ],
+            types
+        );
+    }
+
+    #[test]
+    fn tokenize_runs_inputProseWithRejectedCharacters_returnsTheRunsBetweenThem() {
+        let input = "Hit: 2d6 [fire]. 99999999999999999999 or d4! [open";
+
+        let runs: Vec<Vec<(TokenType, Span)>> = tokenize_runs(input)
+            .into_iter()
+            .map(|run| {
+                run.into_iter()
+                    .map(|token| (token.token_type, token.span))
+                    .collect()
+            })
+            .collect();
+
+        assert_eq!(
+            vec![
+                vec![
+                    (TokenType::Identifier, Span::new(0, 3)),
+                    (TokenType::Colon, Span::new(3, 4)),
+                    (TokenType::Integer, Span::new(5, 6)),
+                    (TokenType::D, Span::new(6, 7)),
+                    (TokenType::Integer, Span::new(7, 8)),
+                    (TokenType::Label, Span::new(9, 15)),
+                ],
+                vec![],
+                vec![
+                    (TokenType::Identifier, Span::new(38, 40)),
+                    (TokenType::D, Span::new(41, 42)),
+                    (TokenType::Integer, Span::new(42, 43)),
+                ],
+                vec![],
+                vec![(TokenType::Identifier, Span::new(46, 50))],
+            ],
+            runs
+        );
+    }
 }
@@ -0,0 +1,175 @@
+//! A dice algebra lexer, parser, and executor.
+//!
+//! The quickest way to roll an expression is [`evaluate`]:
+//!
+//! ```
+//! use rand::SeedableRng;
+//!
+//! let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+//! let outcome = dice_algebra_calculator::evaluate("2d6 + 3", &mut rng).unwrap();
+//!
+//! assert!((5..=15).contains(&outcome.result));
+//! ```
+//!
+//! Each stage is also available on its own: [`tokenize`] turns a string into tokens, [`parse`]
+//! turns tokens into an [`AST`], and [`execute`] rolls the dice of an [`AST`]. Callers that need
+//! `@attributes`, `let` bindings carried between expressions, or macros can use [`evaluate_in`].
+//!
+//! Dice are rolled by a [`DiceSource`]. Any random number generator is one, and
+//! [`dice_source::ScriptedDice`] and [`dice_source::PromptedDice`] supply known values or ask for
+//! the results of real dice instead.
+//!
+//! Expressions can also be built in code with the [`builder`] module instead of being parsed:
+//!
+//! ```
+//! use dice_algebra_calculator::builder::Dice;
+//!
+//! let attack = Dice::new(2, 20).keep_high(1) + 5;
+//! ```
+
+pub mod ast;
+pub mod builder;
+pub mod bytecode;
+pub mod character;
+pub mod chat;
+pub mod critical;
+pub mod dice_error;
+pub mod dice_source;
+pub mod environment;
+pub mod estimate;
+pub mod fair;
+pub mod formatter;
+pub mod json;
+pub mod lexer;
+pub mod macros;
+pub mod parser;
+pub mod scan;
+pub mod server;
+pub mod session;
+pub mod simplify;
+pub mod stats;
+pub mod uniformity;
+
+pub use ast::AST;
+pub use ast::ASTExecutable;
+pub use ast::ASTExecutionResult;
+pub use ast::DieRoll;
+pub use dice_error::DiceError;
+pub use dice_error::Span;
+pub use dice_source::DiceSource;
+pub use environment::Environment;
+pub use lexer::Token;
+pub use macros::MacroTable;
+
+/// The result of evaluating an expression: its total, the list of entries if the expression
+/// produced a list, and a description of every roll that was made.
+pub type Outcome = ASTExecutionResult;
+
+/// Splits a dice algebra expression into tokens.
+pub fn tokenize(input: &str) -> Result<Vec<Token>, DiceError> {
+    return lexer::tokenize(input);
+}
+
+/// Parses tokens produced by [`tokenize`] into an [`AST`] without expanding any macros.
+pub fn parse(tokens: &[Token]) -> Result<AST, DiceError> {
+    return parser::parse(tokens);
+}
+
+/// Rolls the dice of an [`AST`] in an empty [`Environment`].
+pub fn execute(ast: &AST, dice: &mut impl DiceSource) -> Result<Outcome, DiceError> {
+    return ast.execute_ast(dice);
+}
+
+/// Tokenizes, parses, and executes a dice algebra expression.
+///
+/// Errors which can be traced to part of the expression (e.g. an undefined name) carry a
+/// [`Span`] of character positions in `expression`.
+pub fn evaluate(expression: &str, dice: &mut impl DiceSource) -> Result<Outcome, DiceError> {
+    return evaluate_in(
+        expression,
+        dice,
+        &mut Environment::new(),
+        &MacroTable::new(),
+    );
+}
+
+/// Like [`evaluate`], but expands `macros` while parsing and resolves names against `env`.
+pub fn evaluate_in(
+    expression: &str,
+    dice: &mut impl DiceSource,
+    env: &mut Environment,
+    macros: &MacroTable,
+) -> Result<Outcome, DiceError> {
+    let tokens = lexer::tokenize(expression)?;
+
+    let ast = parser::parse_with_macros(&tokens, macros)?;
+
+    return ast.execute_ast_in(dice, env);
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn evaluate__valid_expression__returns_the_same_outcome_as_the_separate_stages() {
+        let input = "d5 + 2d6h1 - 3d100l2";
+        let mut staged_rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let tokens = tokenize(input).unwrap();
+        let ast = parse(&tokens).unwrap();
+        let staged = execute(&ast, &mut staged_rng).unwrap();
+        let outcome = evaluate(input, &mut rng).unwrap();
+
+        assert_eq!(staged.result, outcome.result);
+        assert_eq!(staged.description, outcome.description);
+    }
+
+    #[test]
+    fn evaluate__invalid_expression__returns_DiceError() {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match evaluate("2d6 +", &mut rng) {
+            Err(err) => {
+                assert_eq!("Invalid expression.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn evaluate_in__attributes_and_macros__resolves_both() {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let mut env = Environment::with_attributes(HashMap::from([("prof".to_string(), 2)]));
+        let mut macros = MacroTable::new();
+        macros.define("bonus(n) = n + @prof").unwrap();
+
+        let outcome = evaluate_in("bonus(3) * 2", &mut rng, &mut env, &macros).unwrap();
+
+        assert_eq!(10, outcome.result);
+    }
+
+    #[test]
+    fn evaluate_in__nested_repeats_over_the_budget__returns_DiceError_and_the_next_expression_still_rolls()
+     {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let mut env = Environment::new().without_descriptions();
+        let macros = MacroTable::new();
+
+        match evaluate_in("10000x(10000x(1d6))", &mut rng, &mut env, &macros) {
+            Err(err) => assert_eq!(
+                "Cannot roll or repeat more than 1000000 times in one expression.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+
+        assert!(evaluate_in("1000x(100d6)", &mut rng, &mut env, &macros).is_ok());
+    }
+}
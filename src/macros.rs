@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+
+use crate::dice_error::DiceError;
+use crate::lexer;
+use crate::lexer::Token;
+use crate::lexer::TokenType;
+use crate::parser;
+
+pub struct Macro {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub body: Vec<Token>,
+}
+
+/// Named, optionally parameterised expression templates which are expanded while parsing.
+#[derive(Default)]
+pub struct MacroTable {
+    macros: HashMap<String, Macro>,
+}
+
+impl MacroTable {
+    pub fn new() -> MacroTable {
+        return MacroTable {
+            macros: HashMap::new(),
+        };
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Macro> {
+        return self.macros.get(name);
+    }
+
+    /// Adds a macro, replacing any macro of the same name. A definition which would make a macro
+    /// expand into itself, directly or through other macros, is rejected and the table is left as
+    /// it was.
+    pub fn define(&mut self, definition: &str) -> Result<&Macro, DiceError> {
+        let tokens = lexer::tokenize(definition)?;
+        let definition = parse_definition(tokens)?;
+        let name = definition.name.clone();
+
+        let previous = self.macros.insert(name.clone(), definition);
+
+        let mut chain = vec![name.clone()];
+        if self.reaches(&name, &mut chain) {
+            match previous {
+                Some(previous) => self.macros.insert(name.clone(), previous),
+                None => self.macros.remove(&name),
+            };
+            return Err(DiceError::new(&format!(
+                "Recursive macro: {}",
+                chain.join(" -> ")
+            )));
+        }
+
+        return Ok(&self.macros[&name]);
+    }
+
+    // Follows the macros called by the last macro of `chain`, leaving the path in `chain` if one
+    // of them leads back to `target`.
+    fn reaches(&self, target: &str, chain: &mut Vec<String>) -> bool {
+        let Some(definition) = chain.last().and_then(|name| self.macros.get(name)) else {
+            return false;
+        };
+
+        for called in definition.called_macros() {
+            if called == target {
+                chain.push(called);
+                return true;
+            }
+            if chain.contains(&called) || !self.macros.contains_key(&called) {
+                continue;
+            }
+
+            chain.push(called);
+            if self.reaches(target, chain) {
+                return true;
+            }
+            chain.pop();
+        }
+
+        return false;
+    }
+}
+
+impl Macro {
+    // The names in the body which can refer to other macros. Parameters and names bound by a `let`
+    // in the body shadow macros, except where they are called.
+    fn called_macros(&self) -> Vec<String> {
+        let mut bound: Vec<&str> = self.parameters.iter().map(|name| name.as_str()).collect();
+        for (token, next) in self.body.iter().zip(self.body.iter().skip(1)) {
+            if token.token_type == TokenType::Let
+                && let Some(name) = next.name()
+            {
+                bound.push(name);
+            }
+        }
+
+        let mut called: Vec<String> = Vec::new();
+        for (idx, token) in self.body.iter().enumerate() {
+            if token.name().is_none() || called.contains(&token.text) {
+                continue;
+            }
+
+            let is_call = matches!(
+                self.body.get(idx + 1),
+                Some(next) if next.token_type == TokenType::OpenParenthesis
+            );
+            if is_call || !bound.contains(&token.text.as_str()) {
+                called.push(token.text.clone());
+            }
+        }
+
+        return called;
+    }
+}
+
+pub fn load_macros(path: &str) -> Result<MacroTable, DiceError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => return parse_macros(&contents),
+        Err(err) => {
+            return Err(DiceError::new(&format!(
+                "Failed to read macro file {}: {}",
+                path, err
+            )));
+        }
+    }
+}
+
+// A macro file holds one definition per line, with blank lines and `#` comments.
+pub fn parse_macros(input: &str) -> Result<MacroTable, DiceError> {
+    let mut macros = MacroTable::new();
+
+    for (idx, raw_line) in input.lines().enumerate() {
+        let line = match raw_line.split_once('#') {
+            Some((content, _comment)) => content.trim(),
+            None => raw_line.trim(),
+        };
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Err(err) = macros.define(line) {
+            return Err(DiceError::new(&format!(
+                "Invalid macro file line {}: {}",
+                idx + 1,
+                err.message
+            )));
+        }
+    }
+
+    return Ok(macros);
+}
+
+// Definitions look like `name = body` or `name(a, b) = body`.
+pub fn is_definition(tokens: &[Token]) -> bool {
+    let mut itr = tokens.iter();
+
+    match itr.next() {
+        Some(token) if token.name().is_some() => {}
+        _ => return false,
+    };
+
+    match itr.next() {
+        Some(token) if token.token_type == TokenType::Assign => return true,
+        Some(token) if token.token_type == TokenType::OpenParenthesis => {}
+        _ => return false,
+    };
+
+    for token in itr.by_ref() {
+        if token.name().is_some() {
+            continue;
+        }
+        match token.token_type {
+            TokenType::Comma => continue,
+            TokenType::CloseParenthesis => break,
+            _ => return false,
+        }
+    }
+
+    return matches!(itr.next(), Some(token) if token.token_type == TokenType::Assign);
+}
+
+fn parse_definition(tokens: Vec<Token>) -> Result<Macro, DiceError> {
+    if !is_definition(&tokens) {
+        return Err(DiceError::new(
+            "Invalid macro definition (expected `name = body` or `name(a, b) = body`).",
+        ));
+    }
+
+    let mut tokens = tokens.into_iter();
+    let mut parameters: Vec<String> = Vec::new();
+
+    let name = match tokens.next() {
+        Some(token) => token.text,
+        None => return Err(DiceError::new("Invalid macro definition.")),
+    };
+
+    for token in tokens.by_ref() {
+        match token.token_type {
+            _ if token.name().is_some() => {
+                if parameters.contains(&token.text) {
+                    return Err(DiceError::new(&format!(
+                        "Macro {} has more than one parameter named {}.",
+                        name, token.text
+                    )));
+                }
+                parameters.push(token.text);
+            }
+            TokenType::Assign => break,
+            _ => continue,
+        }
+    }
+
+    let body: Vec<Token> = tokens.collect();
+
+    if body.is_empty() {
+        return Err(DiceError::new(&format!(
+            "Macro {} has an empty body.",
+            name
+        )));
+    }
+
+    parser::validate_parenthesis(&body)?;
+
+    return Ok(Macro {
+        name: name,
+        parameters: parameters,
+        body: body,
+    });
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::ast::ASTExecutable;
+
+    fn evaluate(input: &str, macros: &MacroTable) -> Result<i128, DiceError> {
+        let tokens = lexer::tokenize(input)?;
+        let ast = parser::parse_with_macros(&tokens, macros)?;
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        return Ok(ast.execute_ast(&mut rng)?.result);
+    }
+
+    #[test]
+    fn define__parameterised_macro__records_name_parameters_and_body() {
+        let mut macros = MacroTable::new();
+
+        let definition = macros
+            .define("attack(bonus, extra) = 1d20 + bonus")
+            .unwrap();
+
+        assert_eq!("attack", definition.name);
+        assert_eq!(vec!["bonus", "extra"], definition.parameters);
+        assert_eq!(5, definition.body.len());
+    }
+
+    #[test]
+    fn define__not_a_definition__returns_error() {
+        let mut macros = MacroTable::new();
+
+        match macros.define("1d20 + 5") {
+            Err(err) => {
+                assert_eq!(
+                    "Invalid macro definition (expected `name = body` or `name(a, b) = body`).",
+                    err.message
+                )
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn is_definition__call_without_assignment__returns_false() {
+        let tokens = lexer::tokenize("attack(5) + 2").unwrap();
+
+        assert!(!is_definition(&tokens));
+    }
+
+    #[test]
+    fn parse_with_macros__macro_with_arguments__expands_into_the_body() {
+        let macros = parse_macros("attack(bonus) = 10 + bonus\nfireball = 8 * 6\n").unwrap();
+
+        assert_eq!(26, evaluate("2 * attack(3)", &macros).unwrap());
+        assert_eq!(48, evaluate("fireball", &macros).unwrap());
+        assert_eq!(
+            61,
+            evaluate("attack(fireball) + attack(0) - 7", &macros).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_with_macros__let_binding_shadows_macro__uses_the_binding() {
+        let macros = parse_macros("fireball = 8 * 6").unwrap();
+
+        assert_eq!(
+            2,
+            evaluate("let fireball = 2 in fireball", &macros).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_with_macros__macros_named_like_shorthand_keywords__expand() {
+        let macros = parse_macros("of(n) = n + 1\ndis = 7\nbest(n) = n * 2\n").unwrap();
+
+        assert_eq!(18, evaluate("of(2) + dis + best(4)", &macros).unwrap());
+    }
+
+    #[test]
+    fn parse_with_macros__nested_calls_expanding_too_far__returns_spanned_error() {
+        let macros = parse_macros("twice(a) = a + a").unwrap();
+        let input = format!("{}1{}", "twice(".repeat(24), ")".repeat(24));
+
+        match evaluate(&input, &macros) {
+            Err(err) => {
+                assert_eq!(
+                    "Macro calls may not expand into more than 100000 parts of an expression.",
+                    err.message
+                );
+                assert!(err.span.is_some());
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+        assert_eq!(
+            2048,
+            evaluate(
+                &format!("{}1{}", "twice(".repeat(11), ")".repeat(11)),
+                &macros
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_with_macros__wrong_number_of_arguments__returns_spanned_error() {
+        let macros = parse_macros("attack(bonus) = 10 + bonus").unwrap();
+
+        match evaluate("1 + attack(1, 2)", &macros) {
+            Err(err) => {
+                assert_eq!("Macro attack expects 1 argument(s) but got 2.", err.message);
+                assert_eq!(Some(crate::dice_error::Span::new(4, 10)), err.span);
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn define__mutually_recursive_macros__returns_error_and_keeps_the_old_definition() {
+        let mut macros = parse_macros("ping(n) = pong(n) + 1\npong(n) = n").unwrap();
+
+        match macros.define("pong(n) = ping(n)") {
+            Err(err) => {
+                assert_eq!("Recursive macro: pong -> ping -> pong", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+        assert_eq!(3, evaluate("ping(2)", &macros).unwrap());
+    }
+
+    #[test]
+    fn parse_with_macros__caller_binds_a_name_the_body_uses__returns_error() {
+        let macros = parse_macros("bonus = level + 1\ntwice(n) = let a = 2 in a * n").unwrap();
+
+        match evaluate("let level = 5 in bonus", &macros) {
+            Err(err) => assert_eq!(
+                "Macro bonus uses level, which a let around the call also binds.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+        match evaluate("let a = 5 in twice(a)", &macros) {
+            Err(err) => assert_eq!(
+                "Macro twice binds a with a let, which would capture its argument for n.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+        assert_eq!(10, evaluate("let b = 5 in twice(b)", &macros).unwrap());
+    }
+
+    #[test]
+    fn parse_with_macros__call_to_unknown_macro__returns_error() {
+        let macros = MacroTable::new();
+
+        match evaluate("attack(1)", &macros) {
+            Err(err) => {
+                assert_eq!("Undefined macro: attack", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse_macros__invalid_line__returns_error_with_line_number() {
+        match parse_macros("# house rules\nfireball = \n") {
+            Err(err) => {
+                assert_eq!(
+                    "Invalid macro file line 2: Macro fireball has an empty body.",
+                    err.message
+                )
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+}
@@ -1,55 +1,545 @@
+use std::collections::HashMap;
 use std::env;
+use std::io::Read;
 use std::io::Write;
+use std::net::TcpListener;
 
-use crate::{ast::ASTExecutable, dice_error::DiceError};
+use dice_algebra_calculator::AST;
+use dice_algebra_calculator::ASTExecutable;
+use dice_algebra_calculator::DiceError;
+use dice_algebra_calculator::Environment;
+use dice_algebra_calculator::MacroTable;
+use dice_algebra_calculator::character;
+use dice_algebra_calculator::critical;
+use dice_algebra_calculator::critical::CriticalRule;
+use dice_algebra_calculator::dice_source::PromptedDice;
+use dice_algebra_calculator::estimate;
+use dice_algebra_calculator::estimate::Estimate;
+use dice_algebra_calculator::estimate::Rounding;
+use dice_algebra_calculator::fair;
+use dice_algebra_calculator::fair::FairRoll;
+use dice_algebra_calculator::fair::FairSession;
+use dice_algebra_calculator::lexer;
+use dice_algebra_calculator::macros;
+use dice_algebra_calculator::parser;
+use dice_algebra_calculator::scan;
+use dice_algebra_calculator::server;
+use dice_algebra_calculator::server::Server;
+use dice_algebra_calculator::session;
+use dice_algebra_calculator::simplify;
+use dice_algebra_calculator::uniformity;
 
-mod ast;
-mod dice_error;
-mod lexer;
-mod parser;
+struct Options {
+    format: bool,
+    estimate: bool,
+    rounding: Rounding,
+    manual: bool,
+    simplify: bool,
+    crit: Option<CriticalRule>,
+    verbose: bool,
+    json: bool,
+    repl: bool,
+    character: Option<String>,
+    macros: Option<String>,
+    log: Option<String>,
+    replay: Option<String>,
+    fair: bool,
+    verify: Option<(String, FairRoll)>,
+    self_test: bool,
+    scan: bool,
+    serve: bool,
+    bind: Option<String>,
+}
 
 fn main() {
-    let mut verbose = false;
-    if let Some(arg) = env::args().nth(1)
-        && arg == "--v"
-    {
-        verbose = true;
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => exit_with_error(&err),
+    };
+
+    if options.self_test {
+        match run_self_test() {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(err) => exit_with_error(&err),
+        }
+    }
+
+    let attributes = match &options.character {
+        None => HashMap::new(),
+        Some(path) => match character::load_character(path) {
+            Ok(attributes) => attributes,
+            Err(err) => exit_with_error(&err),
+        },
+    };
+
+    let mut macros = match &options.macros {
+        None => MacroTable::new(),
+        Some(path) => match macros::load_macros(path) {
+            Ok(macros) => macros,
+            Err(err) => exit_with_error(&err),
+        },
+    };
+
+    if options.serve {
+        let address = options.bind.as_deref().unwrap_or(server::DEFAULT_ADDRESS);
+        if let Err(err) = run_server(address, Server::new(macros, attributes)) {
+            exit_with_error(&err);
+        }
+        return;
+    }
+
+    let mut env = Environment::with_attributes(attributes);
+
+    // Descriptions are only printed in verbose mode, as part of the JSON output, or to show the
+    // verified dice.
+    if !options.verbose && !options.json && options.verify.is_none() {
+        env = env.without_descriptions();
     }
 
-    print!("Please enter a dice algebra expression: ");
+    if let Some(path) = &options.replay {
+        match run_replay(path) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(err) => exit_with_error(&err),
+        }
+    }
+
+    if options.scan {
+        let mut text = String::new();
+        if let Err(err) = std::io::stdin().read_to_string(&mut text) {
+            exit_with_error(&DiceError::new(&format!(
+                "Could not read the text: {}",
+                err
+            )));
+        }
+        run_scan(text.trim_end(), &options, &mut env);
+        return;
+    }
+
+    let mut fair = match options.fair {
+        true => Some(start_fair_session()),
+        false => None,
+    };
+
+    if options.repl {
+        run_repl(&options, &mut env, &mut macros, &mut fair);
+        reveal_server_seed(&fair);
+        return;
+    }
+
+    let input_buffer = prompt("Please enter a dice algebra expression: ").unwrap_or_default();
+    let input = input_buffer.trim_end();
+
+    let result = match &options.verify {
+        Some((commitment, roll)) => {
+            run_verify(input, commitment, roll, &options, &mut env, &macros)
+        }
+        None => run(input, &options, &mut env, &macros, &mut fair),
+    };
+    reveal_server_seed(&fair);
+
+    if let Err(err) = result {
+        print_error(input, &err);
+        std::process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, DiceError> {
+    let mut options = Options {
+        format: false,
+        estimate: false,
+        rounding: Rounding::Down,
+        manual: false,
+        simplify: false,
+        crit: None,
+        verbose: false,
+        json: false,
+        repl: false,
+        character: None,
+        macros: None,
+        log: None,
+        replay: None,
+        fair: false,
+        verify: None,
+        self_test: false,
+        scan: false,
+        serve: false,
+        bind: None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "fmt" => options.format = true,
+            "estimate" => options.estimate = true,
+            "--round" => match args.next() {
+                Some(policy) => options.rounding = Rounding::parse(&policy)?,
+                None => return Err(DiceError::new("--round requires a rounding policy.")),
+            },
+            "selftest" => options.self_test = true,
+            "scan" => options.scan = true,
+            "serve" => options.serve = true,
+            "--bind" => match args.next() {
+                Some(address) => options.bind = Some(address),
+                None => return Err(DiceError::new("--bind requires an address.")),
+            },
+            "replay" => match args.next() {
+                Some(path) => options.replay = Some(path),
+                None => return Err(DiceError::new("replay requires a session log path.")),
+            },
+            "--manual" => options.manual = true,
+            "--simplify" => options.simplify = true,
+            "--crit" => match args.next() {
+                Some(rule) => options.crit = Some(CriticalRule::parse(&rule)?),
+                None => return Err(DiceError::new("--crit requires a rule.")),
+            },
+            "--v" => options.verbose = true,
+            "--json" => options.json = true,
+            "--repl" => options.repl = true,
+            "--character" => match args.next() {
+                Some(path) => options.character = Some(path),
+                None => return Err(DiceError::new("--character requires a file path.")),
+            },
+            "--macros" => match args.next() {
+                Some(path) => options.macros = Some(path),
+                None => return Err(DiceError::new("--macros requires a file path.")),
+            },
+            "verify" => {
+                let (Some(commitment), Some(server_seed), Some(client_seed), Some(nonce)) =
+                    (args.next(), args.next(), args.next(), args.next())
+                else {
+                    return Err(DiceError::new(
+                        "verify requires a commitment, a server seed, a client seed, and a nonce.",
+                    ));
+                };
+                let Ok(nonce) = nonce.parse::<u64>() else {
+                    return Err(DiceError::new(&format!("Invalid nonce: {}", nonce)));
+                };
+
+                let roll = FairRoll {
+                    server_seed: server_seed,
+                    client_seed: client_seed,
+                    nonce: nonce,
+                };
+                options.verify = Some((commitment, roll));
+            }
+            "--fair" => options.fair = true,
+            "--log" => match args.next() {
+                Some(path) => options.log = Some(path),
+                None => return Err(DiceError::new("--log requires a file path.")),
+            },
+            _ => return Err(DiceError::new(&format!("Unknown argument: {}", arg))),
+        }
+    }
+
+    if options.fair && options.manual {
+        return Err(DiceError::new("--fair cannot be combined with --manual."));
+    }
+
+    return Ok(options);
+}
+
+fn prompt(message: &str) -> Option<String> {
+    print!("{}", message);
     if let Err(err) = std::io::stdout().flush() {
         eprintln!("Error: Failed to flush stdout buffer: {err}");
         std::process::exit(1);
     }
 
     let mut input_buffer = String::new();
-    if let Err(err) = std::io::stdin().read_line(&mut input_buffer) {
-        eprintln!("Error: Failed to read input: {err}");
-        std::process::exit(1);
+    match std::io::stdin().read_line(&mut input_buffer) {
+        Ok(0) => return None,
+        Ok(_) => return Some(input_buffer),
+        Err(err) => {
+            eprintln!("Error: Failed to read input: {err}");
+            std::process::exit(1);
+        }
     };
-    let input = input_buffer.trim_end();
+}
 
-    if let Err(err) = run(input, verbose) {
-        eprintln!("Error! {}", err.message);
-        std::process::exit(1);
+fn print_error(input: &str, err: &DiceError) {
+    eprintln!("Error! {}", err.message);
+
+    if let Some(span) = err.span {
+        eprintln!("  {}", input);
+        eprintln!(
+            "  {}{}",
+            " ".repeat(span.start),
+            "^".repeat(span.end.saturating_sub(span.start).max(1))
+        );
     }
 }
 
-fn run(input: &str, verbose: bool) -> Result<(), DiceError> {
-    let tokens = lexer::tokenize(input)?;
+fn exit_with_error(err: &DiceError) -> ! {
+    eprintln!("Error! {}", err.message);
+    std::process::exit(1);
+}
 
-    let ast = parser::parse(&tokens)?;
+// Parses an expression and applies the transformations asked for on the command line.
+fn parse_input(input: &str, options: &Options, macros: &MacroTable) -> Result<AST, DiceError> {
+    let mut ast = parser::parse_with_macros(&lexer::tokenize(input)?, macros)?;
 
-    let result = ast.execute_ast(&mut rand::rng())?;
+    if let Some(rule) = &options.crit {
+        ast = critical::critical_damage(ast, rule);
+    }
+    if options.simplify {
+        ast = simplify::simplify(ast);
+    }
 
-    if verbose {
+    return Ok(ast);
+}
+
+fn run(
+    input: &str,
+    options: &Options,
+    env: &mut Environment,
+    macros: &MacroTable,
+    fair: &mut Option<FairSession>,
+) -> Result<(), DiceError> {
+    let ast = parse_input(input, options, macros)?;
+
+    if options.format {
+        println!("{}", ast);
+        return Ok(());
+    }
+    if options.estimate {
+        let estimates = estimate::estimates(&ast, options.rounding, env)?;
+        println!("{} = {}", input, estimates);
+        return Ok(());
+    }
+
+    if options.crit.is_some() && !options.simplify && !options.json {
+        println!("Critical damage: {}", ast);
+    }
+    if options.simplify && !options.json {
+        println!("Simplified to: {}", ast);
+    }
+
+    // Dice rolled by the calculator come from a generator with a fresh seed, so that a session log
+    // can roll them again.
+    let (entry, result) = match (options.manual, fair) {
+        (true, _) => {
+            let dice = PromptedDice::new(std::io::stdin().lock(), std::io::stdout());
+            session::record(input, &ast, None, dice, env)?
+        }
+        (false, Some(fair)) => {
+            let roll = fair.next_roll();
+            eprintln!("Rolling with nonce {}", roll.nonce);
+            session::record(input, &ast, None, roll.dice(), env)?
+        }
+        (false, None) => {
+            let seed = rand::random::<u64>();
+            session::record(input, &ast, Some(seed), session::seeded_dice(seed), env)?
+        }
+    };
+
+    if let Some(path) = &options.log {
+        session::append_entry(path, &entry)?;
+    }
+
+    if options.json {
+        println!("{}", result.to_json());
+        return Ok(());
+    }
+
+    if options.verbose {
         print!("{}", result.description);
     }
-    println!("\nYour result is: {}", result.result);
+    let subtotals = match result.subtotals_summary() {
+        Some(summary) => format!(" ({})", summary),
+        None => String::new(),
+    };
+    match &result.list {
+        Some(list) => println!(
+            "\nYour result is: {:?} (total {}){}",
+            list, result.result, subtotals
+        ),
+        None => println!("\nYour result is: {}{}", result.result, subtotals),
+    }
+    if let Some(summary) = result.criticals.summary() {
+        println!("*** {}! ***", summary.to_uppercase());
+    }
+
+    return Ok(());
+}
+
+fn start_fair_session() -> FairSession {
+    let server_seed = fair::generate_server_seed();
+    eprintln!("Server seed commitment: {}", fair::commitment(&server_seed));
+
+    let client_seed = prompt("Please enter a client seed: ").unwrap_or_default();
+
+    return FairSession::new(server_seed, client_seed.trim().to_string());
+}
+
+fn reveal_server_seed(fair: &Option<FairSession>) {
+    if let Some(fair) = fair {
+        eprintln!("\nServer seed: {}", fair.server_seed());
+    }
+}
+
+fn run_verify(
+    input: &str,
+    commitment: &str,
+    roll: &FairRoll,
+    options: &Options,
+    env: &mut Environment,
+    macros: &MacroTable,
+) -> Result<(), DiceError> {
+    let ast = parse_input(input, options, macros)?;
+
+    let result = ast.execute_ast_in(&mut roll.verify(commitment)?, env)?;
+
+    print!("{}", result.description);
+    println!("\nVerified result: {}", result.result);
 
     return Ok(());
 }
 
+fn run_repl(
+    options: &Options,
+    env: &mut Environment,
+    macros: &mut MacroTable,
+    fair: &mut Option<FairSession>,
+) {
+    println!("Enter dice algebra expressions or macro definitions like `fireball = 8d6`.");
+    println!("Enter `exit` or press Ctrl-D to quit.");
+
+    while let Some(input_buffer) = prompt("\n> ") {
+        let input = input_buffer.trim();
+
+        if input.is_empty() {
+            continue;
+        }
+        if input == "exit" {
+            break;
+        }
+
+        if let Err(err) = run_repl_line(input, options, env, macros, fair) {
+            print_error(input, &err);
+        }
+    }
+}
+
+fn run_repl_line(
+    input: &str,
+    options: &Options,
+    env: &mut Environment,
+    macros: &mut MacroTable,
+    fair: &mut Option<FairSession>,
+) -> Result<(), DiceError> {
+    let tokens = lexer::tokenize(input)?;
+
+    if macros::is_definition(&tokens) {
+        let definition = macros.define(input)?;
+        println!("Defined macro {}", definition.name);
+        return Ok(());
+    }
+
+    return run(input, options, env, macros, fair);
+}
+
+fn run_server(address: &str, server: Server) -> Result<(), DiceError> {
+    let listener = TcpListener::bind(address)
+        .map_err(|err| DiceError::new(&format!("Failed to listen on {}: {}", address, err)))?;
+
+    println!("Serving dice rolls on http://{}", address);
+
+    return server.serve(listener);
+}
+
+// Checks that the generator the CLI rolls with rolls every face of common dice equally often, and
+// returns whether it does.
+fn run_self_test() -> Result<bool, DiceError> {
+    let mut dice = session::seeded_dice(rand::random::<u64>());
+    let reports = uniformity::self_test(&mut dice, 100_000)?;
+
+    for report in &reports {
+        println!("{}", report);
+    }
+
+    let passed = reports.iter().all(|report| report.passed());
+    match passed {
+        true => println!("\nEvery die passed."),
+        false => println!(
+            "\nSome dice failed. A fair generator fails a die about once in a thousand runs, so run the test again before suspecting it."
+        ),
+    }
+
+    return Ok(passed);
+}
+
+// Prints `text` with a note after each dice expression found in it, then where each one was found.
+fn run_scan(text: &str, options: &Options, env: &mut Environment) {
+    let found = scan::scan(text);
+    let notes: Vec<String> = found
+        .iter()
+        .map(|embedded| scan_note(&embedded.ast, options, env))
+        .collect();
+
+    println!("{}", scan::annotate(text, &found, &notes));
+    if !found.is_empty() {
+        println!();
+    }
+    for (embedded, note) in found.iter().zip(&notes) {
+        println!(
+            "Found {} at characters {}-{}: {}",
+            embedded.text,
+            embedded.span.start + 1,
+            embedded.span.end,
+            note
+        );
+    }
+}
+
+// Rolls an expression found by `scan`, or works out its average with `estimate`. An expression
+// which can't be rolled is noted rather than stopping the scan.
+fn scan_note(ast: &AST, options: &Options, env: &mut Environment) -> String {
+    let note = match options.estimate {
+        true => estimate::estimate(ast, Estimate::Average, options.rounding, env)
+            .map(|average| format!("avg {}", average)),
+        false => ast
+            .execute_ast_in(&mut rand::rng(), env)
+            .map(|outcome| format!("rolled {}", outcome.result)),
+    };
+
+    return note.unwrap_or_else(|err| format!("error: {}", err.message));
+}
+
+// Replays every entry of a session log, and returns whether they all rolled the same again.
+fn run_replay(path: &str) -> Result<bool, DiceError> {
+    let entries = session::load_session(path)?;
+    let mut diverged = 0;
+
+    for (idx, entry) in entries.iter().enumerate() {
+        let divergence = match session::replay(entry) {
+            Ok(replayed) => entry.divergence(&replayed),
+            Err(err) => Some(format!("it failed with: {}", err.message)),
+        };
+
+        match divergence {
+            None => println!("Entry {} ({}): OK", idx + 1, entry.input),
+            Some(divergence) => {
+                diverged += 1;
+                println!(
+                    "Entry {} ({}): DIVERGED, {}",
+                    idx + 1,
+                    entry.input,
+                    divergence
+                );
+            }
+        }
+    }
+
+    println!(
+        "\nReplayed {} entries, {} diverged.",
+        entries.len(),
+        diverged
+    );
+
+    return Ok(diverged == 0);
+}
+
 #[cfg(test)]
 #[allow(non_snake_case)]
 mod tests {
@@ -65,14 +555,149 @@ mod tests {
         let tree = parser::parse(&tokens).unwrap();
         let result = tree.execute_ast(&mut rng).unwrap();
 
-        assert_eq!(-33, result.result);
+        assert_eq!(-31, result.result);
         assert_eq!(
             concat!(
-                "\nRolling d5...\nYou rolled: 4\n",
-                "\nRolling 2d6...\nYou rolled: 4\nYou rolled: 3\n",
-                "\nRolling 3d100...\nYou rolled: 18\nYou rolled: 26\nYou rolled: 96\n"
+                "\nRolling d5...\nYou rolled: 5\n",
+                "\nRolling 2d6...\nYou rolled: 5\nYou rolled: 3 (dropped)\n",
+                "\nRolling 3d100...\nYou rolled: 18\nYou rolled: 26\nYou rolled: 97 (dropped)\n"
             ),
             result.description
         );
     }
+
+    #[test]
+    fn run__let_binding_referenced_twice__reuses_the_single_roll() {
+        let input = "let a = 1d20 in a + (a >= 20 ? 10 : 0)";
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let tokens = lexer::tokenize(input).unwrap();
+        let tree = parser::parse(&tokens).unwrap();
+        let result = tree.execute_ast(&mut rng).unwrap();
+
+        assert_eq!(30, result.result);
+        assert_eq!(
+            concat!(
+                "\nRolling 1d20...\nYou rolled: 20\n",
+                "\nBinding a = 20\n",
+                "\nUsing a = 20\n",
+                "\nUsing a = 20\n"
+            ),
+            result.description
+        );
+    }
+
+    #[test]
+    fn run__attributes_from_character_sheet__are_added_to_the_roll() {
+        let input = "1d20 + @str_mod + @prof";
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let attributes = character::parse_json_character(r#"{"str_mod": 3, "prof": 2}"#).unwrap();
+
+        let tokens = lexer::tokenize(input).unwrap();
+        let tree = parser::parse(&tokens).unwrap();
+        let result = tree
+            .execute_ast_in(&mut rng, &mut Environment::with_attributes(attributes))
+            .unwrap();
+
+        assert_eq!(25, result.result);
+    }
+
+    #[test]
+    fn parse_args__unknown_argument__returns_error() {
+        let args = vec!["--x".to_string()];
+
+        match parse_args(args.into_iter()) {
+            Err(err) => {
+                assert_eq!("Unknown argument: --x", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse_args__replay_subcommand__reads_the_session_log_path() {
+        let args = vec!["replay".to_string(), "session.jsonl".to_string()];
+
+        let options = parse_args(args.into_iter()).unwrap();
+
+        assert_eq!(Some("session.jsonl".to_string()), options.replay);
+    }
+
+    #[test]
+    fn parse_args__verify_subcommand__reads_the_revealed_roll() {
+        let args = ["verify", "abc123", "server", "client", "4"].map(str::to_string);
+
+        let (commitment, roll) = parse_args(args.into_iter()).unwrap().verify.unwrap();
+
+        assert_eq!("abc123", commitment);
+        assert_eq!(
+            FairRoll {
+                server_seed: "server".to_string(),
+                client_seed: "client".to_string(),
+                nonce: 4,
+            },
+            roll
+        );
+    }
+
+    #[test]
+    fn parse_args__fmt_subcommand__formats_instead_of_rolling() {
+        let args = vec!["fmt".to_string(), "--repl".to_string()];
+
+        let options = parse_args(args.into_iter()).unwrap();
+
+        assert!(options.format);
+        assert!(options.repl);
+    }
+
+    #[test]
+    fn parse_args__estimate_subcommand__reads_the_rounding_policy() {
+        let args = vec![
+            "estimate".to_string(),
+            "--round".to_string(),
+            "nearest".to_string(),
+        ];
+
+        let options = parse_args(args.into_iter()).unwrap();
+
+        assert!(options.estimate);
+        assert_eq!(Rounding::Nearest, options.rounding);
+    }
+
+    #[test]
+    fn scan_note__estimate_subcommand__notes_the_average() {
+        let args = vec!["scan".to_string(), "estimate".to_string()];
+        let options = parse_args(args.into_iter()).unwrap();
+        let ast = parser::parse(&lexer::tokenize("2d8 + 4").unwrap()).unwrap();
+
+        let note = scan_note(&ast, &options, &mut Environment::new());
+
+        assert!(options.scan);
+        assert_eq!("avg 13", note);
+    }
+
+    #[test]
+    fn parse_input__crit_flag__rolls_the_critical_damage() {
+        let args = vec!["--crit".to_string(), "double".to_string()];
+        let options = parse_args(args.into_iter()).unwrap();
+
+        let ast = parse_input("2d6 + d8 + 3", &options, &MacroTable::new()).unwrap();
+
+        assert_eq!("4d6 + 2d8 + 3", ast.to_string());
+    }
+
+    #[test]
+    fn run__repeated_ability_score_rolls__returns_each_entry_separately() {
+        let input = "6x(4d6dl1)";
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let tokens = lexer::tokenize(input).unwrap();
+        let tree = parser::parse(&tokens).unwrap();
+        let result = tree.execute_ast(&mut rng).unwrap();
+
+        let list = result.list.unwrap();
+        assert_eq!(6, list.len());
+        assert_eq!(list.iter().sum::<i128>(), result.result);
+        assert_eq!(6, result.description.matches("Entry").count());
+    }
 }
@@ -1,16 +1,60 @@
+use std::collections::HashMap;
+
 use crate::ast::AST;
+use crate::ast::AttributeAST;
+use crate::ast::Comparison;
+use crate::ast::ConditionalAST;
+use crate::ast::DynamicRollAST;
+use crate::ast::GroupAST;
 use crate::ast::IntegerAST;
+use crate::ast::LabelAST;
+use crate::ast::LetAST;
 use crate::ast::LongRollAST;
 use crate::ast::MathAST;
 use crate::ast::MathOperation;
+use crate::ast::RepeatAST;
 use crate::ast::ShortRollAST;
+use crate::ast::SortOrder;
+use crate::ast::SuccessCondition;
+use crate::ast::VariableAST;
+use crate::ast::check_repeat_count;
 use crate::dice_error::DiceError;
+use crate::dice_error::Span;
 use crate::lexer::Token;
 use crate::lexer::TokenType;
+use crate::macros::Macro;
+use crate::macros::MacroTable;
+
+/// The deepest an expression may be nested, counting both parentheses and the operations which
+/// are parts of other operations, e.g. each `+` of `1 + 2 + 3`.
+pub const MAX_DEPTH: usize = 128;
+
+/// The most nodes which the macro calls of an expression may expand into, counting every call
+/// separately. Each use of a parameter copies its argument, so nested calls grow quickly.
+pub const MAX_EXPANDED_NODES: usize = 100_000;
+
+struct Selection {
+    keep_high: Option<u64>,
+    keep_low: Option<u64>,
+    drop_high: Option<u64>,
+    drop_low: Option<u64>,
+}
 
 struct TokenItr<'a> {
     tokens: &'a [Token],
     cur_token: usize,
+    macros: &'a MacroTable,
+    arguments: HashMap<String, AST>,
+    expanding: Vec<String>,
+    scope: Vec<String>,
+    // The names bound by lets around the macro call being expanded, which its body can't see.
+    caller_scope: Vec<String>,
+    // Nodes built from a macro body point at the macro call, not into the definition text.
+    call_span: Option<Span>,
+    // How many expressions are being parsed inside each other.
+    depth: usize,
+    // How many nodes the macro calls parsed so far have expanded into.
+    expanded: usize,
 }
 
 impl TokenItr<'_> {
@@ -47,24 +91,161 @@ impl TokenItr<'_> {
     fn is_empty(&self) -> bool {
         return self.cur_token >= self.tokens.len();
     }
+
+    // Whether the next token is written straight after the previous one, with no space between.
+    fn next_is_attached(&self) -> bool {
+        if self.cur_token == 0 {
+            return false;
+        }
+
+        return match self.peek() {
+            Some(token) => token.span.start == self.tokens[self.cur_token - 1].span.end,
+            None => false,
+        };
+    }
+
+    // Whether `name` is bound by a let, a macro argument or a macro, so a keyword spelled the same
+    // way means that instead.
+    fn binds(&self, name: &str) -> bool {
+        return self.scope.iter().any(|bound| bound == name)
+            || self.arguments.contains_key(name)
+            || self.macros.get(name).is_some();
+    }
 }
 
 pub fn parse(tokens: &[Token]) -> Result<AST, DiceError> {
+    return parse_with_macros(tokens, &MacroTable::new());
+}
+
+pub fn parse_with_macros(tokens: &[Token], macros: &MacroTable) -> Result<AST, DiceError> {
     validate_not_empty(tokens)?;
     validate_parenthesis(tokens)?;
 
     let mut itr = TokenItr {
         tokens: tokens,
         cur_token: 0,
+        macros: macros,
+        arguments: HashMap::new(),
+        expanding: Vec::new(),
+        scope: Vec::new(),
+        caller_scope: Vec::new(),
+        call_span: None,
+        depth: 0,
+        expanded: 0,
     };
 
-    let result = parse_add(&mut itr);
+    let result = parse_expression(&mut itr)?;
 
     validate_consumed_all_tokens(&itr)?;
+    validate_depth(&result, 1)?;
+
+    return Ok(result);
+}
+
+fn parse_expression(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    // The operations of the expression nest it further, so the depth is restored afterwards
+    // rather than just decremented.
+    let depth = tokens.depth;
+    let result = nest(tokens).and_then(|_| parse_let_or_conditional(tokens));
+    tokens.depth = depth;
 
     return result;
 }
 
+// Goes one level deeper into the expression being parsed.
+fn nest(tokens: &mut TokenItr) -> Result<(), DiceError> {
+    if tokens.depth >= MAX_DEPTH {
+        return Err(too_deep());
+    }
+
+    tokens.depth += 1;
+    return Ok(());
+}
+
+fn too_deep() -> DiceError {
+    return DiceError::new(&format!(
+        "Expressions may not be nested more than {} levels deep.",
+        MAX_DEPTH
+    ));
+}
+
+fn parse_let_or_conditional(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    if let Some(token) = tokens.peek()
+        && token.token_type == TokenType::Let
+    {
+        return parse_let(tokens);
+    }
+
+    return parse_conditional(tokens);
+}
+
+fn parse_let(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    expect(tokens, TokenType::Let)?;
+    let name = parse_identifier_raw(tokens)?;
+    expect(tokens, TokenType::Assign)?;
+    let value = parse_expression(tokens)?;
+    expect(tokens, TokenType::In)?;
+    tokens.scope.push(name.clone());
+    let body = parse_expression(tokens);
+    tokens.scope.pop();
+    let body = body?;
+
+    return Ok(AST::Let(LetAST {
+        name: name,
+        value: Box::new(value),
+        body: Box::new(body),
+    }));
+}
+
+fn parse_conditional(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let condition = parse_comparison(tokens)?;
+
+    match tokens.peek() {
+        Some(token) if token.token_type == TokenType::Question => {}
+        _ => return Ok(condition),
+    };
+
+    tokens.next(); // discard ? token
+    let if_true = parse_expression(tokens)?;
+    expect(tokens, TokenType::Colon)?;
+    let if_false = parse_expression(tokens)?;
+
+    return Ok(AST::Conditional(ConditionalAST {
+        condition: Box::new(condition),
+        if_true: Box::new(if_true),
+        if_false: Box::new(if_false),
+    }));
+}
+
+fn parse_comparison(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let left = parse_add(tokens)?;
+
+    let Some(token) = tokens.peek() else {
+        return Ok(left);
+    };
+
+    let operation = match token.token_type {
+        TokenType::Equal => MathOperation::Equal,
+        TokenType::NotEqual => MathOperation::NotEqual,
+        TokenType::Greater => MathOperation::Greater,
+        TokenType::GreaterOrEqual => MathOperation::GreaterOrEqual,
+        TokenType::Less => MathOperation::Less,
+        TokenType::LessOrEqual => MathOperation::LessOrEqual,
+        _ => return Ok(left),
+    };
+
+    tokens.next(); // discard comparison token
+    nest(tokens)?;
+
+    let right = parse_add(tokens)?;
+
+    return Ok(AST::Math(MathAST {
+        left: Box::new(left),
+        right: Box::new(right),
+        operation: operation,
+    }));
+}
+
 fn parse_add(tokens: &mut TokenItr) -> Result<AST, DiceError> {
     let mut left = parse_mult(tokens)?;
 
@@ -76,6 +257,7 @@ fn parse_add(tokens: &mut TokenItr) -> Result<AST, DiceError> {
         };
 
         tokens.next(); // discard + or - token
+        nest(tokens)?;
 
         let right = parse_mult(tokens)?;
 
@@ -102,6 +284,7 @@ fn parse_mult(tokens: &mut TokenItr) -> Result<AST, DiceError> {
         };
 
         tokens.next(); // discard * or / token
+        nest(tokens)?;
 
         let right = parse_atom(tokens)?;
 
@@ -117,18 +300,172 @@ fn parse_mult(tokens: &mut TokenItr) -> Result<AST, DiceError> {
     return Ok(left);
 }
 
+// Any atom may be followed by labels, e.g. `2d6[fire]`, and by `adv` or `dis` to roll it twice
+// and keep the better or worse result, e.g. `1d20 adv`.
 fn parse_atom(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let mut result = parse_unlabelled_atom(tokens)?;
+
+    while let Some(token) = tokens.peek() {
+        match token.token_type {
+            TokenType::Label => {
+                let label = token.text.clone();
+                tokens.next(); // discard [label] token
+                nest(tokens)?;
+
+                result = AST::Label(LabelAST {
+                    label: label,
+                    expression: Box::new(result),
+                });
+            }
+            TokenType::Advantage | TokenType::Disadvantage => {
+                let keep_high = token.token_type == TokenType::Advantage;
+                tokens.next(); // discard adv or dis token
+                nest(tokens)?;
+
+                result = with_advantage(result, keep_high);
+            }
+            _ => break,
+        }
+    }
+
+    return Ok(result);
+}
+
+fn parse_unlabelled_atom(tokens: &mut TokenItr) -> Result<AST, DiceError> {
     let next_token = match tokens.peek() {
         None => return Err(DiceError::new("Invalid expression.")),
         Some(token) => token,
     };
 
+    // `adv` and `dis` are keywords unless something binds them, and `best` and `worst` only when
+    // a count follows, so they can still be used as names.
+    if (next_token.token_type == TokenType::Advantage
+        || next_token.token_type == TokenType::Disadvantage)
+        && !tokens.binds(&next_token.text)
+    {
+        return parse_advantage(tokens);
+    }
+
+    if (next_token.token_type == TokenType::Best || next_token.token_type == TokenType::Worst)
+        && matches!(tokens.peek_next(), Some(token) if token.token_type == TokenType::Integer)
+    {
+        return parse_best_of(tokens);
+    }
+
+    if next_token.name().is_some() {
+        return parse_name(tokens);
+    }
+
+    if next_token.token_type == TokenType::Attribute {
+        return parse_attribute(tokens);
+    }
+
+    if next_token.token_type == TokenType::OpenBrace {
+        return parse_group(tokens);
+    }
+
     if next_token.token_type != TokenType::OpenParenthesis {
         return parse_roll(tokens);
     }
 
+    let result = parse_parenthetical(tokens)?;
+
+    if let Some(token) = tokens.peek()
+        && token.token_type == TokenType::D
+    {
+        return parse_dynamic_roll(tokens, result);
+    }
+
+    return Ok(result);
+}
+
+// On their own, `adv` and `dis` roll a d20 with advantage or disadvantage.
+fn parse_advantage(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let keep_high = match tokens.next() {
+        None => return Err(DiceError::new("Invalid expression.")),
+        Some(token) => token.token_type == TokenType::Advantage,
+    };
+
+    return Ok(with_advantage(
+        AST::ShortRoll(ShortRollAST { faces: 20 }),
+        keep_high,
+    ));
+}
+
+// `bestNof` and `worstNof` roll an atom N times and keep the highest or lowest total, e.g.
+// `best3of(1d20 + 2)`.
+fn parse_best_of(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let keep_high = match tokens.next() {
+        None => return Err(DiceError::new("Invalid expression.")),
+        Some(token) => token.token_type == TokenType::Best,
+    };
+    let count = parse_integer_raw(tokens)?;
+    expect(tokens, TokenType::Of)?;
+    let expression = parse_atom(tokens)?;
+
+    if count == 0 {
+        return Err(DiceError::new(
+            "Cannot choose the best or worst of 0 rolls.",
+        ));
+    }
+    check_repeat_count(count)?;
+
+    return Ok(keep_one(expression, count, keep_high));
+}
+
+// Rolls an expression twice and keeps the higher (or lower) total. A single die is rolled as two
+// dice instead, so `1d20 adv` is `2d20h1`.
+fn with_advantage(ast: AST, keep_high: bool) -> AST {
+    let (keep_high, keep_low) = match keep_high {
+        true => (Some(1), None),
+        false => (None, Some(1)),
+    };
+
+    return match ast {
+        AST::ShortRoll(roll) => AST::LongRoll(LongRollAST {
+            die: 2,
+            faces: roll.faces,
+            keep_high: keep_high,
+            keep_low: keep_low,
+            drop_high: None,
+            drop_low: None,
+            critical_success: None,
+            critical_failure: None,
+        }),
+        AST::LongRoll(roll)
+            if roll.die == 1
+                && roll.keep_high.is_none()
+                && roll.keep_low.is_none()
+                && roll.drop_high.is_none()
+                && roll.drop_low.is_none() =>
+        {
+            AST::LongRoll(LongRollAST {
+                die: 2,
+                keep_high: keep_high,
+                keep_low: keep_low,
+                ..roll
+            })
+        }
+        ast => keep_one(ast, 2, keep_high.is_some()),
+    };
+}
+
+// Repeats `expression` `count` times and keeps only the highest (or lowest) total.
+fn keep_one(expression: AST, count: u64, keep_high: bool) -> AST {
+    return AST::Repeat(RepeatAST {
+        count: count,
+        expression: Box::new(expression),
+        keep_high: keep_high.then_some(1),
+        keep_low: (!keep_high).then_some(1),
+        drop_high: None,
+        drop_low: None,
+        sort: None,
+    });
+}
+
+fn parse_parenthetical(tokens: &mut TokenItr) -> Result<AST, DiceError> {
     tokens.next(); // discard ( token
-    let result = parse_add(tokens);
+    let result = parse_expression(tokens);
     tokens.next(); // discard ) token
 
     return result;
@@ -150,28 +487,130 @@ fn parse_roll(tokens: &mut TokenItr) -> Result<AST, DiceError> {
         return parse_longroll(tokens);
     }
 
+    if let Some(token) = tokens.peek_next()
+        && token.token_type == TokenType::Repeat
+    {
+        return parse_repeat(tokens);
+    }
+
     return parse_integer(tokens);
 }
 
-fn parse_longroll(tokens: &mut TokenItr) -> Result<AST, DiceError> {
-    let die = parse_integer_raw(tokens)?;
+fn parse_repeat(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let count = parse_integer_raw(tokens)?;
+    expect(tokens, TokenType::Repeat)?;
+    let expression = parse_atom(tokens)?;
+    let selection = parse_selection(tokens)?;
 
-    match tokens.next() {
-        None => return Err(DiceError::new("Invalid expression.")),
-        Some(token) => {
-            if token.token_type != TokenType::D {
-                return Err(DiceError::new("Invalid expression"));
-            }
+    let sort = match tokens.peek().map(|token| &token.token_type) {
+        Some(TokenType::Sort) => Some(SortOrder::Ascending),
+        Some(TokenType::SortDescending) => Some(SortOrder::Descending),
+        _ => None,
+    };
+    if sort.is_some() {
+        tokens.next(); // discard s or sd token
+    }
+
+    return Ok(AST::Repeat(RepeatAST {
+        count: count,
+        expression: Box::new(expression),
+        keep_high: selection.keep_high,
+        keep_low: selection.keep_low,
+        drop_high: selection.drop_high,
+        drop_low: selection.drop_low,
+        sort: sort,
+    }));
+}
+
+fn parse_group(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let mut children: Vec<AST> = Vec::new();
+
+    expect(tokens, TokenType::OpenBrace)?;
+
+    loop {
+        children.push(parse_expression(tokens)?);
+
+        match tokens.next() {
+            Some(token) if token.token_type == TokenType::Comma => continue,
+            Some(token) if token.token_type == TokenType::CloseBrace => break,
+            _ => return Err(DiceError::new("Invalid expression.")),
         }
+    }
+
+    let selection = parse_selection(tokens)?;
+    // `{3d6, 2d8}>=10` counts successes, while `{3d6, 2d8} >= 10` compares the group's total.
+    let success = match tokens.next_is_attached() {
+        true => parse_success_condition(tokens)?,
+        false => None,
     };
 
-    let faces = parse_integer_raw(tokens)?;
+    return Ok(AST::Group(GroupAST {
+        children: children,
+        keep_high: selection.keep_high,
+        keep_low: selection.keep_low,
+        drop_high: selection.drop_high,
+        drop_low: selection.drop_low,
+        success: success,
+    }));
+}
 
-    let mut result_data = LongRollAST {
-        die: die,
-        faces: faces,
+fn parse_success_condition(tokens: &mut TokenItr) -> Result<Option<SuccessCondition>, DiceError> {
+    let comparison = match tokens.peek().map(|token| &token.token_type) {
+        Some(TokenType::Assign | TokenType::Equal) => Comparison::Equal,
+        Some(TokenType::NotEqual) => Comparison::NotEqual,
+        Some(TokenType::Greater) => Comparison::Greater,
+        Some(TokenType::GreaterOrEqual) => Comparison::GreaterOrEqual,
+        Some(TokenType::Less) => Comparison::Less,
+        Some(TokenType::LessOrEqual) => Comparison::LessOrEqual,
+        _ => return Ok(None),
+    };
+
+    tokens.next(); // discard comparison token
+
+    return Ok(Some(SuccessCondition {
+        comparison: comparison,
+        target: parse_integer_raw(tokens)?,
+    }));
+}
+
+// The critical success range (`cs`) and critical failure range (`cf`) of a roll, in either order.
+// A range is a comparison with a target, or just a target which the die must roll exactly.
+fn parse_critical_ranges(
+    tokens: &mut TokenItr,
+) -> Result<(Option<SuccessCondition>, Option<SuccessCondition>), DiceError> {
+    let mut success: Option<SuccessCondition> = None;
+    let mut failure: Option<SuccessCondition> = None;
+
+    loop {
+        let is_success = match tokens.peek().map(|token| &token.token_type) {
+            Some(TokenType::CriticalSuccess) if success.is_none() => true,
+            Some(TokenType::CriticalFailure) if failure.is_none() => false,
+            _ => return Ok((success, failure)),
+        };
+
+        tokens.next(); // discard cs or cf token
+
+        let range = match parse_success_condition(tokens)? {
+            Some(range) => range,
+            None => SuccessCondition {
+                comparison: Comparison::Equal,
+                target: parse_integer_raw(tokens)?,
+            },
+        };
+
+        match is_success {
+            true => success = Some(range),
+            false => failure = Some(range),
+        }
+    }
+}
+
+fn parse_selection(tokens: &mut TokenItr) -> Result<Selection, DiceError> {
+    let mut selection = Selection {
         keep_high: None,
         keep_low: None,
+        drop_high: None,
+        drop_low: None,
     };
 
     let maybe_token = tokens.peek();
@@ -179,11 +618,19 @@ fn parse_longroll(tokens: &mut TokenItr) -> Result<AST, DiceError> {
         match token.token_type {
             TokenType::KeepHigh => {
                 tokens.next(); // discard h token
-                result_data.keep_high = Some(parse_integer_raw(tokens)?)
+                selection.keep_high = Some(parse_integer_raw(tokens)?)
             }
             TokenType::KeepLow => {
                 tokens.next(); // discard l token
-                result_data.keep_low = Some(parse_integer_raw(tokens)?)
+                selection.keep_low = Some(parse_integer_raw(tokens)?)
+            }
+            TokenType::DropHigh => {
+                tokens.next(); // discard dh token
+                selection.drop_high = Some(parse_integer_raw(tokens)?)
+            }
+            TokenType::DropLow => {
+                tokens.next(); // discard dl token
+                selection.drop_low = Some(parse_integer_raw(tokens)?)
             }
             _ => {
                 // do nothing
@@ -191,16 +638,70 @@ fn parse_longroll(tokens: &mut TokenItr) -> Result<AST, DiceError> {
         }
     }
 
-    return Ok(AST::LongRoll(result_data));
+    return Ok(selection);
+}
+
+fn parse_longroll(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let die = parse_integer_raw(tokens)?;
+
+    if let Some(token) = tokens.peek_next()
+        && token.token_type == TokenType::OpenParenthesis
+    {
+        return parse_dynamic_roll(tokens, AST::Integer(IntegerAST { integer: die }));
+    }
+
+    match tokens.next() {
+        None => return Err(DiceError::new("Invalid expression.")),
+        Some(token) => {
+            if token.token_type != TokenType::D {
+                return Err(DiceError::new("Invalid expression"));
+            }
+        }
+    };
+
+    let faces = parse_integer_raw(tokens)?;
+
+    let selection = parse_selection(tokens)?;
+    let (critical_success, critical_failure) = parse_critical_ranges(tokens)?;
+
+    return Ok(AST::LongRoll(LongRollAST {
+        die: die,
+        faces: faces,
+        keep_high: selection.keep_high,
+        keep_low: selection.keep_low,
+        drop_high: selection.drop_high,
+        drop_low: selection.drop_low,
+        critical_success: critical_success,
+        critical_failure: critical_failure,
+    }));
 }
 
 fn parse_shortroll(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    if let Some(token) = tokens.peek_next()
+        && token.token_type == TokenType::OpenParenthesis
+    {
+        return parse_dynamic_roll(tokens, AST::Integer(IntegerAST { integer: 1 }));
+    }
+
     if let Some(token) = tokens.next()
         && token.token_type == TokenType::D
     {
-        return Ok(AST::ShortRoll(ShortRollAST {
-            faces: parse_integer_raw(tokens)?,
-        }));
+        let faces = parse_integer_raw(tokens)?;
+
+        // A short roll with critical ranges is a roll of one die, e.g. `d20cs>=19` is `1d20cs>=19`.
+        return Ok(match parse_critical_ranges(tokens)? {
+            (None, None) => AST::ShortRoll(ShortRollAST { faces: faces }),
+            (critical_success, critical_failure) => AST::LongRoll(LongRollAST {
+                die: 1,
+                faces: faces,
+                keep_high: None,
+                keep_low: None,
+                drop_high: None,
+                drop_low: None,
+                critical_success: critical_success,
+                critical_failure: critical_failure,
+            }),
+        });
     }
 
     return Err(DiceError::new(
@@ -208,6 +709,232 @@ fn parse_shortroll(tokens: &mut TokenItr) -> Result<AST, DiceError> {
     ));
 }
 
+// Either the number of dice or the number of faces of a roll may be a parenthetical expression,
+// e.g. `(1d4)d6` or `2d(1d8 + 2)`.
+fn parse_dynamic_roll(tokens: &mut TokenItr, die: AST) -> Result<AST, DiceError> {
+    expect(tokens, TokenType::D)?;
+
+    let faces = match tokens.peek() {
+        Some(token) if token.token_type == TokenType::OpenParenthesis => {
+            parse_parenthetical(tokens)?
+        }
+        _ => parse_integer(tokens)?,
+    };
+
+    let selection = parse_selection(tokens)?;
+    let (critical_success, critical_failure) = parse_critical_ranges(tokens)?;
+
+    return Ok(AST::DynamicRoll(DynamicRollAST {
+        die: Box::new(die),
+        faces: Box::new(faces),
+        keep_high: selection.keep_high,
+        keep_low: selection.keep_low,
+        drop_high: selection.drop_high,
+        drop_low: selection.drop_low,
+        critical_success: critical_success,
+        critical_failure: critical_failure,
+    }));
+}
+
+// A name resolves, in order, to an enclosing let binding, a parameter of the macro being
+// expanded, or a macro. Anything else is left as a variable to be looked up during execution.
+fn parse_name(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let (name, span) = match tokens.next() {
+        None => return Err(DiceError::new("Invalid expression.")),
+        Some(token) => (token.text.clone(), token.span),
+    };
+    let span = tokens.call_span.unwrap_or(span);
+
+    let is_call = match tokens.peek() {
+        Some(token) => token.token_type == TokenType::OpenParenthesis,
+        None => false,
+    };
+
+    if !is_call && tokens.scope.contains(&name) {
+        return Ok(AST::Variable(VariableAST {
+            name: name,
+            span: span,
+        }));
+    }
+
+    if !is_call && let Some(argument) = tokens.arguments.get(&name) {
+        if let Some(captured) = free_variables(argument)
+            .into_iter()
+            .find(|variable| tokens.scope.contains(variable))
+        {
+            return Err(DiceError::with_span(
+                &format!(
+                    "Macro {} binds {} with a let, which would capture its argument for {}.",
+                    tokens.expanding.last().cloned().unwrap_or_default(),
+                    captured,
+                    name
+                ),
+                span,
+            ));
+        }
+        return Ok(argument.clone());
+    }
+
+    let macros = tokens.macros;
+    match macros.get(&name) {
+        Some(definition) => return expand_macro(tokens, definition, span),
+        None if is_call => {
+            return Err(DiceError::with_span(
+                &format!("Undefined macro: {}", name),
+                span,
+            ));
+        }
+        None if tokens.caller_scope.contains(&name) => {
+            return Err(DiceError::with_span(
+                &format!(
+                    "Macro {} uses {}, which a let around the call also binds.",
+                    tokens.expanding.last().cloned().unwrap_or_default(),
+                    name
+                ),
+                span,
+            ));
+        }
+        None => {
+            return Ok(AST::Variable(VariableAST {
+                name: name,
+                span: span,
+            }));
+        }
+    }
+}
+
+// The variables of `ast` which aren't bound by a let inside it.
+fn free_variables(ast: &AST) -> Vec<String> {
+    return match ast {
+        AST::Variable(variable) => vec![variable.name.clone()],
+        AST::DynamicRoll(roll) => [free_variables(&roll.die), free_variables(&roll.faces)].concat(),
+        AST::Math(math) => [free_variables(&math.left), free_variables(&math.right)].concat(),
+        AST::Let(binding) => {
+            let mut body = free_variables(&binding.body);
+            body.retain(|variable| *variable != binding.name);
+            [free_variables(&binding.value), body].concat()
+        }
+        AST::Conditional(conditional) => [
+            free_variables(&conditional.condition),
+            free_variables(&conditional.if_true),
+            free_variables(&conditional.if_false),
+        ]
+        .concat(),
+        AST::Repeat(repeat) => free_variables(&repeat.expression),
+        AST::Group(group) => group.children.iter().flat_map(free_variables).collect(),
+        AST::Label(label) => free_variables(&label.expression),
+        _ => Vec::new(),
+    };
+}
+
+fn expand_macro<'a>(
+    tokens: &mut TokenItr<'a>,
+    definition: &'a Macro,
+    span: Span,
+) -> Result<AST, DiceError> {
+    let arguments = parse_macro_arguments(tokens)?;
+
+    if arguments.len() != definition.parameters.len() {
+        return Err(DiceError::with_span(
+            &format!(
+                "Macro {} expects {} argument(s) but got {}.",
+                definition.name,
+                definition.parameters.len(),
+                arguments.len()
+            ),
+            span,
+        ));
+    }
+
+    if tokens.expanding.contains(&definition.name) {
+        let mut chain = tokens.expanding.clone();
+        chain.push(definition.name.clone());
+        return Err(DiceError::with_span(
+            &format!("Recursive macro: {}", chain.join(" -> ")),
+            span,
+        ));
+    }
+
+    let mut expanding = tokens.expanding.clone();
+    expanding.push(definition.name.clone());
+
+    let mut body = TokenItr {
+        tokens: &definition.body,
+        cur_token: 0,
+        macros: tokens.macros,
+        arguments: definition
+            .parameters
+            .iter()
+            .cloned()
+            .zip(arguments)
+            .collect(),
+        expanding: expanding,
+        scope: Vec::new(),
+        caller_scope: [tokens.caller_scope.clone(), tokens.scope.clone()].concat(),
+        call_span: Some(span),
+        depth: tokens.depth,
+        expanded: tokens.expanded,
+    };
+
+    let result = parse_expression(&mut body)?;
+
+    validate_consumed_all_tokens(&body)?;
+
+    tokens.expanded = body.expanded + node_count(&result);
+    if tokens.expanded > MAX_EXPANDED_NODES {
+        return Err(DiceError::with_span(
+            &format!(
+                "Macro calls may not expand into more than {} parts of an expression.",
+                MAX_EXPANDED_NODES
+            ),
+            span,
+        ));
+    }
+
+    return Ok(result);
+}
+
+fn parse_macro_arguments(tokens: &mut TokenItr) -> Result<Vec<AST>, DiceError> {
+    let mut arguments: Vec<AST> = Vec::new();
+
+    match tokens.peek() {
+        Some(token) if token.token_type == TokenType::OpenParenthesis => {}
+        _ => return Ok(arguments),
+    };
+
+    tokens.next(); // discard ( token
+    if let Some(token) = tokens.peek()
+        && token.token_type == TokenType::CloseParenthesis
+    {
+        tokens.next(); // discard ) token
+        return Ok(arguments);
+    }
+
+    loop {
+        arguments.push(parse_expression(tokens)?);
+
+        match tokens.next() {
+            Some(token) if token.token_type == TokenType::Comma => continue,
+            Some(token) if token.token_type == TokenType::CloseParenthesis => {
+                return Ok(arguments);
+            }
+            _ => return Err(DiceError::new("Invalid expression.")),
+        }
+    }
+}
+
+fn parse_attribute(tokens: &mut TokenItr) -> Result<AST, DiceError> {
+    let (name, span) = match tokens.next() {
+        None => return Err(DiceError::new("Invalid expression.")),
+        Some(token) => (token.text.clone(), token.span),
+    };
+
+    return Ok(AST::Attribute(AttributeAST {
+        name: name,
+        span: tokens.call_span.unwrap_or(span),
+    }));
+}
+
 fn parse_integer(tokens: &mut TokenItr) -> Result<AST, DiceError> {
     return Ok(AST::Integer(IntegerAST {
         integer: parse_integer_raw(tokens)?,
@@ -224,6 +951,59 @@ fn parse_integer_raw(tokens: &mut TokenItr) -> Result<u64, DiceError> {
     return Err(DiceError::new("Invalid expression."));
 }
 
+fn parse_identifier_raw(tokens: &mut TokenItr) -> Result<String, DiceError> {
+    if let Some(token) = tokens.next()
+        && let Some(name) = token.name()
+    {
+        return Ok(name.to_string());
+    }
+
+    return Err(DiceError::new("Invalid expression."));
+}
+
+fn expect(tokens: &mut TokenItr, token_type: TokenType) -> Result<(), DiceError> {
+    if let Some(token) = tokens.next()
+        && token.token_type == token_type
+    {
+        return Ok(());
+    }
+
+    return Err(DiceError::new("Invalid expression."));
+}
+
+// Macros can build expressions deeper than the parser nests, since each call's body is parsed
+// separately from the arguments which are put into it.
+fn validate_depth(ast: &AST, depth: usize) -> Result<(), DiceError> {
+    if depth > MAX_DEPTH {
+        return Err(too_deep());
+    }
+
+    return children(ast)
+        .into_iter()
+        .try_for_each(|child| validate_depth(child, depth + 1));
+}
+
+fn node_count(ast: &AST) -> usize {
+    return 1 + children(ast).into_iter().map(node_count).sum::<usize>();
+}
+
+fn children(ast: &AST) -> Vec<&AST> {
+    return match ast {
+        AST::DynamicRoll(roll) => vec![&roll.die, &roll.faces],
+        AST::Math(math) => vec![&math.left, &math.right],
+        AST::Let(binding) => vec![&binding.value, &binding.body],
+        AST::Conditional(conditional) => vec![
+            &conditional.condition,
+            &conditional.if_true,
+            &conditional.if_false,
+        ],
+        AST::Repeat(repeat) => vec![&repeat.expression],
+        AST::Group(group) => group.children.iter().collect(),
+        AST::Label(label) => vec![&label.expression],
+        _ => Vec::new(),
+    };
+}
+
 fn validate_consumed_all_tokens(tokens: &TokenItr) -> Result<(), DiceError> {
     if !tokens.is_empty() {
         return Err(DiceError::new("Invalid expression."));
@@ -240,7 +1020,7 @@ fn validate_not_empty(tokens: &[Token]) -> Result<(), DiceError> {
     return Ok(());
 }
 
-fn validate_parenthesis(tokens: &[Token]) -> Result<(), DiceError> {
+pub fn validate_parenthesis(tokens: &[Token]) -> Result<(), DiceError> {
     let mut open_count = 0;
     let mut close_count = 0;
 
@@ -266,6 +1046,7 @@ fn validate_parenthesis(tokens: &[Token]) -> Result<(), DiceError> {
 #[allow(non_snake_case)]
 mod tests {
     use super::*;
+    use crate::dice_error::Span;
 
     #[test]
     fn parse__empty_input__returns_error() {
@@ -284,6 +1065,8 @@ mod tests {
         let input: Vec<Token> = vec![Token {
             token_type: TokenType::OpenParenthesis,
             integer: 0,
+            text: String::new(),
+            span: Span::default(),
         }];
 
         match parse(&input) {
@@ -302,6 +1085,8 @@ mod tests {
         let input: Vec<Token> = vec![Token {
             token_type: TokenType::CloseParenthesis,
             integer: 0,
+            text: String::new(),
+            span: Span::default(),
         }];
 
         match parse(&input) {
@@ -321,10 +1106,14 @@ mod tests {
             Token {
                 token_type: TokenType::CloseParenthesis,
                 integer: 0,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::OpenParenthesis,
                 integer: 0,
+                text: String::new(),
+                span: Span::default(),
             },
         ];
 
@@ -342,10 +1131,14 @@ mod tests {
             Token {
                 token_type: TokenType::Add,
                 integer: 0,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
         ];
 
@@ -363,10 +1156,14 @@ mod tests {
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::Add,
                 integer: 0,
+                text: String::new(),
+                span: Span::default(),
             },
         ];
 
@@ -384,10 +1181,14 @@ mod tests {
             Token {
                 token_type: TokenType::Multiply,
                 integer: 0,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
         ];
 
@@ -405,10 +1206,14 @@ mod tests {
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::Multiply,
                 integer: 0,
+                text: String::new(),
+                span: Span::default(),
             },
         ];
 
@@ -425,6 +1230,8 @@ mod tests {
         let input: Vec<Token> = vec![Token {
             token_type: TokenType::D,
             integer: 10,
+            text: String::new(),
+            span: Span::default(),
         }];
 
         match parse(&input) {
@@ -441,10 +1248,14 @@ mod tests {
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::D,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
         ];
 
@@ -462,18 +1273,26 @@ mod tests {
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::D,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::KeepHigh,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
         ];
 
@@ -491,18 +1310,26 @@ mod tests {
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::D,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::KeepHigh,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
         ];
 
@@ -520,30 +1347,44 @@ mod tests {
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::D,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::KeepHigh,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::KeepLow,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
             Token {
                 token_type: TokenType::Integer,
                 integer: 10,
+                text: String::new(),
+                span: Span::default(),
             },
         ];
 
@@ -554,4 +1395,376 @@ mod tests {
             Ok(_) => assert!(false, "Should have returned an error"),
         }
     }
+
+    #[test]
+    fn parse__let_binding__returns_let_with_value_and_body() {
+        let tokens = crate::lexer::tokenize("let a = d20 in a + a").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::Let(ast) => {
+                assert_eq!("a", ast.name);
+                assert!(matches!(*ast.value, AST::ShortRoll(_)));
+                assert!(matches!(*ast.body, AST::Math(_)));
+            }
+            _ => assert!(false, "Should have returned a let"),
+        }
+    }
+
+    #[test]
+    fn parse__let_binding_without_in__returns_error() {
+        let tokens = crate::lexer::tokenize("let a = d20 a").unwrap();
+
+        match parse(&tokens) {
+            Err(err) => {
+                assert_eq!("Invalid expression.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse__conditional__returns_conditional_with_comparison() {
+        let tokens = crate::lexer::tokenize("d20 >= 20 ? 10 : 0").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::Conditional(ast) => {
+                assert!(matches!(
+                    *ast.condition,
+                    AST::Math(MathAST {
+                        operation: MathOperation::GreaterOrEqual,
+                        ..
+                    })
+                ));
+            }
+            _ => assert!(false, "Should have returned a conditional"),
+        }
+    }
+
+    #[test]
+    fn parse__conditional_without_else_branch__returns_error() {
+        let tokens = crate::lexer::tokenize("d20 >= 20 ? 10").unwrap();
+
+        match parse(&tokens) {
+            Err(err) => {
+                assert_eq!("Invalid expression.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse__attribute__returns_attribute_with_name_and_span() {
+        let tokens = crate::lexer::tokenize("1d20 + @str_mod").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::Math(ast) => match *ast.right {
+                AST::Attribute(attribute) => {
+                    assert_eq!("str_mod", attribute.name);
+                    assert_eq!(Span::new(7, 15), attribute.span);
+                }
+                _ => assert!(false, "Should have returned an attribute"),
+            },
+            _ => assert!(false, "Should have returned a math operation"),
+        }
+    }
+
+    #[test]
+    fn parse__repeat_with_modifiers__returns_repeat_of_the_atom() {
+        let tokens = crate::lexer::tokenize("6x(4d6dl1)h3sd").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::Repeat(ast) => {
+                assert_eq!(6, ast.count);
+                assert_eq!(Some(3), ast.keep_high);
+                assert!(matches!(ast.sort, Some(SortOrder::Descending)));
+                match *ast.expression {
+                    AST::LongRoll(roll) => assert_eq!(Some(1), roll.drop_low),
+                    _ => assert!(false, "Should have repeated a long roll"),
+                }
+            }
+            _ => assert!(false, "Should have returned a repeat"),
+        }
+    }
+
+    #[test]
+    fn parse__repeat_with_no_expression__returns_error() {
+        let tokens = crate::lexer::tokenize("6x").unwrap();
+
+        match parse(&tokens) {
+            Err(err) => {
+                assert_eq!("Invalid expression.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse__group_with_keep_high__returns_group_of_the_child_expressions() {
+        let tokens = crate::lexer::tokenize("{1d20+5, 1d20+3}kh1 + 2").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::Math(ast) => match *ast.left {
+                AST::Group(group) => {
+                    assert_eq!(2, group.children.len());
+                    assert_eq!(Some(1), group.keep_high);
+                    assert!(group.success.is_none());
+                }
+                _ => assert!(false, "Should have returned a group"),
+            },
+            _ => assert!(false, "Should have returned a math operation"),
+        }
+    }
+
+    #[test]
+    fn parse__group_with_success_condition__returns_group_counting_successes() {
+        let tokens = crate::lexer::tokenize("{3d6, 2d8}>=10").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::Group(group) => {
+                let success = group.success.unwrap();
+                assert!(matches!(success.comparison, Comparison::GreaterOrEqual));
+                assert_eq!(10, success.target);
+            }
+            _ => assert!(false, "Should have returned a group"),
+        }
+    }
+
+    #[test]
+    fn parse__group_followed_by_spaced_comparison__returns_comparison_of_the_total() {
+        let tokens = crate::lexer::tokenize("{1d20} >= 10").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::Math(ast) => {
+                assert!(matches!(ast.operation, MathOperation::GreaterOrEqual));
+                match *ast.left {
+                    AST::Group(group) => assert!(group.success.is_none()),
+                    _ => assert!(false, "Should have returned a group"),
+                }
+            }
+            _ => assert!(false, "Should have returned a math operation"),
+        }
+    }
+
+    #[test]
+    fn parse__unclosed_group__returns_error() {
+        let tokens = crate::lexer::tokenize("{1d20, 1d20").unwrap();
+
+        match parse(&tokens) {
+            Err(err) => {
+                assert_eq!("Invalid expression.", err.message)
+            }
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse__parenthetical_dice_count__returns_dynamic_roll() {
+        let tokens = crate::lexer::tokenize("(1d4)d6h2").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::DynamicRoll(ast) => {
+                assert!(matches!(*ast.die, AST::LongRoll(_)));
+                assert!(matches!(
+                    *ast.faces,
+                    AST::Integer(IntegerAST { integer: 6 })
+                ));
+                assert_eq!(Some(2), ast.keep_high);
+            }
+            _ => assert!(false, "Should have returned a dynamic roll"),
+        }
+    }
+
+    #[test]
+    fn parse__parenthetical_face_count__returns_dynamic_roll() {
+        let tokens = crate::lexer::tokenize("2d(1d8 + 2)").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::DynamicRoll(ast) => {
+                assert!(matches!(*ast.die, AST::Integer(IntegerAST { integer: 2 })));
+                assert!(matches!(*ast.faces, AST::Math(_)));
+            }
+            _ => assert!(false, "Should have returned a dynamic roll"),
+        }
+    }
+
+    #[test]
+    fn parse__short_roll_with_parenthetical_faces__returns_dynamic_roll_of_one_die() {
+        let tokens = crate::lexer::tokenize("d(2 * 3)").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::DynamicRoll(ast) => {
+                assert!(matches!(*ast.die, AST::Integer(IntegerAST { integer: 1 })));
+                assert!(matches!(*ast.faces, AST::Math(_)));
+            }
+            _ => assert!(false, "Should have returned a dynamic roll"),
+        }
+    }
+
+    #[test]
+    fn parse__labelled_roll__binds_the_label_to_the_atom() {
+        let tokens = crate::lexer::tokenize("2d6[fire] * 2").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::Math(math) => match *math.left {
+                AST::Label(label) => {
+                    assert_eq!("fire", label.label);
+                    assert!(matches!(*label.expression, AST::LongRoll(_)));
+                }
+                _ => assert!(false, "Should have labelled the roll"),
+            },
+            _ => assert!(false, "Should have returned a multiplication"),
+        }
+    }
+
+    #[test]
+    fn parse__critical_ranges__bare_target_matches_exactly() {
+        let tokens = crate::lexer::tokenize("1d20cf1cs>=19").unwrap();
+
+        let result = parse(&tokens).unwrap();
+
+        match result {
+            AST::LongRoll(roll) => {
+                assert_eq!(
+                    Some(SuccessCondition {
+                        comparison: Comparison::GreaterOrEqual,
+                        target: 19,
+                    }),
+                    roll.critical_success
+                );
+                assert_eq!(
+                    Some(SuccessCondition {
+                        comparison: Comparison::Equal,
+                        target: 1,
+                    }),
+                    roll.critical_failure
+                );
+            }
+            _ => assert!(false, "Should have returned a long roll"),
+        }
+    }
+
+    #[test]
+    fn parse__short_roll_with_critical_ranges__returns_a_roll_of_one_die() {
+        let parse_input = |input: &str| parse(&crate::lexer::tokenize(input).unwrap()).unwrap();
+
+        assert_eq!(parse_input("1d20cs19"), parse_input("d20cs19"));
+        assert_eq!(parse_input("1d20cs>=19 + 1"), parse_input("d20cs>=19 + 1"));
+        assert_eq!(parse_input("1d20cf1cs20"), parse_input("d20cf1cs20"));
+    }
+
+    #[test]
+    fn parse__critical_keywords_outside_a_roll__are_names() {
+        use crate::ast::ASTExecutable;
+        use rand::SeedableRng;
+
+        let tokens = crate::lexer::tokenize("let cs = 2 in let cf = 3 in cs * cf").unwrap();
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = parse(&tokens).unwrap().execute_ast(&mut rng).unwrap();
+
+        assert_eq!(6, result.result);
+    }
+
+    #[test]
+    fn parse__critical_range_without_target__returns_error() {
+        let tokens = crate::lexer::tokenize("1d20cs>=").unwrap();
+
+        assert!(parse(&tokens).is_err());
+    }
+
+    #[test]
+    fn parse__advantage_shorthand__desugars_to_keeping_one_of_two_dice() {
+        let parse_input = |input: &str| parse(&crate::lexer::tokenize(input).unwrap()).unwrap();
+
+        assert_eq!(parse_input("2d20h1"), parse_input("adv"));
+        assert_eq!(parse_input("2d20l1 + 5"), parse_input("1d20 dis + 5"));
+        assert_eq!(parse_input("2d20h1cs20"), parse_input("1d20cs20 adv"));
+        assert_eq!(parse_input("2x(1d20 + 2)h1"), parse_input("(1d20 + 2) adv"));
+    }
+
+    #[test]
+    fn parse__best_of_N__desugars_to_a_repeat_keeping_one_total() {
+        let parse_input = |input: &str| parse(&crate::lexer::tokenize(input).unwrap()).unwrap();
+
+        assert_eq!(
+            parse_input("3x(1d20 + 2)h1"),
+            parse_input("best3of(1d20 + 2)")
+        );
+        assert_eq!(parse_input("2x(4d6)l1"), parse_input("worst2of 4d6"));
+    }
+
+    #[test]
+    fn parse__advantage_many_times__keeps_the_ast_small() {
+        let tokens = crate::lexer::tokenize(&format!("(1d20 + 1){}", " adv".repeat(40))).unwrap();
+        let mut ast = parse(&tokens).unwrap();
+
+        let mut depth = 0;
+        while let AST::Repeat(repeat) = ast {
+            ast = *repeat.expression;
+            depth += 1;
+        }
+
+        assert_eq!(40, depth);
+    }
+
+    #[test]
+    fn parse__shorthand_keywords_bound_by_let__are_names() {
+        use crate::ast::ASTExecutable;
+        use rand::SeedableRng;
+
+        let evaluate = |input: &str| {
+            let ast = parse(&crate::lexer::tokenize(input).unwrap()).unwrap();
+            let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+            return ast.execute_ast(&mut rng).unwrap().result;
+        };
+
+        assert_eq!(4, evaluate("let best = 3 in best + 1"));
+        assert_eq!(4, evaluate("let adv = 2 in adv * 2"));
+        assert_eq!(6, evaluate("let of = 2 in let worst = 3 in of * worst"));
+    }
+
+    #[test]
+    fn parse__best_of_zero__returns_error() {
+        let tokens = crate::lexer::tokenize("best0of(1d20)").unwrap();
+
+        match parse(&tokens) {
+            Err(err) => assert_eq!("Cannot choose the best or worst of 0 rolls.", err.message),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn parse__repeat_keywords_outside_a_repeat__are_names() {
+        use crate::ast::ASTExecutable;
+        use rand::SeedableRng;
+
+        let tokens = crate::lexer::tokenize("let x = 3 in 2x(x) + x").unwrap();
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let result = parse(&tokens).unwrap().execute_ast(&mut rng).unwrap();
+
+        assert_eq!(9, result.result);
+    }
 }
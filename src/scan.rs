@@ -0,0 +1,231 @@
+use crate::ast::AST;
+use crate::dice_error::Span;
+use crate::lexer;
+use crate::lexer::Token;
+use crate::lexer::TokenType;
+use crate::parser;
+
+/// The most tokens an expression found by [`scan`] can have. Every run of tokens up to this long
+/// is tried, so longer runs would make scanning a long line slow.
+pub const MAX_EXPRESSION_TOKENS: usize = 64;
+
+/// A dice expression found in a piece of text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Embedded {
+    /// The character positions of the expression in the scanned text.
+    pub span: Span,
+    /// The expression as it was written in the text.
+    pub text: String,
+    pub ast: AST,
+}
+
+/// Finds every dice expression in `text`, such as the `(2d8 + 4)` of a stat block's
+/// `Hit: 13 (2d8 + 4) piercing damage.`
+///
+/// Each line is tokenized once and split at the characters which the lexer rejects. Within each
+/// part, the longest run of at most [`MAX_EXPRESSION_TOKENS`] tokens which parses into an
+/// expression with at least one die is taken as an expression. Runs which use names are skipped,
+/// since a word of prose would otherwise be read as a variable. Expressions never span more than
+/// one line.
+pub fn scan(text: &str) -> Vec<Embedded> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut found: Vec<Embedded> = Vec::new();
+    let mut line_start = 0;
+
+    for line in text.split('\n') {
+        let line_length = line.chars().count();
+
+        for tokens in lexer::tokenize_runs(line) {
+            for (start, end, ast) in find_expressions(&tokens) {
+                let span = Span::new(line_start + start, line_start + end);
+                found.push(Embedded {
+                    span: span,
+                    text: chars[span.start..span.end].iter().collect(),
+                    ast: ast,
+                });
+            }
+        }
+
+        // skip the newline
+        line_start += line_length + 1;
+    }
+
+    return found;
+}
+
+/// Copies `text` with each note written in brackets after the expression it belongs to, e.g.
+/// `Hit: 13 (2d8 + 4) [rolled 15] piercing damage.`
+pub fn annotate(text: &str, embedded: &[Embedded], notes: &[String]) -> String {
+    let mut annotated = String::new();
+    let mut notes_by_end = embedded
+        .iter()
+        .map(|expression| expression.span.end)
+        .zip(notes)
+        .peekable();
+
+    for (idx, char) in text.chars().enumerate() {
+        while let Some((_, note)) = notes_by_end.next_if(|(end, _)| *end == idx) {
+            annotated.push_str(&format!(" [{}]", note));
+        }
+        annotated.push(char);
+    }
+    for (_, note) in notes_by_end {
+        annotated.push_str(&format!(" [{}]", note));
+    }
+
+    return annotated;
+}
+
+// Finds the longest run of tokens starting at each token which parses into a dice expression, and
+// carries on after it. Positions are those of the tokens' spans.
+fn find_expressions(tokens: &[Token]) -> Vec<(usize, usize, AST)> {
+    let mut found: Vec<(usize, usize, AST)> = Vec::new();
+    let mut first = 0;
+
+    while first < tokens.len() {
+        // A dice expression runs at least up to its first roll, so only runs which reach the next
+        // roll are parsed, starting no more than half the limit before it.
+        let roll = match tokens[first..].iter().position(|token| {
+            matches!(
+                token.token_type,
+                TokenType::D | TokenType::Advantage | TokenType::Disadvantage
+            )
+        }) {
+            Some(position) => first + position,
+            None => break,
+        };
+        first = first.max(roll.saturating_sub(MAX_EXPRESSION_TOKENS / 2));
+        let window = tokens.len().min(first + MAX_EXPRESSION_TOKENS);
+
+        let longest = (roll + 1..=window).rev().find_map(|last| {
+            let ast = parser::parse(&tokens[first..last]).ok()?;
+            match is_dice_expression(&ast) {
+                true => Some((last, ast)),
+                false => None,
+            }
+        });
+
+        match longest {
+            Some((last, ast)) => {
+                found.push((tokens[first].span.start, tokens[last - 1].span.end, ast));
+                first = last;
+            }
+            None => first += 1,
+        }
+    }
+
+    return found;
+}
+
+fn is_dice_expression(ast: &AST) -> bool {
+    let has_dice = any_node(ast, &|node| {
+        matches!(
+            node,
+            AST::ShortRoll(_) | AST::LongRoll(_) | AST::DynamicRoll(_)
+        )
+    });
+    let uses_names = any_node(ast, &|node| {
+        matches!(node, AST::Variable(_) | AST::Attribute(_))
+    });
+
+    return has_dice && !uses_names;
+}
+
+fn any_node(ast: &AST, matches: &dyn Fn(&AST) -> bool) -> bool {
+    if matches(ast) {
+        return true;
+    }
+
+    let children: Vec<&AST> = match ast {
+        AST::DynamicRoll(roll) => vec![&roll.die, &roll.faces],
+        AST::Math(math) => vec![&math.left, &math.right],
+        AST::Let(binding) => vec![&binding.value, &binding.body],
+        AST::Conditional(conditional) => vec![
+            &conditional.condition,
+            &conditional.if_true,
+            &conditional.if_false,
+        ],
+        AST::Repeat(repeat) => vec![&repeat.expression],
+        AST::Group(group) => group.children.iter().collect(),
+        AST::Label(label) => vec![&label.expression],
+        _ => Vec::new(),
+    };
+
+    return children.into_iter().any(|child| any_node(child, matches));
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan__stat_block__finds_the_damage_roll() {
+        let text =
+            "Bite. Melee Weapon Attack: +6 to hit, reach 5 ft.\nHit: 13 (2d8 + 4) piercing damage.";
+
+        let found = scan(text);
+
+        assert_eq!(1, found.len());
+        assert_eq!("(2d8 + 4)", found[0].text);
+        assert_eq!(Span::new(58, 67), found[0].span);
+        assert_eq!("2d8 + 4", found[0].ast.to_string());
+    }
+
+    #[test]
+    fn scan__several_expressions__finds_each_longest_expression() {
+        let text = "Deals 1d6+2 slashing, plus 3d6 [fire] if it's 1d20 >= 15, or d4 cold!";
+
+        let found: Vec<String> = scan(text).into_iter().map(|found| found.text).collect();
+
+        assert_eq!(vec!["1d6+2", "3d6 [fire]", "1d20 >= 15", "d4"], found);
+    }
+
+    #[test]
+    fn scan__long_line_of_arithmetic__finds_the_dice_within_the_token_limit() {
+        let text = format!("{}2d8 + 4", "1 + ".repeat(2_000));
+
+        let found = scan(&text);
+
+        assert_eq!(1, found.len());
+        assert!(found[0].text.ends_with("+ 1 + 2d8 + 4"));
+        assert!(lexer::tokenize(&found[0].text).unwrap().len() <= MAX_EXPRESSION_TOKENS);
+    }
+
+    #[test]
+    fn scan__rejected_characters_and_huge_integers__split_the_line_around_them() {
+        let text = "Hit. 99999999999999999999 + 1d6; [unclosed 2d4 \"3d8\"";
+
+        let found: Vec<(String, Span)> = scan(text)
+            .into_iter()
+            .map(|found| (found.text, found.span))
+            .collect();
+
+        assert_eq!(
+            vec![
+                ("1d6".to_string(), Span::new(28, 31)),
+                ("2d4".to_string(), Span::new(43, 46)),
+                ("3d8".to_string(), Span::new(48, 51)),
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn scan__prose_without_dice__finds_nothing() {
+        assert!(scan("Armor Class 15 (natural armor), speed 30 ft. [see page 2").is_empty());
+    }
+
+    #[test]
+    fn annotate__notes__are_written_after_each_expression() {
+        let text = "Hit: 13 (2d8 + 4) piercing damage plus 1d6";
+        let found = scan(text);
+
+        let annotated = annotate(text, &found, &["avg 13".to_string(), "avg 3".to_string()]);
+
+        assert_eq!(
+            "Hit: 13 (2d8 + 4) [avg 13] piercing damage plus 1d6 [avg 3]",
+            annotated
+        );
+    }
+}
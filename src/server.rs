@@ -0,0 +1,626 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Take;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::ast::AST;
+use crate::critical;
+use crate::critical::CriticalRule;
+use crate::dice_error::DiceError;
+use crate::environment::Environment;
+use crate::json::JsonValue;
+use crate::json::parse_json;
+use crate::lexer;
+use crate::macros::MacroTable;
+use crate::parser;
+use crate::session;
+use crate::simplify;
+use crate::stats;
+
+/// The address `serve` listens on unless another one is given. Only local clients can connect.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+// The request line and headers together.
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+const MAX_CONNECTIONS: usize = 32;
+// Parsing and rolling recurse once or more for each level an expression is nested, which needs
+// more stack than a thread gets by default in unoptimized builds.
+const CONNECTION_STACK_BYTES: usize = 16 * 1024 * 1024;
+// How long a client has to send its whole request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A response to one request, with a JSON body.
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: JsonValue,
+}
+
+/// Serves the dice engine over HTTP with JSON requests and responses.
+///
+/// Every endpoint takes a `POST` with a JSON object holding an `expression`, and optionally a
+/// `seed`, `attributes` (for `@attributes`), `variables` (for free variable names), and a
+/// `critical` rule (see [`CriticalRule::parse`]) which turns the expression into critical damage:
+///
+/// - `/roll` rolls the expression and returns its result, description, seed, and every die rolled.
+/// - `/stats` rolls it `samples` times (default [`stats::DEFAULT_SAMPLES`]) and returns the
+///   distribution.
+/// - `/parse` returns the expression in canonical form and simplified, without rolling it.
+///
+/// Expressions are parsed with the server's macros, and the server's attributes are the defaults
+/// which a request's `attributes` add to.
+pub struct Server {
+    macros: MacroTable,
+    attributes: HashMap<String, i128>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+}
+
+impl Server {
+    pub fn new(macros: MacroTable, attributes: HashMap<String, i128>) -> Server {
+        return Server {
+            macros: macros,
+            attributes: attributes,
+        };
+    }
+
+    /// Answers requests from `listener` until it fails, each connection on its own thread.
+    /// Connections beyond the first 32 open ones are answered with a 503 straight away.
+    pub fn serve(self, listener: TcpListener) -> Result<(), DiceError> {
+        let server = Arc::new(self);
+        let open_connections = Arc::new(AtomicUsize::new(0));
+
+        for stream in listener.incoming() {
+            let mut stream = stream.map_err(|err| {
+                DiceError::new(&format!("Failed to accept a connection: {}", err))
+            })?;
+
+            if open_connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                open_connections.fetch_sub(1, Ordering::SeqCst);
+                let busy = DiceError::new("The server is busy, please try again later.");
+                let _ = write_response(&mut stream, &error_response(503, &busy));
+                continue;
+            }
+
+            let server = Arc::clone(&server);
+            let connection_count = Arc::clone(&open_connections);
+            let spawned = std::thread::Builder::new()
+                .stack_size(CONNECTION_STACK_BYTES)
+                .spawn(move || {
+                    server.handle_connection(stream);
+                    connection_count.fetch_sub(1, Ordering::SeqCst);
+                });
+            if spawned.is_err() {
+                open_connections.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        return Ok(());
+    }
+
+    pub fn respond(&self, method: &str, path: &str, body: &str) -> Response {
+        let endpoint = match path {
+            "/roll" => Server::roll,
+            "/stats" => Server::stats,
+            "/parse" => Server::parse,
+            _ => {
+                return error_response(404, &DiceError::new(&format!("No endpoint at {}.", path)));
+            }
+        };
+
+        if method != "POST" {
+            return error_response(
+                405,
+                &DiceError::new(&format!("{} only accepts POST requests.", path)),
+            );
+        }
+
+        return match parse_json(body).and_then(|request| endpoint(self, &request)) {
+            Ok(body) => Response {
+                status: 200,
+                body: body,
+            },
+            Err(err) => error_response(400, &err),
+        };
+    }
+
+    fn roll(&self, request: &JsonValue) -> Result<JsonValue, DiceError> {
+        let (input, ast) = self.expression(request)?;
+        let mut env = self.environment(request)?;
+        let seed = seed(request)?.unwrap_or_else(rand::random::<u64>);
+
+        let (entry, result) = session::record(
+            &input,
+            &ast,
+            Some(seed),
+            session::seeded_dice(seed),
+            &mut env,
+        )?;
+
+        let JsonValue::Object(mut members) = entry.to_json() else {
+            unreachable!("session entries are JSON objects");
+        };
+        members.push((
+            "description".to_string(),
+            JsonValue::String(result.description),
+        ));
+
+        return Ok(JsonValue::Object(members));
+    }
+
+    fn stats(&self, request: &JsonValue) -> Result<JsonValue, DiceError> {
+        let (input, ast) = self.expression(request)?;
+        let env = self.environment(request)?;
+        let seed = seed(request)?.unwrap_or_else(rand::random::<u64>);
+        let samples = match request.get("samples") {
+            None => stats::DEFAULT_SAMPLES,
+            Some(JsonValue::Integer(samples)) if *samples >= 0 => {
+                u64::try_from(*samples).unwrap_or(u64::MAX)
+            }
+            Some(_) => {
+                return Err(DiceError::new("samples must be a non-negative integer."));
+            }
+        };
+
+        let statistics = stats::sample(&ast, &mut session::seeded_dice(seed), &env, samples)?;
+
+        let JsonValue::Object(mut members) = statistics.to_json() else {
+            unreachable!("statistics are JSON objects");
+        };
+        members.insert(0, ("input".to_string(), JsonValue::String(input)));
+        members.insert(1, ("seed".to_string(), JsonValue::Integer(seed as i128)));
+
+        return Ok(JsonValue::Object(members));
+    }
+
+    fn parse(&self, request: &JsonValue) -> Result<JsonValue, DiceError> {
+        let (input, ast) = self.expression(request)?;
+
+        return Ok(JsonValue::Object(vec![
+            ("input".to_string(), JsonValue::String(input)),
+            ("expression".to_string(), JsonValue::String(ast.to_string())),
+            (
+                "simplified".to_string(),
+                JsonValue::String(simplify::simplify(ast).to_string()),
+            ),
+        ]));
+    }
+
+    fn expression(&self, request: &JsonValue) -> Result<(String, AST), DiceError> {
+        let Some(JsonValue::String(input)) = request.get("expression") else {
+            return Err(DiceError::new(
+                "The request must have an expression string.",
+            ));
+        };
+
+        let mut ast = parser::parse_with_macros(&lexer::tokenize(input)?, &self.macros)?;
+
+        match request.get("critical") {
+            None => {}
+            Some(JsonValue::String(rule)) => {
+                ast = critical::critical_damage(ast, &CriticalRule::parse(rule)?);
+            }
+            Some(_) => return Err(DiceError::new("critical must be a string.")),
+        }
+
+        return Ok((input.clone(), ast));
+    }
+
+    fn environment(&self, request: &JsonValue) -> Result<Environment, DiceError> {
+        let mut attributes = self.attributes.clone();
+        attributes.extend(integer_members(request, "attributes")?);
+
+        let mut env = Environment::with_attributes(attributes);
+        for (name, value) in integer_members(request, "variables")? {
+            env.bind(&name, value);
+        }
+
+        return Ok(env);
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) {
+        let response = match read_request(&stream) {
+            Ok(request) => self.respond(&request.method, &request.path, &request.body),
+            Err(response) => response,
+        };
+
+        // The client is gone if the response can't be written, so there is no one to tell.
+        let _ = write_response(&mut stream, &response);
+    }
+}
+
+fn seed(request: &JsonValue) -> Result<Option<u64>, DiceError> {
+    return match request.get("seed") {
+        None | Some(JsonValue::Null) => Ok(None),
+        Some(JsonValue::Integer(seed)) => u64::try_from(*seed)
+            .map(Some)
+            .map_err(|_| DiceError::new("seed must be an integer from 0 to 2^64 - 1.")),
+        Some(_) => Err(DiceError::new(
+            "seed must be an integer from 0 to 2^64 - 1.",
+        )),
+    };
+}
+
+fn integer_members(request: &JsonValue, key: &str) -> Result<Vec<(String, i128)>, DiceError> {
+    let members = match request.get(key) {
+        None => return Ok(Vec::new()),
+        Some(JsonValue::Object(members)) => members,
+        Some(_) => {
+            return Err(DiceError::new(&format!("{} must be an object.", key)));
+        }
+    };
+
+    let mut integers = Vec::new();
+    for (name, value) in members {
+        let JsonValue::Integer(integer) = value else {
+            return Err(DiceError::new(&format!(
+                "{} {} must be an integer.",
+                key, name
+            )));
+        };
+
+        integers.push((name.clone(), *integer));
+    }
+
+    return Ok(integers);
+}
+
+fn error_response(status: u16, err: &DiceError) -> Response {
+    let mut members = vec![("error".to_string(), JsonValue::String(err.message.clone()))];
+
+    if let Some(span) = err.span {
+        members.push((
+            "span".to_string(),
+            JsonValue::Object(vec![
+                ("start".to_string(), JsonValue::Integer(span.start as i128)),
+                ("end".to_string(), JsonValue::Integer(span.end as i128)),
+            ]),
+        ));
+    }
+
+    return Response {
+        status: status,
+        body: JsonValue::Object(members),
+    };
+}
+
+// Only what the endpoints need of HTTP/1.1 is supported: a request line, headers, and a body of
+// Content-Length bytes. Every connection is closed after one response.
+fn read_request(stream: &TcpStream) -> Result<Request, Response> {
+    let bad_request = |reason: &str| error_response(400, &DiceError::new(reason));
+
+    let mut reader = BufReader::new(DeadlineReader {
+        stream: stream,
+        deadline: Instant::now() + READ_TIMEOUT,
+    });
+    let mut head = (&mut reader).take(MAX_HEAD_BYTES as u64);
+
+    let request_line = read_head_line(&mut head, "Failed to read the request.")?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(bad_request("Invalid HTTP request line."));
+    };
+
+    let mut content_length: usize = 0;
+    loop {
+        let header = read_head_line(&mut head, "Failed to read the request headers.")?;
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value
+                .trim()
+                .parse()
+                .map_err(|_| bad_request("Invalid Content-Length header."))?;
+        }
+    }
+
+    if content_length > MAX_BODY_BYTES {
+        return Err(error_response(
+            413,
+            &DiceError::new(&format!(
+                "The request body must be at most {} bytes.",
+                MAX_BODY_BYTES
+            )),
+        ));
+    }
+
+    let mut body = vec![0u8; content_length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|_| bad_request("Failed to read the request body."))?;
+
+    return Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        body: String::from_utf8(body).map_err(|_| bad_request("The body must be UTF-8."))?,
+    });
+}
+
+// Reads a line of the request line and headers, which may only be `MAX_HEAD_BYTES` long in total.
+fn read_head_line<R: BufRead>(head: &mut Take<R>, failure: &str) -> Result<String, Response> {
+    let mut line = String::new();
+    head.read_line(&mut line)
+        .map_err(|_| error_response(400, &DiceError::new(failure)))?;
+
+    if !line.ends_with('\n') && head.limit() == 0 {
+        return Err(error_response(
+            413,
+            &DiceError::new(&format!(
+                "The request line and headers must be at most {} bytes.",
+                MAX_HEAD_BYTES
+            )),
+        ));
+    }
+
+    return Ok(line);
+}
+
+// Reads from a connection until `deadline`, so a client which trickles its request in can't keep
+// the connection for longer than `READ_TIMEOUT`.
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+
+        self.stream.set_read_timeout(Some(remaining))?;
+        return self.stream.read(buf);
+    }
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> std::io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let body = response.body.to_string();
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        body.len(),
+        body
+    )?;
+
+    return stream.flush();
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+    use crate::macros;
+
+    fn server() -> Server {
+        let macros = macros::parse_macros("attack(bonus) = 1d20 + bonus").unwrap();
+        let attributes = HashMap::from([("str_mod".to_string(), 3)]);
+
+        return Server::new(macros, attributes);
+    }
+
+    #[test]
+    fn respond__roll_with_seed__rolls_the_same_dice_every_time() {
+        let body = r#"{"expression": "attack(@str_mod) + 2d6", "seed": 7}"#;
+
+        let first = server().respond("POST", "/roll", body);
+        let second = server().respond("POST", "/roll", body);
+
+        assert_eq!(200, first.status);
+        assert_eq!(first, second);
+        assert_eq!(
+            Some(&JsonValue::String("1d20 + @str_mod + 2d6".to_string())),
+            first.body.get("expression")
+        );
+        let Some(JsonValue::Array(rolls)) = first.body.get("rolls") else {
+            panic!("Expected the rolled dice");
+        };
+        assert_eq!(3, rolls.len());
+    }
+
+    #[test]
+    fn respond__roll_with_context__uses_the_attributes_and_variables() {
+        let body = r#"{"expression": "level + @str_mod", "attributes": {"str_mod": 5}, "variables": {"level": 4}}"#;
+
+        let response = server().respond("POST", "/roll", body);
+
+        assert_eq!(Some(&JsonValue::Integer(9)), response.body.get("result"));
+    }
+
+    #[test]
+    fn respond__stats__returns_the_distribution() {
+        let body = r#"{"expression": "1d4", "seed": 1, "samples": 1000}"#;
+
+        let response = server().respond("POST", "/stats", body);
+
+        assert_eq!(
+            Some(&JsonValue::Integer(1000)),
+            response.body.get("samples")
+        );
+        let Some(JsonValue::Array(distribution)) = response.body.get("distribution") else {
+            panic!("Expected a distribution");
+        };
+        assert_eq!(4, distribution.len());
+    }
+
+    #[test]
+    fn respond__parse__returns_the_canonical_and_simplified_expressions() {
+        let response = server().respond("POST", "/parse", r#"{"expression": "d6+d6+(2)"}"#);
+
+        assert_eq!(
+            Some(&JsonValue::String("d6 + d6 + 2".to_string())),
+            response.body.get("expression")
+        );
+        assert_eq!(
+            Some(&JsonValue::String("2d6 + 2".to_string())),
+            response.body.get("simplified")
+        );
+    }
+
+    #[test]
+    fn respond__critical_rule__rolls_the_critical_damage() {
+        let response = server().respond(
+            "POST",
+            "/parse",
+            r#"{"expression": "2d6 + 3", "critical": "max"}"#,
+        );
+
+        assert_eq!(
+            Some(&JsonValue::String("12 + 2d6 + 3".to_string())),
+            response.body.get("expression")
+        );
+    }
+
+    #[test]
+    fn respond__expression_over_the_dice_limit__returns_400_with_the_error() {
+        let response = server().respond("POST", "/roll", r#"{"expression": "10001d6"}"#);
+
+        assert_eq!(400, response.status);
+        assert!(matches!(
+            response.body.get("error"),
+            Some(JsonValue::String(message)) if message.contains("10000")
+        ));
+    }
+
+    // Responds on a thread with the stack of a connection's thread.
+    fn respond_on_connection_thread(path: &'static str, body: String) -> Response {
+        return std::thread::Builder::new()
+            .stack_size(CONNECTION_STACK_BYTES)
+            .spawn(move || server().respond("POST", path, &body))
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn respond__deeply_nested_expression__returns_400_with_the_error() {
+        let expression = format!("{}1{}", "(".repeat(20_000), ")".repeat(20_000));
+        let chain = format!("{}1", "1 + ".repeat(16_000));
+
+        for input in [expression, chain] {
+            let body = format!(r#"{{"expression": "{}"}}"#, input);
+
+            let response = respond_on_connection_thread("/parse", body);
+
+            assert_eq!(400, response.status);
+            assert_eq!(
+                Some(&JsonValue::String(
+                    "Expressions may not be nested more than 128 levels deep.".to_string()
+                )),
+                response.body.get("error")
+            );
+        }
+    }
+
+    #[test]
+    fn respond__expression_nested_almost_to_the_limit__rolls_on_a_connection_thread() {
+        let expression = format!("{}1d6{}", "(1 + ".repeat(63), ")".repeat(63));
+        let body = format!(
+            r#"{{"expression": "{}", "critical": "double"}}"#,
+            expression
+        );
+
+        let response = respond_on_connection_thread("/roll", body);
+
+        assert_eq!(200, response.status);
+    }
+
+    #[test]
+    fn respond__unknown_path_or_method__returns_404_or_405() {
+        assert_eq!(404, server().respond("POST", "/nope", "{}").status);
+        assert_eq!(405, server().respond("GET", "/roll", "").status);
+    }
+
+    #[test]
+    fn serve__oversized_header__returns_413() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || server().serve(listener));
+
+        // The header is sent up to exactly the limit, so the server reads everything it was sent.
+        let request_line = "POST /roll HTTP/1.1\r\nX-Padding: ";
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{}{}",
+            request_line,
+            "a".repeat(MAX_HEAD_BYTES - request_line.len())
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(
+            head.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+            "{}",
+            head
+        );
+        assert_eq!(
+            Some(&JsonValue::String(
+                "The request line and headers must be at most 8192 bytes.".to_string()
+            )),
+            parse_json(body).unwrap().get("error")
+        );
+    }
+
+    #[test]
+    fn serve__local_client__receives_a_json_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || server().serve(listener));
+
+        let body = r#"{"expression": "2d6 + 3", "seed": 1}"#;
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "POST /roll HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        let body = parse_json(body).unwrap();
+        assert_eq!(
+            Some(&JsonValue::String("2d6 + 3".to_string())),
+            body.get("input")
+        );
+    }
+}
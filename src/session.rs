@@ -0,0 +1,539 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+
+use crate::ast::AST;
+use crate::ast::ASTExecutable;
+use crate::ast::ASTExecutionResult;
+use crate::dice_error::DiceError;
+use crate::dice_source::DiceSource;
+use crate::dice_source::ScriptedDice;
+use crate::environment::Environment;
+use crate::json::JsonValue;
+use crate::json::parse_json;
+use crate::lexer;
+use crate::parser;
+
+/// The version of the calculator, which is logged with each entry since a different version may
+/// roll the same expression differently.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The face value a die with `faces` faces rolled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RolledDie {
+    pub faces: u64,
+    pub value: u64,
+}
+
+/// Rolls dice with another source and remembers every value it rolled.
+pub struct RecordingDice<D: DiceSource> {
+    dice: D,
+    rolls: Vec<RolledDie>,
+}
+
+impl<D: DiceSource> RecordingDice<D> {
+    pub fn new(dice: D) -> RecordingDice<D> {
+        return RecordingDice {
+            dice: dice,
+            rolls: Vec::new(),
+        };
+    }
+
+    pub fn into_rolls(self) -> Vec<RolledDie> {
+        return self.rolls;
+    }
+}
+
+impl<D: DiceSource> DiceSource for RecordingDice<D> {
+    fn roll(&mut self, faces: u64) -> Result<u64, DiceError> {
+        let value = self.dice.roll(faces)?;
+        self.rolls.push(RolledDie {
+            faces: faces,
+            value: value,
+        });
+
+        return Ok(value);
+    }
+}
+
+/// One expression evaluated during a session.
+///
+/// `input` is the expression as it was entered and `expression` is the canonical form of what was
+/// executed (after macros were expanded and any simplification), which is what a replay runs.
+/// `seed` is the seed of the [`ChaCha12Rng`] which rolled the dice (see [`seeded_dice`]), or
+/// `None` when they came from somewhere else, such as real dice at the table. Dice with 0 or 1
+/// faces aren't rolled, so they don't appear in `rolls`.
+///
+/// `attributes` holds the value of each `@attribute` the expression used, by name, and `version`
+/// is the [`VERSION`] of the calculator which rolled it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionEntry {
+    pub input: String,
+    pub expression: String,
+    pub seed: Option<u64>,
+    pub rolls: Vec<RolledDie>,
+    pub result: i128,
+    pub list: Option<Vec<i128>>,
+    pub attributes: Vec<(String, i128)>,
+    pub version: String,
+}
+
+/// The generator a session entry with `seed` rolls its dice with.
+pub fn seeded_dice(seed: u64) -> ChaCha12Rng {
+    return ChaCha12Rng::seed_from_u64(seed);
+}
+
+/// Executes `ast` with `dice`, recording the session entry for it alongside its result.
+pub fn record(
+    input: &str,
+    ast: &AST,
+    seed: Option<u64>,
+    dice: impl DiceSource,
+    env: &mut Environment,
+) -> Result<(SessionEntry, ASTExecutionResult), DiceError> {
+    let mut dice = RecordingDice::new(dice);
+    let result = ast.execute_ast_in(&mut dice, env)?;
+
+    let mut names = attribute_names(ast);
+    names.sort();
+    names.dedup();
+    let attributes = names
+        .into_iter()
+        .filter_map(|name| env.attribute(&name).map(|value| (name, value)))
+        .collect();
+
+    let entry = SessionEntry {
+        input: input.to_string(),
+        expression: ast.to_string(),
+        seed: seed,
+        rolls: dice.into_rolls(),
+        result: result.result,
+        list: result.list.clone(),
+        attributes: attributes,
+        version: VERSION.to_string(),
+    };
+
+    return Ok((entry, result));
+}
+
+/// Executes the expression of a logged entry again with the same dice: the same seed, or the
+/// recorded values when the entry has no seed. The expression's `@attributes` have the values
+/// which were logged with it.
+pub fn replay(entry: &SessionEntry) -> Result<SessionEntry, DiceError> {
+    let ast = parser::parse(&lexer::tokenize(&entry.expression)?)?;
+    let attributes: HashMap<String, i128> = entry.attributes.iter().cloned().collect();
+    let mut env = Environment::with_attributes(attributes).without_descriptions();
+
+    let (replayed, _) = match entry.seed {
+        Some(seed) => record(&entry.input, &ast, Some(seed), seeded_dice(seed), &mut env)?,
+        None => {
+            let values = entry.rolls.iter().map(|roll| roll.value);
+            record(
+                &entry.input,
+                &ast,
+                None,
+                ScriptedDice::new(values),
+                &mut env,
+            )?
+        }
+    };
+
+    return Ok(replayed);
+}
+
+impl SessionEntry {
+    /// Describes the first way in which `replayed` differs from this entry, if it does, and
+    /// whether it was replayed by a different version of the calculator than it was logged by.
+    pub fn divergence(&self, replayed: &SessionEntry) -> Option<String> {
+        let difference = self.difference(replayed)?;
+
+        if self.version != replayed.version {
+            return Some(format!(
+                "{} (logged by version {} but replayed by version {})",
+                difference, self.version, replayed.version
+            ));
+        }
+
+        return Some(difference);
+    }
+
+    fn difference(&self, replayed: &SessionEntry) -> Option<String> {
+        for (idx, (logged, rolled)) in self.rolls.iter().zip(&replayed.rolls).enumerate() {
+            if logged != rolled {
+                return Some(format!(
+                    "die {} was a d{} showing {} but is now a d{} showing {}",
+                    idx + 1,
+                    logged.faces,
+                    logged.value,
+                    rolled.faces,
+                    rolled.value
+                ));
+            }
+        }
+
+        if self.rolls.len() != replayed.rolls.len() {
+            return Some(format!(
+                "{} dice were rolled but now {} are",
+                self.rolls.len(),
+                replayed.rolls.len()
+            ));
+        }
+
+        if self.result != replayed.result {
+            return Some(format!(
+                "the result was {} but is now {}",
+                self.result, replayed.result
+            ));
+        }
+
+        if self.list != replayed.list {
+            return Some(format!(
+                "the list was {:?} but is now {:?}",
+                self.list, replayed.list
+            ));
+        }
+
+        return None;
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let rolls = self
+            .rolls
+            .iter()
+            .map(|roll| {
+                JsonValue::Object(vec![
+                    ("faces".to_string(), JsonValue::Integer(roll.faces as i128)),
+                    ("value".to_string(), JsonValue::Integer(roll.value as i128)),
+                ])
+            })
+            .collect();
+
+        let mut members = vec![
+            ("input".to_string(), JsonValue::String(self.input.clone())),
+            (
+                "expression".to_string(),
+                JsonValue::String(self.expression.clone()),
+            ),
+            (
+                "seed".to_string(),
+                match self.seed {
+                    Some(seed) => JsonValue::Integer(seed as i128),
+                    None => JsonValue::Null,
+                },
+            ),
+            ("rolls".to_string(), JsonValue::Array(rolls)),
+            ("result".to_string(), JsonValue::Integer(self.result)),
+        ];
+
+        if let Some(list) = &self.list {
+            let entries = list
+                .iter()
+                .map(|value| JsonValue::Integer(*value))
+                .collect();
+            members.push(("list".to_string(), JsonValue::Array(entries)));
+        }
+
+        let attributes = self
+            .attributes
+            .iter()
+            .map(|(name, value)| (name.clone(), JsonValue::Integer(*value)))
+            .collect();
+        members.push(("attributes".to_string(), JsonValue::Object(attributes)));
+        members.push((
+            "version".to_string(),
+            JsonValue::String(self.version.clone()),
+        ));
+
+        return JsonValue::Object(members);
+    }
+
+    pub fn from_json(value: &JsonValue) -> Result<SessionEntry, DiceError> {
+        let seed = match field(value, "seed")? {
+            JsonValue::Null => None,
+            seed => Some(unsigned(seed, "seed")?),
+        };
+
+        let JsonValue::Array(logged_rolls) = field(value, "rolls")? else {
+            return Err(invalid_entry("rolls must be an array"));
+        };
+        let mut rolls: Vec<RolledDie> = Vec::new();
+        for roll in logged_rolls {
+            rolls.push(RolledDie {
+                faces: unsigned(field(roll, "faces")?, "faces")?,
+                value: unsigned(field(roll, "value")?, "value")?,
+            });
+        }
+
+        let list = match value.get("list") {
+            None => None,
+            Some(JsonValue::Array(entries)) => Some(
+                entries
+                    .iter()
+                    .map(|entry| integer(entry, "list"))
+                    .collect::<Result<Vec<i128>, DiceError>>()?,
+            ),
+            Some(_) => return Err(invalid_entry("list must be an array")),
+        };
+
+        return Ok(SessionEntry {
+            input: string(field(value, "input")?, "input")?,
+            expression: string(field(value, "expression")?, "expression")?,
+            seed: seed,
+            rolls: rolls,
+            result: integer(field(value, "result")?, "result")?,
+            list: list,
+            attributes: attributes(field(value, "attributes")?)?,
+            version: string(field(value, "version")?, "version")?,
+        });
+    }
+}
+
+/// Appends an entry to a session log, which holds one JSON object per line.
+pub fn append_entry(path: &str, entry: &SessionEntry) -> Result<(), DiceError> {
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", entry.to_json()));
+
+    return written
+        .map_err(|err| DiceError::new(&format!("Failed to write session log {}: {}", path, err)));
+}
+
+pub fn load_session(path: &str) -> Result<Vec<SessionEntry>, DiceError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            return Err(DiceError::new(&format!(
+                "Failed to read session log {}: {}",
+                path, err
+            )));
+        }
+    };
+
+    return parse_session(&contents);
+}
+
+pub fn parse_session(contents: &str) -> Result<Vec<SessionEntry>, DiceError> {
+    let mut entries: Vec<SessionEntry> = Vec::new();
+
+    for (idx, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry = parse_json(line).and_then(|value| SessionEntry::from_json(&value));
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(err) => {
+                return Err(DiceError::new(&format!(
+                    "Invalid session log line {}: {}",
+                    idx + 1,
+                    err.message
+                )));
+            }
+        }
+    }
+
+    return Ok(entries);
+}
+
+// The names of the `@attributes` which `ast` uses, with repeats.
+fn attribute_names(ast: &AST) -> Vec<String> {
+    return match ast {
+        AST::Attribute(attribute) => vec![attribute.name.clone()],
+        AST::DynamicRoll(roll) => {
+            [attribute_names(&roll.die), attribute_names(&roll.faces)].concat()
+        }
+        AST::Math(math) => [attribute_names(&math.left), attribute_names(&math.right)].concat(),
+        AST::Let(binding) => [
+            attribute_names(&binding.value),
+            attribute_names(&binding.body),
+        ]
+        .concat(),
+        AST::Conditional(conditional) => [
+            attribute_names(&conditional.condition),
+            attribute_names(&conditional.if_true),
+            attribute_names(&conditional.if_false),
+        ]
+        .concat(),
+        AST::Repeat(repeat) => attribute_names(&repeat.expression),
+        AST::Group(group) => group.children.iter().flat_map(attribute_names).collect(),
+        AST::Label(label) => attribute_names(&label.expression),
+        _ => Vec::new(),
+    };
+}
+
+fn attributes(value: &JsonValue) -> Result<Vec<(String, i128)>, DiceError> {
+    let JsonValue::Object(members) = value else {
+        return Err(invalid_entry("attributes must be an object"));
+    };
+
+    return members
+        .iter()
+        .map(|(name, value)| Ok((name.clone(), integer(value, "attributes")?)))
+        .collect();
+}
+
+fn field<'a>(value: &'a JsonValue, key: &str) -> Result<&'a JsonValue, DiceError> {
+    return value
+        .get(key)
+        .ok_or_else(|| invalid_entry(&format!("{} is missing", key)));
+}
+
+fn string(value: &JsonValue, key: &str) -> Result<String, DiceError> {
+    let JsonValue::String(string) = value else {
+        return Err(invalid_entry(&format!("{} must be a string", key)));
+    };
+
+    return Ok(string.clone());
+}
+
+fn integer(value: &JsonValue, key: &str) -> Result<i128, DiceError> {
+    let JsonValue::Integer(integer) = value else {
+        return Err(invalid_entry(&format!("{} must be an integer", key)));
+    };
+
+    return Ok(*integer);
+}
+
+fn unsigned(value: &JsonValue, key: &str) -> Result<u64, DiceError> {
+    return u64::try_from(integer(value, key)?)
+        .map_err(|_| invalid_entry(&format!("{} must be a non-negative integer", key)));
+}
+
+fn invalid_entry(reason: &str) -> DiceError {
+    return DiceError::new(&format!("Invalid session entry: {}.", reason));
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+
+    fn record_input(input: &str, seed: Option<u64>, dice: impl DiceSource) -> SessionEntry {
+        let ast = parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+
+        let (entry, _) = record(input, &ast, seed, dice, &mut Environment::new()).unwrap();
+
+        return entry;
+    }
+
+    #[test]
+    fn replay__seeded_entry__matches_the_logged_entry() {
+        let entry = record_input("6x(4d6dl1)sd + d20", Some(42), seeded_dice(42));
+
+        let replayed = replay(&entry).unwrap();
+
+        assert_eq!(25, entry.rolls.len());
+        assert_eq!(entry, replayed);
+        assert_eq!(None, entry.divergence(&replayed));
+    }
+
+    #[test]
+    fn replay__entry_rolled_at_the_table__replays_the_logged_values() {
+        let entry = record_input("2d6 + 3", None, ScriptedDice::new([4, 6]));
+
+        let replayed = replay(&entry).unwrap();
+
+        assert_eq!(13, replayed.result);
+        assert_eq!(None, entry.divergence(&replayed));
+    }
+
+    #[test]
+    fn divergence__tampered_result__describes_the_difference() {
+        let entry = record_input("2d6 + 3", None, ScriptedDice::new([4, 6]));
+        let mut tampered = entry.clone();
+        tampered.result = 20;
+
+        let replayed = replay(&tampered).unwrap();
+
+        assert_eq!(
+            Some("the result was 20 but is now 13".to_string()),
+            tampered.divergence(&replayed)
+        );
+    }
+
+    #[test]
+    fn divergence__different_dice__describes_the_first_different_die() {
+        let entry = record_input("1d20 + 2d6", Some(7), seeded_dice(7));
+        let mut tampered = entry.clone();
+        tampered.rolls[1].faces = 8;
+
+        let replayed = replay(&tampered).unwrap();
+        let divergence = tampered.divergence(&replayed).unwrap();
+
+        assert!(
+            divergence.starts_with("die 2 was a d8 showing"),
+            "{}",
+            divergence
+        );
+    }
+
+    #[test]
+    fn replay__entry_using_attributes__replays_with_the_logged_values() {
+        let ast = parser::parse(&lexer::tokenize("d20 + @str + @str").unwrap()).unwrap();
+        let attributes = HashMap::from([("str".to_string(), 3), ("dex".to_string(), 1)]);
+        let mut env = Environment::with_attributes(attributes);
+
+        let (entry, _) =
+            record("d20 + @str + @str", &ast, Some(5), seeded_dice(5), &mut env).unwrap();
+        let replayed = replay(&entry).unwrap();
+
+        assert_eq!(vec![("str".to_string(), 3)], entry.attributes);
+        assert_eq!(entry, replayed);
+    }
+
+    #[test]
+    fn divergence__logged_by_another_version__names_both_versions() {
+        let entry = record_input("2d6 + 3", None, ScriptedDice::new([4, 6]));
+        let mut tampered = entry.clone();
+        tampered.result = 20;
+        tampered.version = "0.0.1".to_string();
+
+        let replayed = replay(&tampered).unwrap();
+
+        assert_eq!(
+            Some(format!(
+                "the result was 20 but is now 13 (logged by version 0.0.1 but replayed by version {})",
+                VERSION
+            )),
+            tampered.divergence(&replayed)
+        );
+    }
+
+    #[test]
+    fn parse_session__logged_entries__round_trip_through_json_lines() {
+        let seeded = record_input("let a = d20 in a + 3x(d4)", Some(3), seeded_dice(3));
+        let mut with_attributes = record_input("d6", None, ScriptedDice::new([2]));
+        with_attributes.attributes = vec![("str".to_string(), -1)];
+        let manual = record_input("d6", None, ScriptedDice::new([5]));
+        let log = format!(
+            "{}\n\n{}\n{}\n",
+            seeded.to_json(),
+            manual.to_json(),
+            with_attributes.to_json()
+        );
+
+        assert_eq!(
+            vec![seeded, manual, with_attributes],
+            parse_session(&log).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_session__entry_without_result__returns_error_with_the_line() {
+        let log = r#"{"input": "d6", "expression": "d6", "seed": null, "rolls": []}"#;
+
+        match parse_session(log) {
+            Err(err) => assert_eq!(
+                "Invalid session log line 1: Invalid session entry: result is missing.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+}
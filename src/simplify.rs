@@ -0,0 +1,348 @@
+use crate::ast::AST;
+use crate::ast::ConditionalAST;
+use crate::ast::DynamicRollAST;
+use crate::ast::IntegerAST;
+use crate::ast::LetAST;
+use crate::ast::LongRollAST;
+use crate::ast::MAX_DICE;
+use crate::ast::MathAST;
+use crate::ast::MathOperation;
+
+/// Folds integer arithmetic, merges like dice in sums (`1d6 + 2d6` becomes `3d6`), and removes
+/// subtrees which cannot change the total, such as `0 * 1d4`.
+///
+/// The simplified expression can roll exactly the same totals with the same probabilities, and
+/// fails in the same cases, but it may roll the dice in a different order and describe them
+/// differently.
+pub fn simplify(ast: AST) -> AST {
+    return match ast {
+        AST::Math(math) => match math.operation {
+            MathOperation::Add | MathOperation::Subtract => simplify_sum(AST::Math(math)),
+            _ => simplify_math(math),
+        },
+        AST::DynamicRoll(roll) => simplify_dynamic_roll(roll),
+        AST::Let(binding) => AST::Let(LetAST {
+            name: binding.name,
+            value: Box::new(simplify(*binding.value)),
+            body: Box::new(simplify(*binding.body)),
+        }),
+        AST::Conditional(conditional) => match simplify(*conditional.condition) {
+            AST::Integer(IntegerAST { integer: 0 }) => simplify(*conditional.if_false),
+            AST::Integer(_) => simplify(*conditional.if_true),
+            condition => AST::Conditional(ConditionalAST {
+                condition: Box::new(condition),
+                if_true: Box::new(simplify(*conditional.if_true)),
+                if_false: Box::new(simplify(*conditional.if_false)),
+            }),
+        },
+        AST::Repeat(mut repeat) => {
+            repeat.expression = Box::new(simplify(*repeat.expression));
+            AST::Repeat(repeat)
+        }
+        AST::Group(mut group) => {
+            group.children = group.children.into_iter().map(simplify).collect();
+            AST::Group(group)
+        }
+        AST::Label(mut label) => {
+            label.expression = Box::new(simplify(*label.expression));
+            AST::Label(label)
+        }
+        ast => ast,
+    };
+}
+
+fn simplify_math(math: MathAST) -> AST {
+    let left = simplify(*math.left);
+    let right = simplify(*math.right);
+
+    // Operations which would fail (e.g. division by zero) are left to fail when executed.
+    if let (AST::Integer(l), AST::Integer(r)) = (&left, &right)
+        && let Ok(result) = math.operation.apply(l.integer as i128, r.integer as i128)
+        && let Ok(integer) = u64::try_from(result)
+    {
+        return AST::Integer(IntegerAST { integer: integer });
+    }
+
+    match (&math.operation, &left, &right) {
+        (
+            MathOperation::Multiply | MathOperation::Divide,
+            _,
+            AST::Integer(IntegerAST { integer: 1 }),
+        ) if !may_produce_list(&left) => {
+            return left;
+        }
+        (MathOperation::Multiply, AST::Integer(IntegerAST { integer: 1 }), _)
+            if !may_produce_list(&right) =>
+        {
+            return right;
+        }
+        (MathOperation::Multiply, AST::Integer(IntegerAST { integer: 0 }), other)
+        | (MathOperation::Multiply, other, AST::Integer(IntegerAST { integer: 0 }))
+            if can_discard(other) =>
+        {
+            return AST::Integer(IntegerAST { integer: 0 });
+        }
+        _ => {}
+    }
+
+    return AST::Math(MathAST {
+        operation: math.operation,
+        left: Box::new(left),
+        right: Box::new(right),
+    });
+}
+
+// A chain of additions and subtractions is flattened into its terms so that integers anywhere in
+// the chain can be folded together, and dice with the same number of faces and the same sign can
+// be merged. The added terms come first, then the subtracted terms, then the folded integer.
+fn simplify_sum(ast: AST) -> AST {
+    let mut terms: Vec<(bool, AST)> = Vec::new();
+    collect_terms(ast, false, &mut terms);
+
+    let mut added: Vec<AST> = Vec::new();
+    let mut subtracted: Vec<AST> = Vec::new();
+    let mut integers: Vec<(bool, u64)> = Vec::new();
+
+    for (is_subtracted, term) in terms {
+        match term {
+            AST::Integer(integer) => integers.push((is_subtracted, integer.integer)),
+            term if is_subtracted => merge_term(&mut subtracted, term),
+            term => merge_term(&mut added, term),
+        }
+    }
+
+    let mut terms: Vec<(bool, AST)> = added.into_iter().map(|term| (false, term)).collect();
+    terms.extend(subtracted.into_iter().map(|term| (true, term)));
+
+    let total: i128 = integers
+        .iter()
+        .map(|(is_subtracted, integer)| match is_subtracted {
+            true => -(*integer as i128),
+            false => *integer as i128,
+        })
+        .sum();
+    match u64::try_from(total.unsigned_abs()) {
+        Ok(0) => {}
+        Ok(integer) => terms.push((total < 0, AST::Integer(IntegerAST { integer: integer }))),
+        Err(_) => terms.extend(integers.into_iter().map(|(is_subtracted, integer)| {
+            (is_subtracted, AST::Integer(IntegerAST { integer: integer }))
+        })),
+    }
+
+    let mut terms = terms.into_iter();
+    let mut result = match terms.next() {
+        None => return AST::Integer(IntegerAST { integer: 0 }),
+        Some((false, first)) => first,
+        Some((true, first)) => subtract(AST::Integer(IntegerAST { integer: 0 }), first),
+    };
+
+    for (is_subtracted, term) in terms {
+        result = match is_subtracted {
+            true => subtract(result, term),
+            false => AST::Math(MathAST {
+                operation: MathOperation::Add,
+                left: Box::new(result),
+                right: Box::new(term),
+            }),
+        };
+    }
+
+    // A sum of a single list (`6x(1d6) + 0`) is a scalar, and must stay one.
+    if may_produce_list(&result) {
+        return AST::Math(MathAST {
+            operation: MathOperation::Add,
+            left: Box::new(result),
+            right: Box::new(AST::Integer(IntegerAST { integer: 0 })),
+        });
+    }
+
+    return result;
+}
+
+fn collect_terms(ast: AST, is_subtracted: bool, terms: &mut Vec<(bool, AST)>) {
+    match ast {
+        AST::Math(math)
+            if matches!(math.operation, MathOperation::Add | MathOperation::Subtract) =>
+        {
+            let right_is_subtracted = match math.operation {
+                MathOperation::Subtract => !is_subtracted,
+                _ => is_subtracted,
+            };
+
+            collect_terms(*math.left, is_subtracted, terms);
+            collect_terms(*math.right, right_is_subtracted, terms);
+        }
+        ast => terms.push((is_subtracted, simplify(ast))),
+    }
+}
+
+fn merge_term(terms: &mut Vec<AST>, term: AST) {
+    if let Some((die, faces)) = plain_dice(&term) {
+        for existing in terms.iter_mut() {
+            if let Some((existing_die, existing_faces)) = plain_dice(existing)
+                && existing_faces == faces
+                && existing_die + die <= MAX_DICE
+            {
+                *existing = AST::LongRoll(LongRollAST {
+                    die: existing_die + die,
+                    faces: faces,
+                    keep_high: None,
+                    keep_low: None,
+                    drop_high: None,
+                    drop_low: None,
+                    critical_success: None,
+                    critical_failure: None,
+                });
+                return;
+            }
+        }
+    }
+
+    terms.push(term);
+}
+
+fn subtract(left: AST, right: AST) -> AST {
+    return AST::Math(MathAST {
+        operation: MathOperation::Subtract,
+        left: Box::new(left),
+        right: Box::new(right),
+    });
+}
+
+// The dice count and face count of a roll without keep, drop, or critical modifiers.
+fn plain_dice(ast: &AST) -> Option<(u64, u64)> {
+    return match ast {
+        AST::ShortRoll(roll) => Some((1, roll.faces)),
+        AST::LongRoll(roll)
+            if roll.keep_high.is_none()
+                && roll.keep_low.is_none()
+                && roll.drop_high.is_none()
+                && roll.drop_low.is_none()
+                && roll.critical_success.is_none()
+                && roll.critical_failure.is_none()
+                && roll.die <= MAX_DICE =>
+        {
+            Some((roll.die, roll.faces))
+        }
+        _ => None,
+    };
+}
+
+fn simplify_dynamic_roll(roll: DynamicRollAST) -> AST {
+    let die = simplify(*roll.die);
+    let faces = simplify(*roll.faces);
+
+    if let (AST::Integer(die), AST::Integer(faces)) = (&die, &faces) {
+        return AST::LongRoll(LongRollAST {
+            die: die.integer,
+            faces: faces.integer,
+            keep_high: roll.keep_high,
+            keep_low: roll.keep_low,
+            drop_high: roll.drop_high,
+            drop_low: roll.drop_low,
+            critical_success: roll.critical_success.clone(),
+            critical_failure: roll.critical_failure.clone(),
+        });
+    }
+
+    return AST::DynamicRoll(DynamicRollAST {
+        die: Box::new(die),
+        faces: Box::new(faces),
+        keep_high: roll.keep_high,
+        keep_low: roll.keep_low,
+        drop_high: roll.drop_high,
+        drop_low: roll.drop_low,
+        critical_success: roll.critical_success.clone(),
+        critical_failure: roll.critical_failure.clone(),
+    });
+}
+
+// Whether executing the expression can never fail or roll a critical, so that leaving it out
+// changes nothing but the description.
+fn can_discard(ast: &AST) -> bool {
+    return match ast {
+        AST::Integer(_) | AST::ShortRoll(_) => true,
+        AST::LongRoll(roll) => {
+            roll.die <= MAX_DICE
+                && roll.critical_success.is_none()
+                && roll.critical_failure.is_none()
+        }
+        _ => false,
+    };
+}
+
+fn may_produce_list(ast: &AST) -> bool {
+    return match ast {
+        AST::Repeat(_) | AST::Let(_) | AST::Conditional(_) => true,
+        AST::Label(label) => may_produce_list(&label.expression),
+        _ => false,
+    };
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::ast::ASTExecutable;
+    use crate::lexer;
+    use crate::parser;
+
+    fn simplified(input: &str) -> String {
+        let ast = parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+
+        return simplify(ast).to_string();
+    }
+
+    #[test]
+    fn simplify__macro_expansion_leftovers__folds_integers_and_removes_dead_dice() {
+        assert_eq!("1d20 + 5", simplified("1d20 + 2 + 3 - 0 * 1d4"));
+    }
+
+    #[test]
+    fn simplify__integer_arithmetic__folds_to_one_integer() {
+        assert_eq!("8", simplified("2 * 3 + 4 / 2"));
+        assert_eq!("1", simplified("(1 + 1) * 3 >= 6"));
+        assert_eq!("0 - 4", simplified("1 - 5"));
+    }
+
+    #[test]
+    fn simplify__like_dice_with_the_same_sign__are_merged() {
+        assert_eq!("3d6", simplified("1d6 + 2d6"));
+        assert_eq!(
+            "1d20 + 2d6 - 2d4 + 3",
+            simplified("1d20 - d4 + d6 + 3 - 1d4 + d6")
+        );
+    }
+
+    #[test]
+    fn simplify__dice_with_modifiers__are_not_merged() {
+        assert_eq!("d6 + 2d6h1", simplified("d6 + 2d6h1"));
+    }
+
+    #[test]
+    fn simplify__division_by_zero__is_left_to_fail_when_executed() {
+        assert_eq!("0 * (1d4 / 0)", simplified("0 * (1d4 / 0)"));
+        assert_eq!("1 / 0", simplified("d4 * 0 + 1 / 0"));
+    }
+
+    #[test]
+    fn simplify__constant_condition__keeps_only_the_chosen_branch() {
+        assert_eq!("2d6", simplified("1 + 1 > 1 ? d6 + d6 : 1d4"));
+    }
+
+    #[test]
+    fn simplify__dynamic_roll_of_integers__becomes_a_plain_roll() {
+        assert_eq!("2d6h1", simplified("(2)d(3 + 3)h1"));
+    }
+
+    #[test]
+    fn simplify__sum_of_a_single_list__stays_a_scalar() {
+        let ast = simplify(parser::parse(&lexer::tokenize("6x(4d6dl1) + 2 - 2").unwrap()).unwrap());
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        assert_eq!("6x(4d6dl1) + 0", ast.to_string());
+        assert_eq!(None, ast.execute_ast(&mut rng).unwrap().list);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+
+use crate::ast::AST;
+use crate::bytecode;
+use crate::dice_error::DiceError;
+use crate::dice_source::DiceSource;
+use crate::environment::Environment;
+use crate::json::JsonValue;
+
+/// The most samples [`sample`] takes of one expression.
+pub const MAX_SAMPLES: u64 = 1_000_000;
+
+/// The number of samples to take when the caller doesn't ask for another number.
+pub const DEFAULT_SAMPLES: u64 = 10_000;
+
+/// The most dice [`sample`] rolls plus entries it repeats or groups, across all of its samples.
+pub const MAX_TOTAL_STEPS: u64 = 10_000_000;
+
+/// The distribution of an expression's total, estimated by rolling it many times.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statistics {
+    pub samples: u64,
+    pub mean: f64,
+    pub standard_deviation: f64,
+    pub min: i128,
+    pub max: i128,
+    /// How often each total was rolled, from the lowest total to the highest.
+    pub distribution: Vec<(i128, u64)>,
+}
+
+/// Rolls `ast` `samples` times and summarises the totals. The expression is compiled to bytecode
+/// first, so no descriptions are built.
+pub fn sample(
+    ast: &AST,
+    dice: &mut impl DiceSource,
+    env: &Environment,
+    samples: u64,
+) -> Result<Statistics, DiceError> {
+    if !(1..=MAX_SAMPLES).contains(&samples) {
+        return Err(DiceError::new(&format!(
+            "The number of samples must be from 1 to {}.",
+            MAX_SAMPLES
+        )));
+    }
+
+    let program = bytecode::compile(ast);
+    let mut counts: BTreeMap<i128, u64> = BTreeMap::new();
+    let mut sum: f64 = 0.0;
+    let mut sum_of_squares: f64 = 0.0;
+    let mut steps: u64 = 0;
+
+    for _ in 0..samples {
+        let total = program.execute_in(dice, env)?;
+
+        steps += env.steps();
+        if steps > MAX_TOTAL_STEPS {
+            return Err(DiceError::new(&format!(
+                "Cannot roll or repeat more than {} times across all samples, so fewer samples are needed.",
+                MAX_TOTAL_STEPS
+            )));
+        }
+
+        *counts.entry(total).or_default() += 1;
+        sum += total as f64;
+        sum_of_squares += (total as f64) * (total as f64);
+    }
+
+    let mean = sum / samples as f64;
+    let variance = (sum_of_squares / samples as f64 - mean * mean).max(0.0);
+
+    return Ok(Statistics {
+        samples: samples,
+        mean: mean,
+        standard_deviation: variance.sqrt(),
+        min: *counts.keys().next().unwrap_or(&0),
+        max: *counts.keys().next_back().unwrap_or(&0),
+        distribution: counts.into_iter().collect(),
+    });
+}
+
+impl Statistics {
+    pub fn to_json(&self) -> JsonValue {
+        let distribution = self
+            .distribution
+            .iter()
+            .map(|(total, count)| {
+                JsonValue::Object(vec![
+                    ("total".to_string(), JsonValue::Integer(*total)),
+                    ("count".to_string(), JsonValue::Integer(*count as i128)),
+                ])
+            })
+            .collect();
+
+        return JsonValue::Object(vec![
+            (
+                "samples".to_string(),
+                JsonValue::Integer(self.samples as i128),
+            ),
+            ("mean".to_string(), JsonValue::Float(self.mean)),
+            (
+                "standard_deviation".to_string(),
+                JsonValue::Float(self.standard_deviation),
+            ),
+            ("min".to_string(), JsonValue::Integer(self.min)),
+            ("max".to_string(), JsonValue::Integer(self.max)),
+            ("distribution".to_string(), JsonValue::Array(distribution)),
+        ]);
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::lexer;
+    use crate::parser;
+
+    fn sample_input(input: &str, samples: u64) -> Result<Statistics, DiceError> {
+        let ast = parser::parse(&lexer::tokenize(input).unwrap()).unwrap();
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        return sample(&ast, &mut rng, &Environment::new(), samples);
+    }
+
+    #[test]
+    fn sample__two_d6__estimates_the_mean_and_covers_every_total() {
+        let stats = sample_input("2d6 + 1", 100_000).unwrap();
+
+        assert!((stats.mean - 8.0).abs() < 0.05, "{}", stats.mean);
+        assert!((stats.standard_deviation - 2.415).abs() < 0.05);
+        assert_eq!((3, 13), (stats.min, stats.max));
+        assert_eq!(11, stats.distribution.len());
+        assert_eq!(
+            100_000,
+            stats
+                .distribution
+                .iter()
+                .map(|(_, count)| count)
+                .sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn sample__too_many_samples__returns_DiceError() {
+        match sample_input("d6", MAX_SAMPLES + 1) {
+            Err(err) => assert_eq!(
+                "The number of samples must be from 1 to 1000000.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn sample__too_many_repeats_across_all_samples__returns_DiceError() {
+        match sample_input("10000x(1)", 1_001) {
+            Err(err) => assert_eq!(
+                "Cannot roll or repeat more than 10000000 times across all samples, so fewer samples are needed.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::fmt;
+
+use crate::dice_error::DiceError;
+use crate::dice_source::DiceSource;
+
+/// The die sizes which [`self_test`] checks.
+pub const SELF_TEST_DICE: [u64; 8] = [2, 4, 6, 8, 10, 12, 20, 100];
+
+/// The most faces a die which [`check_die`] checks may have, since every face is counted.
+pub const MAX_CHECKED_FACES: u64 = 1_000_000;
+
+// The critical values below are for a significance level of 0.1%, so that a fair source fails a
+// check about once in a thousand runs.
+const Z_CRITICAL: f64 = 3.0902;
+const KS_COEFFICIENT: f64 = 1.9495;
+// A single face is only reported as skewed when its count is this many standard deviations from
+// the expected count, which keeps false alarms rare even across a d100's faces.
+const SKEW_DEVIATIONS: f64 = 4.5;
+
+/// The results of rolling one die size many times with a dice source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UniformityReport {
+    pub faces: u64,
+    pub rolls: u64,
+    /// How often each face was rolled, starting with face 1.
+    pub counts: Vec<u64>,
+    /// Pearson's chi-square statistic of the counts against a uniform distribution.
+    pub chi_square: f64,
+    pub chi_square_critical: f64,
+    /// The Kolmogorov-Smirnov statistic: the largest difference between the cumulative share of
+    /// rolls at or below a face and the share a fair die would have.
+    pub ks_statistic: f64,
+    pub ks_critical: f64,
+    /// Faces which were never rolled.
+    pub missing_faces: Vec<u64>,
+    /// Faces which were rolled far more or far less often than a fair die would roll them.
+    pub skewed_faces: Vec<u64>,
+}
+
+impl UniformityReport {
+    pub fn passed(&self) -> bool {
+        return self.chi_square <= self.chi_square_critical
+            && self.ks_statistic <= self.ks_critical
+            && self.missing_faces.is_empty()
+            && self.skewed_faces.is_empty();
+    }
+
+    fn expected(&self) -> f64 {
+        return self.rolls as f64 / self.faces as f64;
+    }
+}
+
+/// Rolls a die with `faces` faces `rolls` times and checks that every face came up about equally
+/// often.
+pub fn check_die(
+    dice: &mut impl DiceSource,
+    faces: u64,
+    rolls: u64,
+) -> Result<UniformityReport, DiceError> {
+    if faces < 2 {
+        return Err(DiceError::new(
+            "Only dice with at least 2 faces can be checked.",
+        ));
+    }
+    // The chi-square test needs at least 5 expected rolls of every face.
+    let minimum_rolls = match faces.checked_mul(5) {
+        Some(minimum_rolls) if faces <= MAX_CHECKED_FACES => minimum_rolls,
+        _ => {
+            return Err(DiceError::new(&format!(
+                "Only dice with at most {} faces can be checked.",
+                MAX_CHECKED_FACES
+            )));
+        }
+    };
+    if rolls < minimum_rolls {
+        return Err(DiceError::new(&format!(
+            "At least {} rolls are needed to check a d{}.",
+            minimum_rolls, faces
+        )));
+    }
+
+    let mut counts = vec![0u64; faces as usize];
+    for _ in 0..rolls {
+        let value = dice.roll(faces)?;
+        if !(1..=faces).contains(&value) {
+            return Err(DiceError::new(&format!(
+                "The dice source rolled {} on a d{}.",
+                value, faces
+            )));
+        }
+
+        counts[(value - 1) as usize] += 1;
+    }
+
+    let expected = rolls as f64 / faces as f64;
+    let deviation = (expected * (1.0 - 1.0 / faces as f64)).sqrt();
+
+    let chi_square = counts
+        .iter()
+        .map(|count| (*count as f64 - expected).powi(2) / expected)
+        .sum();
+
+    let mut cumulative = 0;
+    let mut ks_statistic: f64 = 0.0;
+    for (idx, count) in counts.iter().enumerate() {
+        cumulative += count;
+        let observed = cumulative as f64 / rolls as f64;
+        let uniform = (idx + 1) as f64 / faces as f64;
+        ks_statistic = ks_statistic.max((observed - uniform).abs());
+    }
+
+    let faces_where = |matches: &dyn Fn(u64) -> bool| -> Vec<u64> {
+        return (1..=faces)
+            .filter(|face| matches(counts[(face - 1) as usize]))
+            .collect();
+    };
+    let missing_faces = faces_where(&|count| count == 0);
+    let skewed_faces = faces_where(&|count| {
+        count > 0 && (count as f64 - expected).abs() > SKEW_DEVIATIONS * deviation
+    });
+
+    return Ok(UniformityReport {
+        faces: faces,
+        rolls: rolls,
+        counts: counts,
+        chi_square: chi_square,
+        chi_square_critical: chi_square_critical(faces - 1),
+        ks_statistic: ks_statistic,
+        ks_critical: KS_COEFFICIENT / (rolls as f64).sqrt(),
+        missing_faces: missing_faces,
+        skewed_faces: skewed_faces,
+    });
+}
+
+/// Checks every die size in [`SELF_TEST_DICE`] with `rolls` rolls each.
+pub fn self_test(
+    dice: &mut impl DiceSource,
+    rolls: u64,
+) -> Result<Vec<UniformityReport>, DiceError> {
+    return SELF_TEST_DICE
+        .iter()
+        .map(|faces| check_die(dice, *faces, rolls))
+        .collect();
+}
+
+// The Wilson-Hilferty approximation of the chi-square distribution's critical value. It is 3% too
+// high for a d2 (1 degree of freedom) and well within 1% for a d4 and larger dice.
+fn chi_square_critical(degrees_of_freedom: u64) -> f64 {
+    let k = degrees_of_freedom as f64;
+    let spread = 2.0 / (9.0 * k);
+
+    return k * (1.0 - spread + Z_CRITICAL * spread.sqrt()).powi(3);
+}
+
+impl fmt::Display for UniformityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "d{}: {} rolls, chi-square {:.2} (limit {:.2}), Kolmogorov-Smirnov {:.4} (limit {:.4}): {}",
+            self.faces,
+            self.rolls,
+            self.chi_square,
+            self.chi_square_critical,
+            self.ks_statistic,
+            self.ks_critical,
+            match self.passed() {
+                true => "OK",
+                false => "FAILED",
+            }
+        )?;
+
+        for face in &self.missing_faces {
+            write!(f, "\n  Face {} was never rolled", face)?;
+        }
+        for face in &self.skewed_faces {
+            write!(
+                f,
+                "\n  Face {} was rolled {} times, expected about {:.0}",
+                face,
+                self.counts[(face - 1) as usize],
+                self.expected()
+            )?;
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    // Rolls the way dice were rolled before the top face was fixed.
+    struct NoTopFace(rand_chacha::ChaCha12Rng);
+
+    impl DiceSource for NoTopFace {
+        fn roll(&mut self, faces: u64) -> Result<u64, DiceError> {
+            return Ok(self.0.random_range(1..faces));
+        }
+    }
+
+    #[test]
+    fn self_test__seeded_generator__passes_every_die() {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let reports = self_test(&mut rng, 50_000).unwrap();
+
+        assert_eq!(SELF_TEST_DICE.len(), reports.len());
+        for report in reports {
+            assert!(report.passed(), "{}", report);
+        }
+    }
+
+    #[test]
+    fn check_die__top_face_never_rolled__reports_the_missing_face() {
+        let mut dice = NoTopFace(rand_chacha::ChaCha12Rng::seed_from_u64(1));
+
+        let report = check_die(&mut dice, 20, 20_000).unwrap();
+
+        assert!(!report.passed());
+        assert_eq!(vec![20], report.missing_faces);
+        assert!(report.chi_square > report.chi_square_critical);
+    }
+
+    #[test]
+    fn check_die__loaded_die__reports_the_skewed_face() {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let mut dice =
+            crate::dice_source::ScriptedDice::new((0..6_000).map(|roll| match roll % 25 {
+                0 => 6,
+                _ => rng.random_range(1..=6),
+            }));
+
+        let report = check_die(&mut dice, 6, 6_000).unwrap();
+
+        assert!(!report.passed());
+        assert_eq!(vec![6], report.skewed_faces);
+    }
+
+    #[test]
+    fn check_die__too_few_rolls__returns_DiceError() {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match check_die(&mut rng, 20, 99) {
+            Err(err) => assert_eq!("At least 100 rolls are needed to check a d20.", err.message),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+
+    #[test]
+    fn check_die__huge_die__returns_DiceError() {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        match check_die(&mut rng, u64::MAX, u64::MAX) {
+            Err(err) => assert_eq!(
+                "Only dice with at most 1000000 faces can be checked.",
+                err.message
+            ),
+            Ok(_) => assert!(false, "Should have returned an error"),
+        }
+    }
+}